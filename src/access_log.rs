@@ -0,0 +1,136 @@
+use crate::error::XenonResult;
+use chrono::Utc;
+use log::*;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// A single access log entry, written as one line of JSON.
+#[derive(Debug, Serialize)]
+pub struct AccessLogRecord {
+    pub timestamp: String,
+    pub remote_addr: SocketAddr,
+    pub method: String,
+    pub path: String,
+    pub session_id: Option<String>,
+    pub status: u16,
+    pub elapsed_ms: u64,
+}
+
+impl AccessLogRecord {
+    pub fn new(
+        remote_addr: SocketAddr,
+        method: String,
+        path: String,
+        session_id: Option<String>,
+        status: u16,
+        elapsed_ms: u64,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now().to_rfc3339(),
+            remote_addr,
+            method,
+            path,
+            session_id,
+            status,
+            elapsed_ms,
+        }
+    }
+}
+
+/// Writes access log records to a file, separately from the main log.
+#[derive(Debug)]
+pub struct AccessLogger {
+    file: Mutex<File>,
+}
+
+impl AccessLogger {
+    /// Open the access log for appending, creating it if it does not exist.
+    pub async fn open(path: &Path) -> XenonResult<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Write the record. Errors are logged rather than returned, since a failure
+    /// to write the access log should not affect the request.
+    pub async fn log(&self, record: &AccessLogRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to serialize access log record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut file = self.file.lock().await;
+        // Flush each record, since tokio completes file writes in the background.
+        let result = match file.write_all(&line).await {
+            Ok(()) => file.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::access_log::{AccessLogRecord, AccessLogger};
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let path = std::env::temp_dir().join(format!("xenon-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let remote_addr = "127.0.0.1:50000".parse().unwrap();
+        {
+            let logger = AccessLogger::open(&path).await.unwrap();
+            logger
+                .log(&AccessLogRecord::new(
+                    remote_addr,
+                    "POST".to_string(),
+                    "/session".to_string(),
+                    None,
+                    200,
+                    120,
+                ))
+                .await;
+        }
+        // Reopening appends to the existing file.
+        let logger = AccessLogger::open(&path).await.unwrap();
+        logger
+            .log(&AccessLogRecord::new(
+                remote_addr,
+                "GET".to_string(),
+                "/session/abc/url".to_string(),
+                Some("abc".to_string()),
+                404,
+                3,
+            ))
+            .await;
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["method"], "POST");
+        assert_eq!(lines[0]["session_id"], serde_json::Value::Null);
+        assert_eq!(lines[0]["status"], 200);
+        assert_eq!(lines[1]["path"], "/session/abc/url");
+        assert_eq!(lines[1]["session_id"], "abc");
+        assert_eq!(lines[1]["remote_addr"], "127.0.0.1:50000");
+        assert_eq!(lines[1]["elapsed_ms"], 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,9 @@
 use crate::error::XenonError;
+use log::*;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 pub fn default_sessions_per_driver() -> u32 {
     1
@@ -10,24 +13,122 @@ pub fn default_max_sessions() -> u32 {
     5
 }
 
+/// Sessions with no requests for this long are deleted, unless the browser
+/// config specifies otherwise.
+pub const DEFAULT_SESSION_TIMEOUT_SECS: u64 = 1800;
+
+/// How the requested `browserVersion` is matched against the configured `version`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionMatchMode {
+    /// The versions must be identical.
+    Exact,
+    /// The requested version must be the configured version, or a prefix of it made
+    /// of whole components, e.g. "114" and "114.0" match "114.0.5735.90" but "11" does not.
+    #[default]
+    Prefix,
+    /// The major versions must be the same, and the configured version must be at least
+    /// the requested version, e.g. "114.0.5000" matches "114.0.5735.90" but "114.1" does not.
+    /// Versions that are not all numeric must be identical.
+    SemverCompatible,
+}
+
+impl VersionMatchMode {
+    /// Does the configured version satisfy the requested version?
+    pub fn matches(self, configured: &str, requested: &str) -> bool {
+        match self {
+            VersionMatchMode::Exact => configured == requested,
+            VersionMatchMode::Prefix => matches_version_prefix(configured, requested),
+            VersionMatchMode::SemverCompatible => matches_version_semver(configured, requested),
+        }
+    }
+}
+
+/// Is `requested` equal to `configured`, or a prefix of it ending at a `.`?
+fn matches_version_prefix(configured: &str, requested: &str) -> bool {
+    match configured.strip_prefix(requested) {
+        Some(rest) => rest.is_empty() || rest.starts_with('.'),
+        None => false,
+    }
+}
+
+/// Is `configured` the same major version as `requested`, and not older?
+fn matches_version_semver(configured: &str, requested: &str) -> bool {
+    let parse = |version: &str| -> Option<Vec<u64>> {
+        version.split('.').map(|x| x.parse().ok()).collect()
+    };
+    let (mut configured_parts, mut requested_parts) = match (parse(configured), parse(requested)) {
+        (Some(c), Some(r)) => (c, r),
+        _ => return configured == requested,
+    };
+    if configured_parts[0] != requested_parts[0] {
+        return false;
+    }
+    // Missing components count as 0, so "114" is the same as "114.0.0".
+    let len = configured_parts.len().max(requested_parts.len());
+    configured_parts.resize(len, 0);
+    requested_parts.resize(len, 0);
+    configured_parts >= requested_parts
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BrowserConfig {
     name: String,
     version: Option<String>,
+    /// How the requested `browserVersion` is matched against `version`. Default is `prefix`.
+    #[serde(default)]
+    version_match_mode: VersionMatchMode,
+    /// Match the requested `browserVersion` against this regex instead of an exact version.
+    /// Ignored if `version` is also set.
+    version_regex: Option<String>,
+    /// The compiled `version_regex`, or None if it is invalid.
+    #[serde(skip)]
+    version_regex_compiled: OnceLock<Option<Regex>>,
     os: Option<String>,
     /// driver_path always contains a path to a webdriver
     /// It may be configured value or a default one.
     driver_path: Option<PathBuf>,
+    /// Extra arguments for the webdriver. `{PORT}` is replaced with the port,
+    /// otherwise `--port=<port>` is appended.
     args: Option<Vec<String>>,
+    /// The number of additional ports to reserve for each webdriver, for drivers
+    /// that need more than one port. These are consecutive ports after the main
+    /// port, and `{EXTRA_PORT_1}`, `{EXTRA_PORT_2}` etc. in `args` are replaced with them.
+    extra_ports: Option<u32>,
     #[serde(default = "default_sessions_per_driver")]
     sessions_per_driver: u32,
     #[serde(default = "default_max_sessions")]
     max_sessions: u32,
+    /// Additional capabilities to send to the webdriver for every session.
+    /// Capabilities provided by the client take precedence over these.
+    extra_capabilities: Option<serde_json::Value>,
+    /// Default value for the `acceptInsecureCerts` capability, e.g. for test
+    /// environments that use self-signed certificates.
+    accept_insecure_certs: Option<bool>,
+    /// Optional command (and arguments) to run before terminating a webdriver.
+    pre_stop_hook: Option<Vec<String>>,
+    /// If set, only these capability keys will be forwarded to the webdriver.
+    /// Any other keys sent by the client are removed.
+    capabilities_filter: Option<Vec<String>>,
+    /// Delete sessions that have not received a request for this many seconds.
+    /// Default is 1800 (30 minutes).
+    session_timeout_secs: Option<u64>,
+    /// The maximum number of requests per second for each session. Requests over
+    /// the limit get a 429 response. Default is no limit.
+    max_requests_per_second: Option<u32>,
+    /// Start one webdriver for this browser when Xenon starts, so that the first
+    /// session does not have to wait for it.
+    #[serde(default)]
+    pre_warm: bool,
+    /// Keep each webdriver running for this many seconds after its last session ends,
+    /// so that it can be reused by new sessions. By default, webdrivers are terminated
+    /// as soon as they have no sessions.
+    idle_service_timeout_secs: Option<u64>,
 }
 
 impl BrowserConfig {
     pub fn name(&self) -> &str {
-        &self.name.as_str()
+        self.name.as_str()
     }
 
     pub fn driver_path(&self) -> &Path {
@@ -43,6 +144,35 @@ impl BrowserConfig {
         &self.args
     }
 
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn pre_stop_hook(&self) -> Option<&[String]> {
+        self.pre_stop_hook.as_deref()
+    }
+
+    pub fn session_timeout_secs(&self) -> u64 {
+        self.session_timeout_secs
+            .unwrap_or(DEFAULT_SESSION_TIMEOUT_SECS)
+    }
+
+    pub fn pre_warm(&self) -> bool {
+        self.pre_warm
+    }
+
+    pub fn max_requests_per_second(&self) -> Option<u32> {
+        self.max_requests_per_second
+    }
+
+    pub fn extra_ports(&self) -> u32 {
+        self.extra_ports.unwrap_or_default()
+    }
+
+    pub fn idle_service_timeout_secs(&self) -> Option<u64> {
+        self.idle_service_timeout_secs
+    }
+
     pub fn sessions_per_driver(&self) -> u32 {
         self.sessions_per_driver
     }
@@ -51,6 +181,58 @@ impl BrowserConfig {
         self.max_sessions
     }
 
+    /// Add the default capabilities for this browser to the W3C `capabilities` object
+    /// that will be sent to the webdriver. Defaults are merged into `alwaysMatch` and
+    /// never overwrite values provided by the client. Keys that the client sent in
+    /// `firstMatch` are skipped, since a key may not appear in both.
+    pub fn apply_default_capabilities(&self, capabilities: &mut serde_json::Value) {
+        let mut defaults = serde_json::Map::new();
+        if let Some(extra) = self.extra_capabilities.as_ref().and_then(|x| x.as_object()) {
+            defaults.extend(extra.clone());
+        }
+        if let Some(accept_insecure_certs) = self.accept_insecure_certs {
+            defaults
+                .entry("acceptInsecureCerts")
+                .or_insert(serde_json::Value::Bool(accept_insecure_certs));
+        }
+
+        if let Some(entries) = capabilities.get("firstMatch").and_then(|v| v.as_array()) {
+            for entry in entries.iter().filter_map(|x| x.as_object()) {
+                defaults.retain(|key, _| !entry.contains_key(key));
+            }
+        }
+        if !defaults.is_empty() {
+            merge_json_defaults(
+                always_match_mut(capabilities),
+                &serde_json::Value::Object(defaults),
+            );
+        }
+    }
+
+    /// Remove any capability keys not listed in `capabilities_filter` from both the
+    /// W3C `capabilities` (alwaysMatch and each firstMatch entry) and the legacy
+    /// `desiredCapabilities`.
+    pub fn filter_capabilities(
+        &self,
+        capabilities: &mut serde_json::Value,
+        desired_capabilities: &mut serde_json::Value,
+    ) {
+        if let Some(filter) = &self.capabilities_filter {
+            if let Some(always_match) = capabilities.get_mut("alwaysMatch") {
+                retain_keys(always_match, filter);
+            }
+            if let Some(first_match) = capabilities
+                .get_mut("firstMatch")
+                .and_then(|v| v.as_array_mut())
+            {
+                for entry in first_match {
+                    retain_keys(entry, filter);
+                }
+            }
+            retain_keys(desired_capabilities, filter);
+        }
+    }
+
     /// Does this browser match the capabilities we are searching for?
     /// Browser name must match.
     /// For browser version and platform, the following rules apply:
@@ -60,25 +242,41 @@ impl BrowserConfig {
     /// 2. If the actual version or platform is not specified on the browser
     ///    object, it is considered unknown and thus will only match if the
     ///    version or platform is not required.
+    ///
+    /// If `firstMatch` is specified, the browser matches if any of its entries
+    /// (merged with `alwaysMatch`) match.
     pub fn matches_capabilities(&self, capabilities: &Capabilities) -> bool {
-        if self.name.to_lowercase() != capabilities.browser_name().to_lowercase() {
-            return false;
+        capabilities
+            .merged_matches()
+            .iter()
+            .any(|browser_match| self.matches_browser(browser_match))
+    }
+
+    fn matches_browser(&self, capabilities: &BrowserMatch) -> bool {
+        match &capabilities.browser_name {
+            Some(name) if canonical_browser_name(name) == canonical_browser_name(&self.name) => {}
+            _ => return false,
         }
 
-        if let Some(required_version) = capabilities.browser_version() {
+        if let Some(required_version) = &capabilities.browser_version {
             if !required_version.is_empty() {
-                match &self.version {
-                    Some(v) => {
-                        if v != required_version {
+                match (&self.version, self.version_regex()) {
+                    (Some(v), _) => {
+                        if !self.version_match_mode.matches(v, required_version) {
                             return false;
                         }
                     }
-                    None => return false,
+                    (None, Some(re)) => {
+                        if !re.is_match(required_version) {
+                            return false;
+                        }
+                    }
+                    (None, None) => return false,
                 }
             }
         }
 
-        if let Some(required_os) = capabilities.platform_name() {
+        if let Some(required_os) = &capabilities.platform_name {
             let required_os = required_os.to_lowercase();
             if required_os.to_lowercase() != "any" {
                 match &self.os {
@@ -95,9 +293,53 @@ impl BrowserConfig {
         true
     }
 
+    /// The compiled `version_regex`, if set and valid. This is only compiled once.
+    fn version_regex(&self) -> Option<&Regex> {
+        self.version_regex_compiled
+            .get_or_init(|| {
+                let pattern = self.version_regex.as_ref()?;
+                match Regex::new(pattern) {
+                    Ok(re) => Some(re),
+                    Err(e) => {
+                        warn!(
+                            "Invalid version_regex '{}' for browser '{}': {}",
+                            pattern, self.name, e
+                        );
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+
     /// Does a preparation of a config for its usage
     /// sets a default fields, make a validation
     pub fn sanitize(&mut self) -> Result<(), XenonError> {
+        self.name = self.name.trim().to_string();
+        if self.name.is_empty() {
+            return Err(XenonError::ConfigValidation(
+                "Browser name must not be empty".to_string(),
+            ));
+        }
+
+        if let Some(pattern) = &self.version_regex {
+            if let Err(e) = Regex::new(pattern) {
+                return Err(XenonError::ConfigValidation(format!(
+                    "Invalid version_regex for browser '{}': {}",
+                    self.name, e
+                )));
+            }
+        }
+
+        if let Some(hook) = &self.pre_stop_hook {
+            if hook.is_empty() {
+                return Err(XenonError::ConfigValidation(format!(
+                    "pre_stop_hook for browser '{}' must not be empty",
+                    self.name
+                )));
+            }
+        }
+
         if self.driver_path.is_none() {
             let default = default_webdriver(&self.name).ok_or_else(|| {
                 XenonError::ConfigUnexpectedBrowser(
@@ -113,48 +355,559 @@ impl BrowserConfig {
     }
 }
 
+/// Get the `alwaysMatch` object from the W3C `capabilities` object, creating it if needed.
+fn always_match_mut(capabilities: &mut serde_json::Value) -> &mut serde_json::Value {
+    if !capabilities.is_object() {
+        *capabilities = serde_json::json!({});
+    }
+    let always_match = &mut capabilities["alwaysMatch"];
+    if !always_match.is_object() {
+        *always_match = serde_json::json!({});
+    }
+    always_match
+}
+
+/// Recursively merge `defaults` into `target`. Existing values in `target` take precedence.
+fn merge_json_defaults(target: &mut serde_json::Value, defaults: &serde_json::Value) {
+    if let (Some(target), Some(defaults)) = (target.as_object_mut(), defaults.as_object()) {
+        for (key, value) in defaults {
+            match target.get_mut(key) {
+                Some(existing) => merge_json_defaults(existing, value),
+                None => {
+                    target.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Remove all keys from a JSON object, except those in `keys`.
+fn retain_keys(value: &mut serde_json::Value, keys: &[String]) {
+    if let Some(obj) = value.as_object_mut() {
+        obj.retain(|k, _| keys.contains(k));
+    }
+}
+
+/// Normalize a browser name for comparison. Names are case-insensitive, and some
+/// browsers are known by more than one name (e.g. `MicrosoftEdge` is `edge`).
+fn canonical_browser_name(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.as_str() {
+        "microsoftedge" => "edge".to_string(),
+        "internet explorer" => "ie".to_string(),
+        _ => name,
+    }
+}
+
 pub fn default_webdriver<S: AsRef<str>>(browser: S) -> Option<&'static Path> {
-    match browser.as_ref() {
+    match canonical_browser_name(browser.as_ref()).as_str() {
         "firefox" => Some("geckodriver".as_ref()),
         "chrome" => Some("chromedriver".as_ref()),
+        "edge" => Some("msedgedriver".as_ref()),
+        "safari" => Some("safaridriver".as_ref()),
+        "ie" => Some("IEDriverServer".as_ref()),
         _ => None,
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BrowserMatch {
-    browser_name: String,
+    browser_name: Option<String>,
     browser_version: Option<String>,
     platform_name: Option<String>,
 }
 
+impl BrowserMatch {
+    /// Combine with a `firstMatch` entry. Values from `alwaysMatch` (self) take precedence.
+    fn merge(&self, first_match: &BrowserMatch) -> BrowserMatch {
+        BrowserMatch {
+            browser_name: self
+                .browser_name
+                .clone()
+                .or_else(|| first_match.browser_name.clone()),
+            browser_version: self
+                .browser_version
+                .clone()
+                .or_else(|| first_match.browser_version.clone()),
+            platform_name: self
+                .platform_name
+                .clone()
+                .or_else(|| first_match.platform_name.clone()),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Capabilities {
+    #[serde(default, alias = "always_match")]
     always_match: BrowserMatch,
+    #[serde(default, alias = "first_match")]
+    first_match: Option<Vec<BrowserMatch>>,
 }
 
 impl Capabilities {
+    /// The requested browser name, used for logging.
+    /// If it is not in `alwaysMatch`, this is the first one found in `firstMatch`.
     pub fn browser_name(&self) -> &str {
-        &self.always_match.browser_name
+        self.always_match
+            .browser_name
+            .as_deref()
+            .or_else(|| {
+                self.first_match
+                    .iter()
+                    .flatten()
+                    .find_map(|x| x.browser_name.as_deref())
+            })
+            .unwrap_or_default()
     }
 
-    pub fn browser_version(&self) -> &Option<String> {
-        &self.always_match.browser_version
+    /// Each `firstMatch` entry merged with `alwaysMatch`, in order.
+    /// If there are no `firstMatch` entries, this is just `alwaysMatch`.
+    pub fn merged_matches(&self) -> Vec<BrowserMatch> {
+        match &self.first_match {
+            Some(first_match) if !first_match.is_empty() => first_match
+                .iter()
+                .map(|x| self.always_match.merge(x))
+                .collect(),
+            _ => vec![self.always_match.clone()],
+        }
     }
+}
+
+/// The raw W3C `capabilities` object sent by the client.
+/// This must be a JSON object containing only `alwaysMatch` (an object) and/or
+/// `firstMatch` (an array of objects). Anything else is rejected when deserializing.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct CapabilitiesJson(serde_json::Value);
 
-    pub fn platform_name(&self) -> &Option<String> {
-        &self.always_match.platform_name
+impl CapabilitiesJson {
+    pub fn as_value(&self) -> &serde_json::Value {
+        &self.0
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl<'de> Deserialize<'de> for CapabilitiesJson {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let obj = value
+            .as_object()
+            .ok_or_else(|| D::Error::custom("capabilities must be a JSON object"))?;
+        for (key, v) in obj {
+            match key.as_str() {
+                "alwaysMatch" | "always_match" => {
+                    if !v.is_object() {
+                        return Err(D::Error::custom("alwaysMatch must be a JSON object"));
+                    }
+                }
+                "firstMatch" | "first_match" => {
+                    let all_objects = v
+                        .as_array()
+                        .map(|entries| entries.iter().all(|x| x.is_object()));
+                    if all_objects != Some(true) {
+                        return Err(D::Error::custom(
+                            "firstMatch must be an array of JSON objects",
+                        ));
+                    }
+                }
+                k => {
+                    return Err(D::Error::custom(format!(
+                        "unknown key '{}' in capabilities",
+                        k
+                    )));
+                }
+            }
+        }
+        Ok(Self(value))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct W3CCapabilities {
     /// The W3C capabilities object, used to match browser/version/OS etc.
-    pub capabilities: serde_json::Value,
+    pub capabilities: CapabilitiesJson,
     /// All of the additional browser-specific capabilities such as extra arguments etc.
-    #[serde(default)]
+    #[serde(default, alias = "desired_capabilities")]
     pub desired_capabilities: serde_json::Value,
 }
+
+#[cfg(test)]
+mod test {
+    use crate::browser::{
+        default_webdriver, BrowserConfig, Capabilities, CapabilitiesJson, VersionMatchMode,
+        W3CCapabilities, DEFAULT_SESSION_TIMEOUT_SECS,
+    };
+    use std::path::Path;
+
+    #[test]
+    fn test_default_webdriver() {
+        for (name, driver) in [
+            ("firefox", "geckodriver"),
+            ("Chrome", "chromedriver"),
+            ("edge", "msedgedriver"),
+            ("MicrosoftEdge", "msedgedriver"),
+            ("safari", "safaridriver"),
+            ("ie", "IEDriverServer"),
+            ("internet explorer", "IEDriverServer"),
+        ] {
+            assert_eq!(default_webdriver(name), Some(Path::new(driver)));
+        }
+        assert_eq!(default_webdriver("opera"), None);
+    }
+
+    #[test]
+    fn test_matches_edge() {
+        for config_name in ["edge", "MicrosoftEdge"] {
+            let mut browser: BrowserConfig =
+                serde_yaml::from_str(&format!("name: {}", config_name)).unwrap();
+            browser.sanitize().unwrap();
+            assert_eq!(browser.driver_path(), Path::new("msedgedriver"));
+            for browser_name in ["MicrosoftEdge", "edge", "msedge"] {
+                let capabilities: Capabilities = serde_json::from_value(
+                    serde_json::json!({"alwaysMatch": {"browserName": browser_name}}),
+                )
+                .unwrap();
+                assert_eq!(
+                    browser.matches_capabilities(&capabilities),
+                    browser_name != "msedge"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_capabilities_snake_case() {
+        let json = serde_json::json!({
+            "capabilities": { "always_match": { "browserName": "chrome" } },
+            "desired_capabilities": { "browserName": "chrome" }
+        });
+        let w3c_capabilities: W3CCapabilities = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            w3c_capabilities.desired_capabilities["browserName"],
+            "chrome"
+        );
+
+        let capabilities: Capabilities =
+            serde_json::from_value(w3c_capabilities.capabilities.as_value().clone()).unwrap();
+        assert_eq!(capabilities.browser_name(), "chrome");
+    }
+
+    #[test]
+    fn test_extra_capabilities() {
+        let browser: BrowserConfig = serde_yaml::from_str(
+            r#"
+name: chrome
+extra_capabilities:
+  goog:chromeOptions:
+    args: ["--headless"]
+    binary: /usr/bin/chromium
+"#,
+        )
+        .unwrap();
+
+        let mut capabilities = serde_json::json!({
+            "alwaysMatch": {
+                "browserName": "chrome",
+                "goog:chromeOptions": { "args": ["--no-sandbox"] }
+            }
+        });
+        browser.apply_default_capabilities(&mut capabilities);
+        assert_eq!(
+            capabilities,
+            serde_json::json!({
+                "alwaysMatch": {
+                    "browserName": "chrome",
+                    "goog:chromeOptions": {
+                        "args": ["--no-sandbox"],
+                        "binary": "/usr/bin/chromium"
+                    }
+                }
+            })
+        );
+
+        let mut capabilities = serde_json::Value::Null;
+        browser.apply_default_capabilities(&mut capabilities);
+        assert_eq!(
+            capabilities["alwaysMatch"]["goog:chromeOptions"]["args"],
+            serde_json::json!(["--headless"])
+        );
+    }
+
+    #[test]
+    fn test_accept_insecure_certs() {
+        let browser: BrowserConfig =
+            serde_yaml::from_str("name: chrome\naccept_insecure_certs: true").unwrap();
+
+        let mut capabilities = serde_json::json!({ "alwaysMatch": { "browserName": "chrome" } });
+        browser.apply_default_capabilities(&mut capabilities);
+        assert_eq!(capabilities["alwaysMatch"]["acceptInsecureCerts"], true);
+
+        // The client's value takes precedence.
+        let mut capabilities = serde_json::json!({
+            "alwaysMatch": { "browserName": "chrome", "acceptInsecureCerts": false }
+        });
+        browser.apply_default_capabilities(&mut capabilities);
+        assert_eq!(capabilities["alwaysMatch"]["acceptInsecureCerts"], false);
+
+        let mut capabilities = serde_json::json!({
+            "firstMatch": [{ "browserName": "chrome", "acceptInsecureCerts": false }]
+        });
+        browser.apply_default_capabilities(&mut capabilities);
+        assert_eq!(
+            capabilities,
+            serde_json::json!({
+                "firstMatch": [{ "browserName": "chrome", "acceptInsecureCerts": false }]
+            })
+        );
+
+        // Extra capabilities are also skipped if they are in firstMatch.
+        let browser: BrowserConfig = serde_yaml::from_str(
+            r#"
+name: chrome
+accept_insecure_certs: true
+extra_capabilities:
+  goog:chromeOptions: { args: ["--headless"] }
+  pageLoadStrategy: eager
+"#,
+        )
+        .unwrap();
+        let mut capabilities = serde_json::json!({
+            "alwaysMatch": { "browserName": "chrome" },
+            "firstMatch": [
+                { "goog:chromeOptions": { "args": ["--no-sandbox"] } },
+                { "pageLoadStrategy": "none" }
+            ]
+        });
+        browser.apply_default_capabilities(&mut capabilities);
+        assert_eq!(
+            capabilities["alwaysMatch"],
+            serde_json::json!({ "browserName": "chrome", "acceptInsecureCerts": true })
+        );
+
+        // Nothing is added if not configured.
+        let browser: BrowserConfig = serde_yaml::from_str("name: chrome").unwrap();
+        let mut capabilities = serde_json::json!({ "alwaysMatch": { "browserName": "chrome" } });
+        browser.apply_default_capabilities(&mut capabilities);
+        assert!(capabilities["alwaysMatch"]
+            .get("acceptInsecureCerts")
+            .is_none());
+    }
+
+    #[test]
+    fn test_capabilities_filter() {
+        let browser: BrowserConfig = serde_yaml::from_str(
+            r#"
+name: firefox
+capabilities_filter: ["browserName", "moz:firefoxOptions"]
+"#,
+        )
+        .unwrap();
+
+        let mut capabilities = serde_json::json!({
+            "alwaysMatch": { "browserName": "firefox", "se:recordVideo": true },
+            "firstMatch": [{ "moz:firefoxOptions": {}, "unknown": 1 }]
+        });
+        let mut desired_capabilities =
+            serde_json::json!({ "browserName": "firefox", "legacyKey": "x" });
+        browser.filter_capabilities(&mut capabilities, &mut desired_capabilities);
+        assert_eq!(
+            capabilities,
+            serde_json::json!({
+                "alwaysMatch": { "browserName": "firefox" },
+                "firstMatch": [{ "moz:firefoxOptions": {} }]
+            })
+        );
+        assert_eq!(
+            desired_capabilities,
+            serde_json::json!({ "browserName": "firefox" })
+        );
+    }
+
+    #[test]
+    fn test_capabilities_json_strict() {
+        let valid = serde_json::json!({
+            "alwaysMatch": { "browserName": "chrome" },
+            "firstMatch": [{ "platformName": "linux" }, {}]
+        });
+        assert!(serde_json::from_value::<CapabilitiesJson>(valid).is_ok());
+
+        for invalid in [
+            serde_json::json!("chrome"),
+            serde_json::json!({ "alwaysMatch": "chrome" }),
+            serde_json::json!({ "firstMatch": {} }),
+            serde_json::json!({ "firstMatch": ["chrome"] }),
+            serde_json::json!({ "browserName": "chrome" }),
+        ] {
+            assert!(serde_json::from_value::<CapabilitiesJson>(invalid).is_err());
+        }
+    }
+
+    fn browser(yaml: &str) -> BrowserConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn capabilities(json: serde_json::Value) -> Capabilities {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_version_match_mode() {
+        let cases = [
+            // (configured, requested, exact, prefix, semver_compatible)
+            ("114.0.5735.90", "114.0.5735.90", true, true, true),
+            ("114.0.5735.90", "114", false, true, true),
+            ("114.0.5735.90", "114.0", false, true, true),
+            ("114.0.5735.90", "114.0.5735", false, true, true),
+            ("114.0.5735.90", "11", false, false, false),
+            ("114.0.5735.90", "114.0.57", false, false, true),
+            ("114.0.5735.90", "115", false, false, false),
+            ("114.0.5735.90", "113", false, false, false),
+            ("114.0.5735.90", "114.1", false, false, false),
+            ("114.0.5735.90", "114.0.5000", false, false, true),
+            // The client specifies a longer version than the config.
+            ("114", "114.0.5735.90", false, false, false),
+            ("114", "114.0", false, false, true),
+            ("114", "114.0.0", false, false, true),
+            ("114.1", "114.0.5735.90", false, false, true),
+            // Non-numeric versions.
+            ("beta", "beta", true, true, true),
+            ("beta", "BETA", false, false, false),
+            ("114.0-beta", "114", false, true, false),
+            ("114.0-beta", "114.0-beta", true, true, true),
+        ];
+        for (configured, requested, exact, prefix, semver) in cases {
+            for (mode, expected) in [
+                (VersionMatchMode::Exact, exact),
+                (VersionMatchMode::Prefix, prefix),
+                (VersionMatchMode::SemverCompatible, semver),
+            ] {
+                assert_eq!(
+                    mode.matches(configured, requested),
+                    expected,
+                    "{:?}: configured '{}', requested '{}'",
+                    mode,
+                    configured,
+                    requested
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_matches_version_match_mode() {
+        let caps = |version: &str| {
+            capabilities(serde_json::json!({
+                "alwaysMatch": { "browserName": "chrome", "browserVersion": version }
+            }))
+        };
+        let chrome = browser("{name: chrome, version: '114.0.5735.90'}");
+        assert_eq!(chrome.version_match_mode, VersionMatchMode::Prefix);
+        assert!(chrome.matches_capabilities(&caps("114")));
+        assert!(!chrome.matches_capabilities(&caps("114.0.5000")));
+
+        let chrome = browser("{name: chrome, version: '114.0.5735.90', version_match_mode: exact}");
+        assert!(!chrome.matches_capabilities(&caps("114")));
+        assert!(chrome.matches_capabilities(&caps("114.0.5735.90")));
+
+        let chrome = browser(
+            "{name: chrome, version: '114.0.5735.90', version_match_mode: semver_compatible}",
+        );
+        assert!(chrome.matches_capabilities(&caps("114.0.5000")));
+        assert!(!chrome.matches_capabilities(&caps("115")));
+
+        assert!(
+            serde_yaml::from_str::<BrowserConfig>("{name: chrome, version_match_mode: fuzzy}")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_version_regex() {
+        let chrome = browser(r#"{name: chrome, version_regex: '^1[01]\d(\.|$)'}"#);
+        let matches = |version: &str| {
+            chrome.matches_capabilities(&capabilities(serde_json::json!({
+                "alwaysMatch": { "browserName": "chrome", "browserVersion": version }
+            })))
+        };
+        assert!(matches("100"));
+        assert!(matches("119.0.6045.105"));
+        assert!(!matches("120"));
+        assert!(!matches("1000"));
+        // No version requested.
+        assert!(matches(""));
+
+        // An exact version takes precedence.
+        let chrome = browser("{name: chrome, version: '99', version_regex: '.*'}");
+        let caps = capabilities(serde_json::json!({
+            "alwaysMatch": { "browserName": "chrome", "browserVersion": "100" }
+        }));
+        assert!(!chrome.matches_capabilities(&caps));
+
+        let mut invalid = browser("{name: chrome, driver_path: chromedriver, version_regex: '('}");
+        assert!(invalid.sanitize().is_err());
+    }
+
+    #[test]
+    fn test_matches_first_match() {
+        let chrome = browser("{name: chrome, version: '100', os: linux}");
+        let firefox = browser("{name: firefox, os: linux}");
+
+        // No alwaysMatch.
+        let caps = capabilities(serde_json::json!({
+            "firstMatch": [{ "browserName": "firefox" }, { "browserName": "chrome" }]
+        }));
+        assert_eq!(caps.browser_name(), "firefox");
+        assert!(chrome.matches_capabilities(&caps));
+        assert!(firefox.matches_capabilities(&caps));
+
+        // Each firstMatch entry is merged with alwaysMatch.
+        let caps = capabilities(serde_json::json!({
+            "alwaysMatch": { "platformName": "linux" },
+            "firstMatch": [
+                { "browserName": "chrome", "browserVersion": "99" },
+                { "browserName": "firefox" }
+            ]
+        }));
+        assert!(!chrome.matches_capabilities(&caps));
+        assert!(firefox.matches_capabilities(&caps));
+
+        // alwaysMatch takes precedence over firstMatch.
+        let caps = capabilities(serde_json::json!({
+            "alwaysMatch": { "browserName": "chrome" },
+            "firstMatch": [{ "browserName": "firefox", "browserVersion": "100" }]
+        }));
+        assert_eq!(caps.browser_name(), "chrome");
+        assert!(chrome.matches_capabilities(&caps));
+        assert!(!firefox.matches_capabilities(&caps));
+
+        // An empty firstMatch is the same as no firstMatch.
+        let caps = capabilities(serde_json::json!({
+            "alwaysMatch": { "browserName": "chrome" },
+            "firstMatch": []
+        }));
+        assert!(chrome.matches_capabilities(&caps));
+
+        // No browser name at all.
+        let caps = capabilities(serde_json::json!({ "firstMatch": [{}] }));
+        assert_eq!(caps.browser_name(), "");
+        assert!(!chrome.matches_capabilities(&caps));
+    }
+
+    #[test]
+    fn test_session_timeout_secs() {
+        let browser: BrowserConfig =
+            serde_yaml::from_str("name: chrome\nsession_timeout_secs: 300").unwrap();
+        assert_eq!(browser.session_timeout_secs(), 300);
+
+        let browser: BrowserConfig = serde_yaml::from_str("name: firefox").unwrap();
+        assert_eq!(browser.session_timeout_secs(), DEFAULT_SESSION_TIMEOUT_SECS);
+    }
+}
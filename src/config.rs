@@ -2,11 +2,24 @@ use crate::browser::BrowserConfig;
 use crate::error::XenonError;
 use crate::nodes::RemoteNodeCreate;
 use crate::portmanager::ServicePort;
+use crate::ratelimit::RateLimitConfig;
 use log::*;
 use serde::Deserialize;
-use std::path::Path;
+use std::collections::HashSet;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Default, Deserialize)]
+/// How often to check for sessions that have timed out, if not specified in the config.
+const DEFAULT_TIMEOUT_CHECK_INTERVAL_SECS: u64 = 60;
+/// Checking more often than this would mostly just contend for locks.
+const MIN_TIMEOUT_CHECK_INTERVAL_SECS: u64 = 5;
+/// How often to check that remote nodes are still reachable, if not specified in the config.
+const DEFAULT_NODE_HEALTH_CHECK_INTERVAL_SECS: u64 = 30;
+/// A node waits up to 30 seconds for its own WebDriver to start, and then for the browser
+/// to launch, so this needs to be comfortably longer than that.
+const DEFAULT_NODE_TIMEOUT_SECS: u64 = 90;
+
+#[derive(Debug, Default, Clone, Deserialize)]
 pub struct XenonConfig {
     #[serde(default)]
     browsers: Vec<BrowserConfig>,
@@ -14,6 +27,34 @@ pub struct XenonConfig {
     ports: Vec<String>,
     #[serde(default)]
     nodes: Vec<RemoteNodeCreate>,
+    /// Optional prefix for all session ids created by this server.
+    /// Useful for telling apart sessions from multiple Xenon servers behind a load balancer.
+    session_id_prefix: Option<String>,
+    /// Optional per-client rate limiting.
+    rate_limit: Option<RateLimitConfig>,
+    /// Optional path prefix for all routes, e.g. `/selenium` when running behind
+    /// a reverse proxy at a subpath.
+    base_path: Option<String>,
+    /// Value for the `Access-Control-Allow-Origin` header, e.g. `*`.
+    /// CORS headers are only sent when this is set.
+    cors_allow_origin: Option<String>,
+    /// How often to check for timed out sessions, in seconds. Default is 60.
+    timeout_check_interval_secs: Option<u64>,
+    /// How long a new session request may wait for capacity, in seconds.
+    /// Default is 0, which means requests fail immediately if no sessions are available.
+    queue_timeout_secs: Option<u64>,
+    /// How often to check that remote nodes are still reachable, in seconds. Default is 30.
+    node_health_check_interval_secs: Option<u64>,
+    /// How long to wait for a remote node to create a session, in seconds. Default is 90.
+    node_timeout_secs: Option<u64>,
+    /// Send the details of internal errors to clients. Default is false, in which case
+    /// clients only get a reference that can be found in the Xenon log.
+    #[serde(default)]
+    expose_internal_errors: bool,
+    /// Other config files whose `browsers` and `ports` are added to this config.
+    /// Paths are relative to the directory containing this config file.
+    #[serde(default)]
+    include: Vec<String>,
 }
 
 impl XenonConfig {
@@ -24,7 +65,11 @@ impl XenonConfig {
             self.browsers
                 .iter()
                 .fold(0, |acc, browser| acc + browser.max_sessions()) as usize;
-        if port_list.len() < max_sessions {
+        if port_list.is_empty() && max_sessions > 0 {
+            warn!(
+                "No ports configured; add a 'ports' section to the config so that local browsers can be started"
+            );
+        } else if port_list.len() < max_sessions {
             warn!(
                 "Number of ports ({}) is less than the maximum number of sessions ({})",
                 port_list.len(),
@@ -34,8 +79,103 @@ impl XenonConfig {
         port_list
     }
 
-    pub fn has_nodes(&self) -> bool {
-        !self.nodes.is_empty()
+    pub fn session_id_prefix(&self) -> Option<&str> {
+        self.session_id_prefix.as_deref()
+    }
+
+    /// Validate settings that cannot be checked during deserialization.
+    pub fn validate(&self) -> Result<(), XenonError> {
+        if let Some(prefix) = &self.session_id_prefix {
+            if prefix.is_empty()
+                || prefix.len() > 8
+                || !prefix.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return Err(XenonError::ConfigValidation(format!(
+                    "session_id_prefix must be 1-8 alphanumeric characters: '{}'",
+                    prefix
+                )));
+            }
+        }
+
+        let mut node_urls = HashSet::new();
+        for node in &self.nodes {
+            if !node_urls.insert(node.url()) {
+                return Err(XenonError::ConfigValidation(format!(
+                    "Duplicate node URL: {}",
+                    node.url()
+                )));
+            }
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.requests_per_second <= 0.0 || rate_limit.burst == 0 {
+                return Err(XenonError::ConfigValidation(
+                    "rate_limit requests_per_second and burst must both be greater than 0"
+                        .to_string(),
+                ));
+            }
+        }
+
+        if let Some(interval) = self.timeout_check_interval_secs {
+            if interval < MIN_TIMEOUT_CHECK_INTERVAL_SECS {
+                return Err(XenonError::ConfigValidation(format!(
+                    "timeout_check_interval_secs must be at least {}: {}",
+                    MIN_TIMEOUT_CHECK_INTERVAL_SECS, interval
+                )));
+            }
+        }
+
+        if self.node_health_check_interval_secs == Some(0) {
+            return Err(XenonError::ConfigValidation(
+                "node_health_check_interval_secs must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.node_timeout_secs == Some(0) {
+            return Err(XenonError::ConfigValidation(
+                "node_timeout_secs must be greater than 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn timeout_check_interval_secs(&self) -> u64 {
+        self.timeout_check_interval_secs
+            .unwrap_or(DEFAULT_TIMEOUT_CHECK_INTERVAL_SECS)
+    }
+
+    pub fn expose_internal_errors(&self) -> bool {
+        self.expose_internal_errors
+    }
+
+    pub fn queue_timeout_secs(&self) -> u64 {
+        self.queue_timeout_secs.unwrap_or(0)
+    }
+
+    pub fn node_health_check_interval_secs(&self) -> u64 {
+        self.node_health_check_interval_secs
+            .unwrap_or(DEFAULT_NODE_HEALTH_CHECK_INTERVAL_SECS)
+    }
+
+    pub fn node_timeout_secs(&self) -> u64 {
+        self.node_timeout_secs.unwrap_or(DEFAULT_NODE_TIMEOUT_SECS)
+    }
+
+    pub fn rate_limit(&self) -> Option<&RateLimitConfig> {
+        self.rate_limit.as_ref()
+    }
+
+    /// The base path without leading or trailing slashes, or None if not set.
+    pub fn base_path(&self) -> Option<&str> {
+        self.base_path
+            .as_deref()
+            .map(|x| x.trim_matches('/'))
+            .filter(|x| !x.is_empty())
+    }
+
+    pub fn cors_allow_origin(&self) -> Option<&str> {
+        self.cors_allow_origin.as_deref()
     }
 
     /// Get the list of browsers and consume the config.
@@ -46,36 +186,268 @@ impl XenonConfig {
 
 pub fn load_config(config_path: &Path) -> Result<XenonConfig, XenonError> {
     if !config_path.exists() {
-        return Err(XenonError::ConfigNotFound(config_path.to_path_buf()));
+        return match load_config_from_env()? {
+            Some(config) => {
+                info!(
+                    "Config file '{}' not found. Using config from environment variables",
+                    config_path.display()
+                );
+                check_config(config)
+            }
+            None => Err(XenonError::ConfigNotFound(config_path.to_path_buf())),
+        };
+    }
+
+    let config = load_config_file(config_path, &mut Vec::new())?;
+    check_config(config)
+}
+
+/// Load a config file and any files it includes.
+/// `include_stack` contains the files currently being loaded, to detect circular includes.
+fn load_config_file(
+    config_path: &Path,
+    include_stack: &mut Vec<PathBuf>,
+) -> Result<XenonConfig, XenonError> {
+    let load_error = |e: String| XenonError::ConfigLoadError(config_path.to_path_buf(), e);
+    let canonical_path = config_path
+        .canonicalize()
+        .map_err(|e| load_error(e.to_string()))?;
+    if include_stack.contains(&canonical_path) {
+        let chain: Vec<String> = include_stack
+            .iter()
+            .chain(std::iter::once(&canonical_path))
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(load_error(format!(
+            "Circular include: {}",
+            chain.join(" -> ")
+        )));
     }
 
-    let config_str = std::fs::read_to_string(config_path)
-        .map_err(|e| XenonError::ConfigLoadError(config_path.to_path_buf(), e.to_string()))?;
-    let mut config: XenonConfig = serde_yaml::from_str(&config_str)
-        .map_err(|e| XenonError::ConfigLoadError(config_path.to_path_buf(), e.to_string()))?;
+    let config_str = std::fs::read_to_string(config_path).map_err(|e| load_error(e.to_string()))?;
+    let mut config = parse_config(config_path, &config_str)?;
+
+    include_stack.push(canonical_path);
+    let base_dir = config_path.parent().unwrap_or_else(|| Path::new(""));
+    for include in std::mem::take(&mut config.include) {
+        let included = load_config_file(&base_dir.join(include), include_stack)?;
+        config.browsers.extend(included.browsers);
+        config.ports.extend(included.ports);
+    }
+    include_stack.pop();
 
+    Ok(config)
+}
+
+/// Parse a config file as YAML or JSON, depending on the file extension.
+fn parse_config(config_path: &Path, config_str: &str) -> Result<XenonConfig, XenonError> {
+    let extension = config_path
+        .extension()
+        .map(|x| x.to_string_lossy().to_lowercase());
+    let result = match extension.as_deref() {
+        Some("yml") | Some("yaml") => parse_yaml_config(config_str).map_err(|e| e.to_string()),
+        Some("json") => serde_json::from_str(config_str).map_err(|e| e.to_string()),
+        _ => {
+            return Err(XenonError::ConfigUnsupportedFormat(
+                config_path.to_path_buf(),
+            ))
+        }
+    };
+    result.map_err(|e| XenonError::ConfigLoadError(config_path.to_path_buf(), e))
+}
+
+/// Sanitize and validate a newly loaded config.
+fn check_config(mut config: XenonConfig) -> Result<XenonConfig, XenonError> {
     for browser_cfg in &mut config.browsers {
         browser_cfg.sanitize()?;
     }
+    config.validate()?;
 
     Ok(config)
 }
 
+/// The settings that can be given for each browser via environment variables.
+/// Each is read from `XENON_BROWSER_<N>_<SUFFIX>`.
+const BROWSER_ENV_SUFFIXES: &[&str] = &[
+    "NAME",
+    "VERSION",
+    "OS",
+    "DRIVER_PATH",
+    "ARGS",
+    "SESSIONS_PER_DRIVER",
+    "MAX_SESSIONS",
+];
+
+/// Load the config from environment variables, for environments where a config
+/// file cannot easily be provided. Returns None if no config variables are set.
+///
+/// Browsers are numbered from 0, e.g. `XENON_BROWSER_0_NAME=chrome`, and are read
+/// until the first number without a `_NAME`. See `BROWSER_ENV_SUFFIXES` for the other
+/// settings. `_ARGS` is split on whitespace. Ports are read from `XENON_PORTS`
+/// as a comma-separated list, e.g. `XENON_PORTS=40001-41000`.
+pub fn load_config_from_env() -> Result<Option<XenonConfig>, XenonError> {
+    config_from_vars(|key| std::env::var(key).ok())
+}
+
+fn config_from_vars(
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Option<XenonConfig>, XenonError> {
+    let mut browsers = Vec::new();
+    for n in 0.. {
+        let key = |suffix: &str| format!("XENON_BROWSER_{}_{}", n, suffix);
+        let name = match var(&key("NAME")) {
+            Some(name) => name,
+            None => {
+                if let Some(suffix) = BROWSER_ENV_SUFFIXES
+                    .iter()
+                    .find(|suffix| var(&key(suffix)).is_some())
+                {
+                    return Err(XenonError::ConfigValidation(format!(
+                        "{} is set but {} is missing",
+                        key(suffix),
+                        key("NAME")
+                    )));
+                }
+                break;
+            }
+        };
+
+        let number = |suffix: &str| -> Result<Option<u32>, XenonError> {
+            var(&key(suffix))
+                .map(|value| {
+                    value.trim().parse::<u32>().map_err(|_| {
+                        XenonError::ConfigValidation(format!(
+                            "{} must be a number: '{}'",
+                            key(suffix),
+                            value
+                        ))
+                    })
+                })
+                .transpose()
+        };
+
+        let mut browser = serde_json::json!({
+            "name": name,
+            "version": var(&key("VERSION")),
+            "os": var(&key("OS")),
+            "driver_path": var(&key("DRIVER_PATH")),
+            "args": var(&key("ARGS"))
+                .map(|x| x.split_whitespace().map(String::from).collect::<Vec<_>>()),
+        });
+        if let Some(x) = number("SESSIONS_PER_DRIVER")? {
+            browser["sessions_per_driver"] = x.into();
+        }
+        if let Some(x) = number("MAX_SESSIONS")? {
+            browser["max_sessions"] = x.into();
+        }
+        let browser: BrowserConfig = serde_json::from_value(browser).map_err(|e| {
+            XenonError::ConfigValidation(format!("Invalid browser {}: {}", key("*"), e))
+        })?;
+        browsers.push(browser);
+    }
+
+    let ports: Vec<String> = var("XENON_PORTS")
+        .map(|x| {
+            x.split(',')
+                .map(|port| port.trim().to_string())
+                .filter(|port| !port.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if browsers.is_empty() && ports.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(XenonConfig {
+        browsers,
+        ports,
+        ..Default::default()
+    }))
+}
+
+/// Parse the YAML config, including support for YAML merge keys.
+///
+/// Anchors and merge keys can be used to share settings between browsers, e.g.
+///
+/// ```yaml
+/// x-chrome: &chrome
+///   driver_path: /usr/local/bin/chromedriver
+///   max_sessions: 5
+/// browsers:
+///   - <<: *chrome
+///     name: chrome
+///   - <<: *chrome
+///     name: chromium
+///     max_sessions: 10
+/// ```
+///
+/// Keys set directly on an entry take precedence over merged keys.
+fn parse_yaml_config(config_str: &str) -> Result<XenonConfig, serde_yaml::Error> {
+    let mut value: serde_yaml::Value = serde_yaml::from_str(config_str)?;
+    apply_merge_keys(&mut value);
+    serde_yaml::from_value(value)
+}
+
+/// Recursively resolve YAML merge keys (`<<`), which serde_yaml does not handle itself.
+/// Aliases have already been expanded by the parser at this point.
+fn apply_merge_keys(value: &mut serde_yaml::Value) {
+    match value {
+        serde_yaml::Value::Mapping(map) => {
+            for (_, v) in map.iter_mut() {
+                apply_merge_keys(v);
+            }
+
+            let merge_key = serde_yaml::Value::String("<<".to_string());
+            if let Some(merged) = map.remove(&merge_key) {
+                let sources = match merged {
+                    serde_yaml::Value::Sequence(seq) => seq,
+                    other => vec![other],
+                };
+                for source in sources {
+                    if let serde_yaml::Value::Mapping(source) = source {
+                        for (k, v) in source {
+                            if !map.contains_key(&k) {
+                                map.insert(k, v);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        serde_yaml::Value::Sequence(seq) => {
+            for v in seq.iter_mut() {
+                apply_merge_keys(v);
+            }
+        }
+        _ => {}
+    }
+}
+
 pub fn parse_port_list<T: AsRef<str>>(port_ranges: &[T]) -> Vec<ServicePort> {
     let mut ports = Vec::new();
 
     for port_range in port_ranges {
         let range = port_range.as_ref();
+        if let Some(count) = range.strip_prefix("auto:") {
+            match count.parse::<usize>() {
+                Ok(n) => ports.extend(find_free_ports(n)),
+                Err(e) => {
+                    error!("Invalid port count in '{}': {}", range, e.to_string());
+                }
+            }
+            continue;
+        }
+
         let parts: Vec<&str> = range.splitn(2, '-').collect();
         match parts.len() {
-            1 => match parts[0].parse::<ServicePort>() {
-                Ok(x) => ports.push(x),
+            1 => match parts[0].parse::<u16>() {
+                Ok(x) => ports.push(ServicePort::from(x)),
                 Err(e) => {
                     error!("Invalid port '{}': {}", range, e.to_string());
                 }
             },
             2 => {
-                let start: ServicePort = match parts[0].parse() {
+                let start: u16 = match parts[0].parse() {
                     Ok(x) => x,
                     Err(e) => {
                         error!(
@@ -87,7 +459,7 @@ pub fn parse_port_list<T: AsRef<str>>(port_ranges: &[T]) -> Vec<ServicePort> {
                         continue;
                     }
                 };
-                let end: ServicePort = match parts[1].parse() {
+                let end: u16 = match parts[1].parse() {
                     Ok(x) => x,
                     Err(e) => {
                         error!(
@@ -107,9 +479,7 @@ pub fn parse_port_list<T: AsRef<str>>(port_ranges: &[T]) -> Vec<ServicePort> {
                     error!("Start port must precede end port");
                     continue;
                 }
-                for p in start..=end {
-                    ports.push(p);
-                }
+                ports.extend((start..=end).map(ServicePort::from));
             }
             _ => unreachable!(),
         }
@@ -118,31 +488,419 @@ pub fn parse_port_list<T: AsRef<str>>(port_ranges: &[T]) -> Vec<ServicePort> {
     ports
 }
 
+/// Find `n` free ports by asking the OS for them.
+/// Note that the ports are released again before returning, so another process
+/// could still claim one of them before a WebDriver is started on it.
+pub fn find_free_ports(n: usize) -> Vec<ServicePort> {
+    // Keep all listeners open until done, so that the same port is not returned twice.
+    let mut listeners = Vec::with_capacity(n);
+    for _ in 0..n {
+        match TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr().map(|a| (l, a))) {
+            Ok((listener, addr)) => listeners.push((listener, addr.port())),
+            Err(e) => {
+                error!("Unable to find a free port: {}", e.to_string());
+                break;
+            }
+        }
+    }
+    listeners
+        .into_iter()
+        .map(|(_, port)| ServicePort::from(port))
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
-    use crate::config::parse_port_list;
+    use crate::config::{
+        config_from_vars, load_config, parse_config, parse_port_list, parse_yaml_config,
+        XenonConfig,
+    };
+    use crate::error::XenonError;
+    use crate::portmanager::ServicePort;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    fn ports(ports: &[u16]) -> Vec<ServicePort> {
+        ports.iter().copied().map(ServicePort::from).collect()
+    }
 
     #[test]
     fn test_port_parser_empty() {
-        let empty_vec: Vec<u16> = Vec::new();
+        let empty_vec: Vec<ServicePort> = Vec::new();
         let empty_input_vec: Vec<String> = Vec::new();
         assert_eq!(parse_port_list(&empty_input_vec), empty_vec);
     }
 
     #[test]
     fn test_port_parser_single() {
-        assert_eq!(parse_port_list(&["2000"]), vec![2000]);
+        assert_eq!(parse_port_list(&["2000"]), ports(&[2000]));
     }
 
     #[test]
     fn test_port_parser_range() {
-        assert_eq!(parse_port_list(&["2000-2001"]), vec![2000, 2001]);
-        assert_eq!(parse_port_list(&["2000-2000"]), vec![2000]);
+        assert_eq!(parse_port_list(&["2000-2001"]), ports(&[2000, 2001]));
+        assert_eq!(parse_port_list(&["2000-2000"]), ports(&[2000]));
 
         // Errors are logged but ignored.
-        let empty_vec: Vec<u16> = Vec::new();
+        let empty_vec: Vec<ServicePort> = Vec::new();
         assert_eq!(parse_port_list(&["1000-2000"]), empty_vec);
         assert_eq!(parse_port_list(&["2000-3000-4000"]), empty_vec);
-        assert_eq!(parse_port_list(&["2000-2001", "adfasd"]), vec![2000, 2001]);
+        assert_eq!(
+            parse_port_list(&["2000-2001", "adfasd"]),
+            ports(&[2000, 2001])
+        );
+    }
+
+    #[test]
+    fn test_port_parser_auto() {
+        let ports = parse_port_list(&["auto:3"]);
+        assert_eq!(ports.len(), 3);
+        let unique: std::collections::HashSet<_> = ports.iter().collect();
+        assert_eq!(unique.len(), 3);
+
+        let empty_vec: Vec<ServicePort> = Vec::new();
+        assert_eq!(parse_port_list(&["auto:x"]), empty_vec);
+    }
+
+    #[test]
+    fn test_session_id_prefix() {
+        let config: XenonConfig = serde_yaml::from_str("session_id_prefix: hub1").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.session_id_prefix(), Some("hub1"));
+
+        for prefix in ["", "toolong123", "hub-1"] {
+            let config = XenonConfig {
+                session_id_prefix: Some(prefix.to_string()),
+                ..Default::default()
+            };
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_timeout_check_interval_secs() {
+        let config = XenonConfig::default();
+        assert_eq!(config.timeout_check_interval_secs(), 60);
+
+        let config: XenonConfig = serde_yaml::from_str("timeout_check_interval_secs: 5").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.timeout_check_interval_secs(), 5);
+
+        let config: XenonConfig = serde_yaml::from_str("timeout_check_interval_secs: 4").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_node_health_check_interval_secs() {
+        let config = XenonConfig::default();
+        assert_eq!(config.node_health_check_interval_secs(), 30);
+
+        let config: XenonConfig =
+            serde_yaml::from_str("node_health_check_interval_secs: 10").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.node_health_check_interval_secs(), 10);
+
+        let config: XenonConfig =
+            serde_yaml::from_str("node_health_check_interval_secs: 0").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_node_timeout_secs() {
+        let config = XenonConfig::default();
+        assert_eq!(config.node_timeout_secs(), 90);
+
+        let config: XenonConfig = serde_yaml::from_str("node_timeout_secs: 5").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.node_timeout_secs(), 5);
+
+        let config: XenonConfig = serde_yaml::from_str("node_timeout_secs: 0").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    fn env_config(vars: &[(&str, &str)]) -> Result<Option<XenonConfig>, XenonError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        config_from_vars(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        assert!(env_config(&[]).unwrap().is_none());
+
+        let config = env_config(&[
+            ("XENON_BROWSER_0_NAME", "chrome"),
+            ("XENON_BROWSER_0_DRIVER_PATH", "/usr/local/bin/chromedriver"),
+            ("XENON_BROWSER_0_MAX_SESSIONS", "10"),
+            ("XENON_BROWSER_0_ARGS", "--verbose  --log-path=/tmp/x.log"),
+            ("XENON_BROWSER_1_NAME", "firefox"),
+            ("XENON_BROWSER_1_VERSION", "91"),
+            ("XENON_BROWSER_1_SESSIONS_PER_DRIVER", " 1 "),
+            // Not read because there is no browser 2.
+            ("XENON_BROWSER_3_NAME", "safari"),
+            ("XENON_PORTS", "40001-40010, 40020,"),
+        ])
+        .unwrap()
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.browsers.len(), 2);
+        assert_eq!(config.browsers[0].name(), "chrome");
+        assert_eq!(config.browsers[0].max_sessions(), 10);
+        assert_eq!(
+            config.browsers[0].driver_path(),
+            std::path::Path::new("/usr/local/bin/chromedriver")
+        );
+        assert_eq!(
+            config.browsers[0].args(),
+            &Some(vec![
+                "--verbose".to_string(),
+                "--log-path=/tmp/x.log".to_string()
+            ])
+        );
+        assert_eq!(config.browsers[1].name(), "firefox");
+        assert_eq!(config.browsers[1].version(), Some("91"));
+        assert_eq!(config.browsers[1].max_sessions(), 5);
+        assert_eq!(config.browsers[1].sessions_per_driver(), 1);
+        assert_eq!(config.ports, vec!["40001-40010", "40020"]);
+
+        // Ports only.
+        let config = env_config(&[("XENON_PORTS", "auto:2")]).unwrap().unwrap();
+        assert!(config.browsers.is_empty());
+        assert_eq!(config.ports, vec!["auto:2"]);
+    }
+
+    #[test]
+    fn test_config_from_env_errors() {
+        for value in ["abc", "-1", "1.5", ""] {
+            let result = env_config(&[
+                ("XENON_BROWSER_0_NAME", "chrome"),
+                ("XENON_BROWSER_0_MAX_SESSIONS", value),
+            ]);
+            assert!(matches!(result, Err(XenonError::ConfigValidation(_))));
+        }
+
+        let result = env_config(&[("XENON_BROWSER_0_DRIVER_PATH", "/usr/local/bin/chromedriver")]);
+        assert!(matches!(result, Err(XenonError::ConfigValidation(_))));
+
+        let result = env_config(&[
+            ("XENON_BROWSER_0_NAME", "chrome"),
+            ("XENON_BROWSER_1_MAX_SESSIONS", "3"),
+        ]);
+        assert!(matches!(result, Err(XenonError::ConfigValidation(_))));
+    }
+
+    #[test]
+    fn test_yaml_merge_keys() {
+        let config = parse_yaml_config(
+            r#"
+x-base: &base
+  driver_path: /usr/local/bin/chromedriver
+  max_sessions: 3
+browsers:
+  - <<: *base
+    name: chrome
+  - <<: *base
+    name: chromium
+    max_sessions: 7
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.browsers.len(), 2);
+        assert_eq!(config.browsers[0].name(), "chrome");
+        assert_eq!(config.browsers[0].max_sessions(), 3);
+        assert_eq!(config.browsers[1].name(), "chromium");
+        assert_eq!(config.browsers[1].max_sessions(), 7);
+        assert_eq!(
+            config.browsers[1].driver_path(),
+            std::path::Path::new("/usr/local/bin/chromedriver")
+        );
+    }
+
+    #[test]
+    fn test_browsers_and_nodes() {
+        let config = parse_yaml_config(
+            r#"
+browsers:
+  - name: chrome
+  - name: firefox
+    max_sessions: 2
+nodes:
+  - url: http://localhost:8888
+  - name: node2
+    url: http://localhost:8889
+    auth_token: secret
+    service_groups:
+      - browser:
+          name: chrome
+        initial_capacity: 3
+"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let (browsers, nodes) = config.browsers_and_nodes();
+        assert_eq!(browsers.len(), 2);
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].url(), "http://localhost:8888");
+        assert_eq!(nodes[1].url(), "http://localhost:8889");
+    }
+
+    #[test]
+    fn test_duplicate_node_urls() {
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+nodes:
+  - name: node1
+    url: http://localhost:8888
+  - name: node2
+    url: http://localhost:8889
+"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+nodes:
+  - name: node1
+    url: http://localhost:8888
+  - name: node2
+    url: http://localhost:8888
+"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    /// Write each file to a new temporary directory and return the directory.
+    fn write_config_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xenon-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        for (filename, contents) in files {
+            std::fs::write(dir.join(filename), contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = write_config_files(
+            "include",
+            &[
+                (
+                    "xenon.yml",
+                    "include: [shared/browsers.yml]\nbrowsers:\n  - name: chrome\nports: ['4444']",
+                ),
+                (
+                    "shared/browsers.yml",
+                    "include: [ports.yml]\nbrowsers:\n  - name: firefox",
+                ),
+                ("shared/ports.yml", "ports: ['5555-5556']"),
+            ],
+        );
+        let config = load_config(&dir.join("xenon.yml")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (browsers, _) = config.clone().browsers_and_nodes();
+        let names: Vec<&str> = browsers.iter().map(|b| b.name()).collect();
+        assert_eq!(names, vec!["chrome", "firefox"]);
+        assert_eq!(config.get_port_list(), ports(&[4444, 5555, 5556]));
+    }
+
+    #[test]
+    fn test_circular_include() {
+        let dir = write_config_files(
+            "circular-include",
+            &[
+                ("xenon.yml", "include: [shared/a.yml]"),
+                ("shared/a.yml", "include: [b.yml]"),
+                ("shared/b.yml", "include: [../xenon.yml]"),
+            ],
+        );
+        let result = load_config(&dir.join("xenon.yml"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(XenonError::ConfigLoadError(path, message)) => {
+                assert!(path.ends_with("shared/../xenon.yml"));
+                assert!(message.starts_with("Circular include"));
+            }
+            other => panic!("Expected ConfigLoadError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_json_config() {
+        let yaml = r#"
+browsers:
+  - name: chrome
+    driver_path: /usr/local/bin/chromedriver
+    max_sessions: 10
+  - name: firefox
+    version: "110"
+    args: ["--log", "debug"]
+ports:
+  - "4444"
+  - "5000-5002"
+nodes:
+  - name: node1
+    url: http://localhost:8888
+"#;
+        let json = r#"{
+            "browsers": [
+                {
+                    "name": "chrome",
+                    "driver_path": "/usr/local/bin/chromedriver",
+                    "max_sessions": 10
+                },
+                { "name": "firefox", "version": "110", "args": ["--log", "debug"] }
+            ],
+            "ports": ["4444", "5000-5002"],
+            "nodes": [{ "name": "node1", "url": "http://localhost:8888" }]
+        }"#;
+
+        let yaml_config = parse_config(Path::new("xenon.yml"), yaml).unwrap();
+        let yaml_config_alt = parse_config(Path::new("xenon.YAML"), yaml).unwrap();
+        let json_config = parse_config(Path::new("xenon.json"), json).unwrap();
+        assert_eq!(
+            format!("{:?}", yaml_config),
+            format!("{:?}", yaml_config_alt)
+        );
+        assert_eq!(
+            json_config.get_port_list(),
+            ports(&[4444, 5000, 5001, 5002])
+        );
+        assert_eq!(yaml_config.get_port_list(), json_config.get_port_list());
+
+        let (yaml_browsers, yaml_nodes) = yaml_config.browsers_and_nodes();
+        let (json_browsers, json_nodes) = json_config.browsers_and_nodes();
+        assert_eq!(json_browsers.len(), 2);
+        assert_eq!(
+            serde_json::to_value(&yaml_browsers).unwrap(),
+            serde_json::to_value(&json_browsers).unwrap()
+        );
+        assert_eq!(json_nodes.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&yaml_nodes).unwrap(),
+            serde_json::to_value(&json_nodes).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unsupported_config_format() {
+        for filename in ["xenon.toml", "xenon"] {
+            match parse_config(Path::new(filename), "") {
+                Err(XenonError::ConfigUnsupportedFormat(path)) => {
+                    assert_eq!(path, Path::new(filename))
+                }
+                other => panic!("Expected ConfigUnsupportedFormat, got {:?}", other),
+            }
+        }
+
+        match parse_config(Path::new("xenon.json"), "browsers: []") {
+            Err(XenonError::ConfigLoadError(..)) => {}
+            other => panic!("Expected ConfigLoadError, got {:?}", other),
+        }
     }
 }
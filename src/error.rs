@@ -8,6 +8,8 @@ pub type XenonResult<T> = Result<T, XenonError>;
 pub enum XenonError {
     #[error("Invalid port specified")]
     InvalidPort,
+    #[error("Invalid bind address: {0}")]
+    InvalidBindAddress(String),
     #[error("Server error: {0}")]
     ServerError(String),
     #[error("WebDriver request failed: {0}")]
@@ -16,14 +18,21 @@ pub enum XenonError {
     ConfigNotFound(PathBuf),
     #[error("Error loading config from file '{0}': {1}")]
     ConfigLoadError(PathBuf, String),
+    #[error("Unsupported config file format (expected .yml, .yaml or .json): {0}")]
+    ConfigUnsupportedFormat(PathBuf),
+    #[error("Invalid config: {0}")]
+    ConfigValidation(String),
     #[error("Encountered an unexpected browser in config '{0}': {1}")]
     ConfigUnexpectedBrowser(String, String),
     #[error("Error response returned to client")]
     RespondWith(XenonResponse),
+    #[error("Timed out waiting for node: {0}")]
+    NodeTimeout(String),
     #[error("WebDriver response passed through to client")]
-    ResponsePassThrough(Response<Body>),
+    ResponsePassThrough(Box<Response<Body>>),
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {0}")]
+    TlsError(String),
     #[error("IO Error: {0}")]
     IOError(#[from] std::io::Error),
-    #[error("No sessions available for this service")]
-    NoSessionsAvailable,
 }
@@ -0,0 +1,4 @@
+//! Types shared between the Xenon server and its clients.
+mod session_id;
+
+pub use crate::session_id::XenonSessionId;
@@ -1,23 +1,93 @@
 use crate::server::start_server;
-use env_logger::Env;
+use std::env;
 
+mod access_log;
 mod browser;
 mod config;
 mod error;
+mod metrics;
 mod nodes;
 mod portmanager;
+mod queue;
+mod ratelimit;
+mod request_util;
 mod response;
 mod server;
 mod service;
 mod session;
 mod state;
+mod status;
+#[cfg(feature = "tls")]
+mod tls;
 
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("xenon=debug")).init();
+    let filters = log_filters(
+        env::var("RUST_LOG").ok().as_deref(),
+        env::var("XENON_LOG").ok().as_deref(),
+    );
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&filters);
+    if let Ok(style) = env::var("RUST_LOG_STYLE") {
+        builder.parse_write_style(&style);
+    }
+    builder.init();
 
     if let Err(e) = start_server().await {
         println!("Xenon server stopped.\nERROR: {:?}", e);
         std::process::exit(1);
     }
 }
+
+/// Build the log filter from `RUST_LOG` and `XENON_LOG`.
+///
+/// `XENON_LOG` sets the level for Xenon only (e.g. `XENON_LOG=info`), without
+/// affecting other crates. It takes precedence over any Xenon directives in `RUST_LOG`.
+fn log_filters(rust_log: Option<&str>, xenon_log: Option<&str>) -> String {
+    let xenon_log = match xenon_log {
+        Some(x) => x,
+        None => return rust_log.unwrap_or("xenon=debug").to_string(),
+    };
+
+    // RUST_LOG may end with `/regex` to filter messages.
+    let (directives, regex) = rust_log
+        .map(|x| x.split_once('/').unwrap_or((x, "")))
+        .unwrap_or_default();
+    let xenon_directive = format!("xenon={}", xenon_log);
+    let mut filters: Vec<&str> = directives
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with("xenon"))
+        .collect();
+    filters.push(&xenon_directive);
+
+    let mut result = filters.join(",");
+    if !regex.is_empty() {
+        result.push('/');
+        result.push_str(regex);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use crate::log_filters;
+
+    #[test]
+    fn test_log_filters() {
+        assert_eq!(log_filters(None, None), "xenon=debug");
+        assert_eq!(log_filters(Some("info"), None), "info");
+        assert_eq!(log_filters(None, Some("warn")), "xenon=warn");
+        assert_eq!(
+            log_filters(Some("hyper=debug,xenon=trace"), Some("info")),
+            "hyper=debug,xenon=info"
+        );
+        assert_eq!(
+            log_filters(
+                Some("warn,xenon_webdriver::server=trace/session"),
+                Some("info")
+            ),
+            "warn,xenon=info/session"
+        );
+    }
+}
@@ -0,0 +1,337 @@
+//! Prometheus metrics, exposed at /metrics when built with the `metrics` feature.
+//! Without the feature, recording metrics is a no-op.
+
+#[cfg(feature = "metrics")]
+use crate::error::{XenonError, XenonResult};
+#[cfg(feature = "metrics")]
+use crate::nodes::NodeHealthStatus;
+#[cfg(feature = "metrics")]
+use crate::response::XenonResponse;
+#[cfg(feature = "metrics")]
+use crate::state::SessionTotals;
+#[cfg(feature = "metrics")]
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+#[cfg(feature = "metrics")]
+use std::sync::OnceLock;
+use tokio::time::Duration;
+
+#[cfg(feature = "metrics")]
+struct Metrics {
+    registry: Registry,
+    sessions_created: IntCounter,
+    sessions_deleted: IntCounter,
+    sessions_timed_out: IntCounter,
+    sessions_active: IntGauge,
+    services_active: IntGauge,
+    service_peak_concurrent_sessions: IntGaugeVec,
+    service_spawn_failures: IntCounterVec,
+    nodes_registered: IntGauge,
+    nodes_reachable: IntGauge,
+    node_reachable: IntGaugeVec,
+    node_consecutive_failures: IntGaugeVec,
+    node_sessions: IntGaugeVec,
+    session_create_seconds: Histogram,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn new() -> Self {
+        let metrics = Self {
+            registry: Registry::new(),
+            sessions_created: IntCounter::new(
+                "xenon_sessions_created_total",
+                "Total number of sessions created",
+            )
+            .unwrap(),
+            sessions_deleted: IntCounter::new(
+                "xenon_sessions_deleted_total",
+                "Total number of sessions deleted by clients",
+            )
+            .unwrap(),
+            sessions_timed_out: IntCounter::new(
+                "xenon_sessions_timed_out_total",
+                "Total number of sessions deleted due to inactivity",
+            )
+            .unwrap(),
+            sessions_active: IntGauge::new("xenon_sessions_active", "Number of active sessions")
+                .unwrap(),
+            services_active: IntGauge::new(
+                "xenon_services_active",
+                "Number of running WebDriver processes",
+            )
+            .unwrap(),
+            service_peak_concurrent_sessions: IntGaugeVec::new(
+                Opts::new(
+                    "xenon_service_peak_concurrent_sessions",
+                    "Highest number of sessions any one WebDriver in the group has had at once",
+                ),
+                &["group"],
+            )
+            .unwrap(),
+            service_spawn_failures: IntCounterVec::new(
+                Opts::new(
+                    "xenon_service_spawn_failures_total",
+                    "Total number of failed attempts to spawn a WebDriver in the group",
+                ),
+                &["group"],
+            )
+            .unwrap(),
+            nodes_registered: IntGauge::new(
+                "xenon_nodes_registered",
+                "Number of registered remote nodes",
+            )
+            .unwrap(),
+            nodes_reachable: IntGauge::new(
+                "xenon_nodes_reachable",
+                "Number of remote nodes that passed their last health check",
+            )
+            .unwrap(),
+            node_reachable: IntGaugeVec::new(
+                Opts::new(
+                    "xenon_node_reachable",
+                    "Whether the remote node passed its last health check (1) or not (0)",
+                ),
+                &["node", "url"],
+            )
+            .unwrap(),
+            node_consecutive_failures: IntGaugeVec::new(
+                Opts::new(
+                    "xenon_node_consecutive_failures",
+                    "Number of consecutive failed health checks for the remote node",
+                ),
+                &["node", "url"],
+            )
+            .unwrap(),
+            node_sessions: IntGaugeVec::new(
+                Opts::new(
+                    "xenon_node_sessions",
+                    "Number of sessions this server has on the remote node",
+                ),
+                &["node", "url"],
+            )
+            .unwrap(),
+            session_create_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "xenon_session_create_seconds",
+                    "Time taken to create a session, including WebDriver startup",
+                )
+                .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            )
+            .unwrap(),
+        };
+
+        // Registration only fails for duplicate or inconsistent metrics.
+        metrics
+            .registry
+            .register(Box::new(metrics.sessions_created.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.sessions_deleted.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.sessions_timed_out.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.sessions_active.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.services_active.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.service_peak_concurrent_sessions.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.service_spawn_failures.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.nodes_registered.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.nodes_reachable.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.node_reachable.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.node_consecutive_failures.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.node_sessions.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.session_create_seconds.clone()))
+            .unwrap();
+        metrics
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+pub fn observe_session_create_time(_elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics()
+        .session_create_seconds
+        .observe(_elapsed.as_secs_f64());
+}
+
+/// Values read from the current state whenever metrics are scraped.
+#[cfg(feature = "metrics")]
+pub struct MetricsSnapshot {
+    pub sessions_active: usize,
+    pub services_active: usize,
+    /// The peak concurrent sessions per WebDriver, for each service group.
+    pub peak_concurrent_sessions: Vec<(String, usize)>,
+    /// The total number of failed WebDriver spawn attempts, for each service group.
+    pub spawn_failures: Vec<(String, u64)>,
+    /// The health of each remote node, exported as per-node gauges.
+    pub nodes: Vec<NodeHealthStatus>,
+    pub totals: SessionTotals,
+}
+
+/// Bring a counter up to date with a total that is tracked elsewhere.
+#[cfg(feature = "metrics")]
+fn sync_counter(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
+
+/// Render all metrics in the Prometheus text format.
+#[cfg(feature = "metrics")]
+pub fn render(snapshot: &MetricsSnapshot) -> XenonResult<String> {
+    let m = metrics();
+    m.sessions_active.set(snapshot.sessions_active as i64);
+    m.services_active.set(snapshot.services_active as i64);
+    for (group, peak) in &snapshot.peak_concurrent_sessions {
+        m.service_peak_concurrent_sessions
+            .with_label_values(&[group])
+            .set(*peak as i64);
+    }
+    for (group, failures) in &snapshot.spawn_failures {
+        sync_counter(
+            &m.service_spawn_failures.with_label_values(&[group]),
+            *failures,
+        );
+    }
+    m.nodes_registered.set(snapshot.nodes.len() as i64);
+    m.nodes_reachable
+        .set(snapshot.nodes.iter().filter(|node| node.reachable).count() as i64);
+    // Nodes can be removed at runtime, so drop the old labels rather than leaving stale values.
+    m.node_reachable.reset();
+    m.node_consecutive_failures.reset();
+    m.node_sessions.reset();
+    for node in &snapshot.nodes {
+        let id = node.id.to_string();
+        let labels = [id.as_str(), node.url.as_str()];
+        m.node_reachable
+            .with_label_values(&labels)
+            .set(node.reachable as i64);
+        m.node_consecutive_failures
+            .with_label_values(&labels)
+            .set(node.consecutive_failures as i64);
+        m.node_sessions
+            .with_label_values(&labels)
+            .set(node.sessions as i64);
+    }
+    sync_counter(&m.sessions_created, snapshot.totals.created);
+    sync_counter(&m.sessions_deleted, snapshot.totals.deleted);
+    sync_counter(&m.sessions_timed_out, snapshot.totals.timed_out);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&m.registry.gather(), &mut buffer)
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InternalServerError(e.to_string())))?;
+    String::from_utf8(buffer)
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InternalServerError(e.to_string())))
+}
+
+#[cfg(test)]
+#[cfg(feature = "metrics")]
+mod test {
+    use crate::metrics::{observe_session_create_time, render, MetricsSnapshot};
+    use crate::nodes::{NodeHealthStatus, NodeId};
+    use crate::state::SessionTotals;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_render() {
+        observe_session_create_time(Duration::from_millis(300));
+        let mut snapshot = MetricsSnapshot {
+            sessions_active: 1,
+            services_active: 2,
+            peak_concurrent_sessions: vec![("chrome-any".to_string(), 3)],
+            spawn_failures: vec![("chrome-any".to_string(), 2)],
+            nodes: vec![
+                NodeHealthStatus {
+                    id: NodeId::from("node-a"),
+                    name: "a".to_string(),
+                    url: "http://127.0.0.1:4445".to_string(),
+                    reachable: true,
+                    consecutive_failures: 0,
+                    last_contact: None,
+                    capacity: 4,
+                    sessions: 1,
+                },
+                NodeHealthStatus {
+                    id: NodeId::from("node-b"),
+                    name: "b".to_string(),
+                    url: "http://127.0.0.1:4446".to_string(),
+                    reachable: false,
+                    consecutive_failures: 3,
+                    last_contact: None,
+                    capacity: 0,
+                    sessions: 0,
+                },
+            ],
+            totals: SessionTotals {
+                created: 5,
+                deleted: 3,
+                timed_out: 1,
+            },
+        };
+        let output = render(&snapshot).unwrap();
+        assert!(output.contains("xenon_sessions_created_total 5"));
+        assert!(output.contains("xenon_sessions_deleted_total 3"));
+        assert!(output.contains("xenon_sessions_timed_out_total 1"));
+        assert!(output.contains("xenon_sessions_active 1"));
+        assert!(output.contains("xenon_services_active 2"));
+        assert!(output.contains("xenon_service_peak_concurrent_sessions{group=\"chrome-any\"} 3"));
+        assert!(output.contains("xenon_service_spawn_failures_total{group=\"chrome-any\"} 2"));
+        assert!(output.contains("xenon_nodes_registered 2"));
+        assert!(output.contains("xenon_nodes_reachable 1"));
+        assert!(output
+            .contains("xenon_node_reachable{node=\"node-a\",url=\"http://127.0.0.1:4445\"} 1"));
+        assert!(output
+            .contains("xenon_node_reachable{node=\"node-b\",url=\"http://127.0.0.1:4446\"} 0"));
+        assert!(output.contains(
+            "xenon_node_consecutive_failures{node=\"node-b\",url=\"http://127.0.0.1:4446\"} 3"
+        ));
+        assert!(
+            output.contains("xenon_node_sessions{node=\"node-a\",url=\"http://127.0.0.1:4445\"} 1")
+        );
+        assert!(output.contains("xenon_session_create_seconds_bucket{le=\"0.5\"} 1"));
+
+        snapshot.totals.created = 7;
+        snapshot.nodes.pop();
+        let output = render(&snapshot).unwrap();
+        assert!(output.contains("xenon_sessions_created_total 7"));
+        assert!(!output.contains("node-b"));
+    }
+}
@@ -1,11 +1,18 @@
 use crate::browser::BrowserConfig;
 use crate::error::{XenonError, XenonResult};
+use crate::request_util::constant_time_eq;
 use crate::response::XenonResponse;
+use crate::session::{Upstream, XenonSessionId};
+use chrono::{DateTime, Utc};
 use hyper::http::uri::{Authority, Scheme};
 use hyper::Uri;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 
+/// The header used to send a node's `comms_id` between the hub and the node.
+pub const COMMS_ID_HEADER: &str = "x-xenon-comms-id";
+
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct NodeId(String);
 
@@ -15,6 +22,15 @@ impl Default for NodeId {
     }
 }
 
+impl<T> From<T> for NodeId
+where
+    T: Into<String>,
+{
+    fn from(value: T) -> Self {
+        NodeId(value.into())
+    }
+}
+
 impl Display for NodeId {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
@@ -30,16 +46,42 @@ impl NodeId {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RemoteServiceGroup {
     pub browser: BrowserConfig,
-    pub remaining_sessions: u32,
+    /// The number of sessions the node reported as available when its config was fetched.
+    /// This is sent as `remaining_sessions`, which older hubs expect.
+    #[serde(rename = "remaining_sessions", alias = "initial_capacity")]
+    pub initial_capacity: u32,
+    /// The number of sessions this server has created in this group since then.
+    #[serde(skip)]
+    pub sessions_used: u32,
+}
+
+impl RemoteServiceGroup {
+    /// The number of sessions that are assumed to still be available.
+    pub fn remaining(&self) -> u32 {
+        self.initial_capacity.saturating_sub(self.sessions_used)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RemoteNodeCreate {
     #[serde(default)]
     name: String,
     url: String,
     #[serde(default)]
     service_groups: Vec<RemoteServiceGroup>,
+    /// Bearer token for nodes that require authentication.
+    #[serde(default)]
+    auth_token: Option<String>,
+    /// The comms_id to use for this node, as 32 hex digits. If not set, a random one is
+    /// generated. Setting it allows nodes from the config file to be removed at runtime.
+    #[serde(default, skip_serializing)]
+    comms_id: Option<String>,
+}
+
+impl RemoteNodeCreate {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
 }
 
 fn parse_url(url: &str) -> Option<(Scheme, Authority)> {
@@ -66,12 +108,48 @@ pub struct RemoteNode {
     id: NodeId,
     name: String,
     pub url: String,
+    /// A shared secret generated by the hub when the node is added. The hub sends it
+    /// to the node with every request, and the node must present it in its own
+    /// requests to the hub (e.g. to deregister itself).
+    #[serde(skip_serializing)]
     comms_id: u128,
     pub service_groups: Vec<RemoteServiceGroup>,
     #[serde(skip, default = "default_scheme")]
     pub scheme: Scheme,
     #[serde(skip, default = "default_authority")]
     pub authority: Authority,
+    /// The last time the node config was successfully fetched.
+    #[serde(skip)]
+    pub last_successful_contact: Option<DateTime<Utc>>,
+    /// The number of failed attempts to contact the node since the last successful contact.
+    #[serde(skip)]
+    consecutive_failures: u32,
+    #[serde(skip)]
+    auth_token: Option<String>,
+    /// The index of the service group used by each session on this node.
+    #[serde(skip)]
+    session_groups: HashMap<XenonSessionId, usize>,
+    /// Sessions on this node that were injected via `POST /admin/session`.
+    /// They are not counted against any service group.
+    #[serde(skip)]
+    adopted_sessions: HashSet<XenonSessionId>,
+    /// Set when the node stops responding to health checks. No new sessions are
+    /// routed to a disabled node until it responds again.
+    #[serde(skip)]
+    disabled: bool,
+}
+
+/// Health and statistics for a single remote node.
+#[derive(Debug, Serialize)]
+pub struct NodeHealthStatus {
+    pub id: NodeId,
+    pub name: String,
+    pub url: String,
+    pub reachable: bool,
+    pub consecutive_failures: u32,
+    pub last_contact: Option<String>,
+    pub capacity: u32,
+    pub sessions: usize,
 }
 
 impl RemoteNode {
@@ -82,15 +160,35 @@ impl RemoteNode {
                 node_info.url
             )))
         })?;
+        let comms_id = match &node_info.comms_id {
+            Some(x) if x.len() == 32 && x.chars().all(|c| c.is_ascii_hexdigit()) => {
+                u128::from_str_radix(x, 16).unwrap_or_default()
+            }
+            Some(_) => {
+                return Err(XenonError::RespondWith(XenonResponse::ErrorCreatingNode(
+                    format!(
+                        "Invalid comms_id for remote node {}: expected 32 hex digits",
+                        node_info.url
+                    ),
+                )))
+            }
+            None => rand::random::<u128>(),
+        };
 
         Ok(Self {
             id: NodeId::new(),
             name: node_info.name,
             url: node_info.url,
-            comms_id: 0,
+            comms_id,
             service_groups: node_info.service_groups,
             scheme,
             authority,
+            last_successful_contact: None,
+            consecutive_failures: 0,
+            auth_token: node_info.auth_token,
+            session_groups: HashMap::new(),
+            adopted_sessions: HashSet::new(),
+            disabled: false,
         })
     }
 
@@ -98,11 +196,271 @@ impl RemoteNode {
         self.id.clone()
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The comms_id as a hex string, as sent in the `X-Xenon-Comms-Id` header.
+    pub fn comms_id(&self) -> String {
+        format!("{:032x}", self.comms_id)
+    }
+
+    /// Check a comms_id presented by the node against the one generated by the hub.
+    pub fn verify_comms_id(&self, presented: &str) -> bool {
+        constant_time_eq(
+            presented.trim().to_ascii_lowercase().as_bytes(),
+            self.comms_id().as_bytes(),
+        )
+    }
+
+    /// The upstream used for all requests to this node.
+    pub fn upstream(&self) -> Upstream {
+        Upstream {
+            scheme: self.scheme.clone(),
+            authority: self.authority.clone(),
+            auth_token: self.auth_token.clone(),
+            comms_id: Some(self.comms_id()),
+        }
+    }
+
+    /// A node is considered reachable once its config has been fetched,
+    /// until it fails too many health checks.
+    pub fn is_reachable(&self) -> bool {
+        self.last_successful_contact.is_some() && !self.disabled
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// Replace the service groups with those fetched from the node, keeping count
+    /// of the sessions that this server already has on each group.
+    pub fn set_service_groups(&mut self, service_groups: Vec<RemoteServiceGroup>) {
+        self.service_groups = service_groups;
+        self.session_groups
+            .retain(|_, group_index| *group_index < self.service_groups.len());
+        for group in &mut self.service_groups {
+            group.sessions_used = 0;
+        }
+        for group_index in self.session_groups.values() {
+            self.service_groups[*group_index].sessions_used += 1;
+        }
+        self.disabled = false;
+    }
+
+    /// Stop routing new sessions to this node. The capacity of its service groups
+    /// is kept, and is used again once the node responds.
+    pub fn disable(&mut self) {
+        self.disabled = true;
+    }
+
+    /// The total number of sessions this node reported as available.
+    /// This is zero while the node is disabled.
+    pub fn capacity(&self) -> u32 {
+        if self.disabled {
+            return 0;
+        }
+        self.service_groups
+            .iter()
+            .map(|group| group.remaining())
+            .sum()
+    }
+
+    /// The number of sessions this server has on this node.
+    pub fn num_sessions(&self) -> usize {
+        self.session_groups.len() + self.adopted_sessions.len()
+    }
+
+    /// Record a new session in the service group at the specified index.
+    pub fn add_session(&mut self, group_index: usize, xsession_id: XenonSessionId) {
+        if let Some(group) = self.service_groups.get_mut(group_index) {
+            group.sessions_used += 1;
+            self.session_groups.insert(xsession_id, group_index);
+        }
+    }
+
+    /// Record an existing session that was not created via this server.
+    pub fn adopt_session(&mut self, xsession_id: XenonSessionId) {
+        self.adopted_sessions.insert(xsession_id);
+    }
+
+    /// Free the slot used by the specified session, if it was created on this node.
+    pub fn remove_session(&mut self, xsession_id: &XenonSessionId) {
+        self.adopted_sessions.remove(xsession_id);
+        if let Some(group_index) = self.session_groups.remove(xsession_id) {
+            // The service groups may have been replaced since the session was created.
+            if let Some(group) = self.service_groups.get_mut(group_index) {
+                group.sessions_used = group.sessions_used.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn record_contact_success(&mut self) {
+        self.last_successful_contact = Some(Utc::now());
+        self.consecutive_failures = 0;
+    }
+
+    pub fn record_contact_failure(&mut self) {
+        self.consecutive_failures += 1;
+    }
+
+    /// Get the health status of this node.
+    pub fn health(&self) -> NodeHealthStatus {
+        NodeHealthStatus {
+            id: self.id(),
+            name: self.display_name(),
+            url: self.url.clone(),
+            reachable: self.is_reachable(),
+            consecutive_failures: self.consecutive_failures,
+            last_contact: self.last_successful_contact.map(|x| x.to_rfc3339()),
+            capacity: self.capacity(),
+            sessions: self.num_sessions(),
+        }
+    }
+
     pub fn display_name(&self) -> String {
         if self.name.is_empty() {
-            self.id.to_string()
+            // The first 8 characters of the UUID are enough to correlate log lines.
+            self.id.0.chars().take(8).collect()
         } else {
             format!("{} ({})", self.name, self.id)
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::nodes::{RemoteNode, RemoteNodeCreate};
+    use crate::session::XenonSessionId;
+
+    #[test]
+    fn test_display_name() {
+        let node_info: RemoteNodeCreate =
+            serde_yaml::from_str("url: http://localhost:8888").unwrap();
+        let node = RemoteNode::new(node_info).unwrap();
+        let id = node.id().to_string();
+        assert_eq!(node.display_name(), &id[..8]);
+        assert_eq!(node.display_name(), node.display_name());
+
+        let node_info: RemoteNodeCreate =
+            serde_yaml::from_str("{name: node1, url: http://localhost:8888}").unwrap();
+        let node = RemoteNode::new(node_info).unwrap();
+        assert_eq!(node.display_name(), format!("node1 ({})", node.id()));
+    }
+
+    #[test]
+    fn test_comms_id() {
+        let node_info: RemoteNodeCreate =
+            serde_yaml::from_str("url: http://localhost:8888").unwrap();
+        let node = RemoteNode::new(node_info.clone()).unwrap();
+        let comms_id = node.comms_id();
+        assert_eq!(comms_id.len(), 32);
+        assert!(node.verify_comms_id(&comms_id));
+        assert!(!node.verify_comms_id(""));
+        assert!(!node.verify_comms_id("not hex"));
+        assert_eq!(node.upstream().comms_id, Some(comms_id.clone()));
+
+        let other = RemoteNode::new(node_info).unwrap();
+        assert!(!other.verify_comms_id(&comms_id));
+        let json = serde_json::to_value(&node).unwrap();
+        assert!(json.get("comms_id").is_none());
+
+        // The comms_id can be set in the config, so that the node can know it too.
+        let comms_id = "0123456789abcdef0123456789ABCDEF";
+        let node_info: RemoteNodeCreate = serde_yaml::from_str(&format!(
+            "{{url: http://localhost:8888, comms_id: {}}}",
+            comms_id
+        ))
+        .unwrap();
+        let node = RemoteNode::new(node_info).unwrap();
+        assert!(node.verify_comms_id(comms_id));
+        assert_eq!(node.comms_id(), comms_id.to_lowercase());
+
+        let node_info: RemoteNodeCreate =
+            serde_yaml::from_str("{url: http://localhost:8888, comms_id: xyz}").unwrap();
+        assert!(RemoteNode::new(node_info).is_err());
+    }
+
+    #[test]
+    fn test_remaining_sessions() {
+        let node_info: RemoteNodeCreate = serde_yaml::from_str(
+            r#"
+url: http://localhost:8888
+service_groups:
+  - browser:
+      name: chrome
+    initial_capacity: 2
+  - browser:
+      name: firefox
+    remaining_sessions: 1
+"#,
+        )
+        .unwrap();
+        let mut node = RemoteNode::new(node_info).unwrap();
+        assert_eq!(node.service_groups[1].initial_capacity, 1);
+        assert_eq!(node.capacity(), 3);
+        // Sent under the old name, so that older hubs can still read it.
+        let json = serde_json::to_value(&node.service_groups[0]).unwrap();
+        assert_eq!(json["remaining_sessions"], 2);
+        assert!(json.get("initial_capacity").is_none());
+
+        let ids: Vec<XenonSessionId> = (0..3)
+            .map(|i| XenonSessionId::from(i.to_string()))
+            .collect();
+        node.add_session(0, ids[0].clone());
+        node.add_session(0, ids[1].clone());
+        node.add_session(0, ids[2].clone());
+        assert_eq!(node.service_groups[0].sessions_used, 3);
+        assert_eq!(node.service_groups[0].remaining(), 0);
+        assert_eq!(node.capacity(), 1);
+
+        node.remove_session(&ids[0]);
+        node.remove_session(&ids[0]);
+        assert_eq!(node.service_groups[0].sessions_used, 2);
+        assert_eq!(node.capacity(), 1);
+        node.remove_session(&ids[1]);
+        assert_eq!(node.capacity(), 2);
+
+        let adopted = XenonSessionId::from("adopted");
+        node.adopt_session(adopted.clone());
+        assert_eq!(node.num_sessions(), 2);
+        assert_eq!(node.capacity(), 2);
+        node.remove_session(&adopted);
+        assert_eq!(node.num_sessions(), 1);
+    }
+
+    #[test]
+    fn test_disable_and_reenable() {
+        let node_info: RemoteNodeCreate = serde_yaml::from_str(
+            r#"
+url: http://localhost:8888
+service_groups:
+  - browser:
+      name: chrome
+    initial_capacity: 2
+"#,
+        )
+        .unwrap();
+        let mut node = RemoteNode::new(node_info).unwrap();
+        node.record_contact_success();
+        node.add_session(0, XenonSessionId::from("1"));
+        let service_groups = node.service_groups.clone();
+
+        node.disable();
+        assert!(node.is_disabled());
+        assert!(!node.is_reachable());
+        assert_eq!(node.capacity(), 0);
+        assert_eq!(node.service_groups[0].initial_capacity, 2);
+
+        // The session created before the node was disabled still counts.
+        node.set_service_groups(service_groups);
+        assert!(!node.is_disabled());
+        assert!(node.is_reachable());
+        assert_eq!(node.service_groups[0].sessions_used, 1);
+        assert_eq!(node.capacity(), 1);
+    }
+}
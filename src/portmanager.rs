@@ -1,7 +1,30 @@
 use crate::config::XenonConfig;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
 
-pub type ServicePort = u16;
+/// A port managed by the PortManager, for use by a local WebDriver service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(transparent)]
+pub struct ServicePort(u16);
+
+impl From<u16> for ServicePort {
+    fn from(value: u16) -> Self {
+        ServicePort(value)
+    }
+}
+
+impl From<ServicePort> for u16 {
+    fn from(value: ServicePort) -> Self {
+        value.0
+    }
+}
+
+impl Display for ServicePort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 #[derive(Debug)]
 pub enum PortStatus {
@@ -11,29 +34,58 @@ pub enum PortStatus {
 
 #[derive(Debug)]
 pub struct PortManager {
-    ports: HashMap<ServicePort, PortStatus>,
+    ports: BTreeMap<ServicePort, PortStatus>,
 }
 
 impl PortManager {
     pub fn new(config: &XenonConfig) -> Self {
         // Parse port list.
         let port_list = config.get_port_list();
-        let mut ports = HashMap::new();
+        let mut ports = BTreeMap::new();
         for port in port_list {
             ports.insert(port, PortStatus::Available);
         }
         Self { ports }
     }
 
-    pub fn lock_next_port(&mut self) -> Option<ServicePort> {
-        for (k, v) in self.ports.iter_mut() {
-            if let PortStatus::Available = *v {
-                *v = PortStatus::Taken;
-                return Some(*k);
-            }
+    /// All managed ports in ascending order, whether they are available or not.
+    pub fn all_ports(&self) -> impl Iterator<Item = ServicePort> + '_ {
+        self.ports.keys().copied()
+    }
+
+    /// All ports that are not currently in use, in ascending order.
+    pub fn available_ports(&self) -> impl Iterator<Item = ServicePort> + '_ {
+        self.ports
+            .iter()
+            .filter(|(_, v)| matches!(v, PortStatus::Available))
+            .map(|(k, _)| *k)
+    }
+
+    /// Lock a block of `count` consecutive available ports, e.g. for a WebDriver that
+    /// needs more than one port. Either all ports in the block are locked, or none
+    /// are (in which case None is returned).
+    pub fn reserve_range(&mut self, count: usize) -> Option<Vec<ServicePort>> {
+        if count == 0 {
+            return None;
+        }
+
+        let available: Vec<u16> = self.available_ports().map(u16::from).collect();
+
+        let block = available
+            .windows(count)
+            .find(|w| w[count - 1] as usize - w[0] as usize == count - 1)?;
+        let block: Vec<ServicePort> = block.iter().copied().map(ServicePort::from).collect();
+        for port in &block {
+            self.ports.insert(*port, PortStatus::Taken);
         }
+        Some(block)
+    }
 
-        None
+    /// Unlock all of the specified ports, e.g. a block locked with `reserve_range()`.
+    pub fn release_range(&mut self, ports: &[ServicePort]) {
+        for port in ports {
+            self.unlock_port(*port);
+        }
     }
 
     pub fn unlock_port(&mut self, port: ServicePort) {
@@ -42,3 +94,52 @@ impl PortManager {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::config::XenonConfig;
+    use crate::portmanager::{PortManager, ServicePort};
+
+    #[test]
+    fn test_reserve_range() {
+        let config: XenonConfig =
+            serde_yaml::from_str("ports: [\"5000-5002\", \"5004-5006\"]").unwrap();
+        let mut port_manager = PortManager::new(&config);
+        assert_eq!(
+            port_manager.reserve_range(1),
+            Some(vec![ServicePort::from(5000)])
+        );
+
+        // 5001-5002 is too small, so the block must come from the second range.
+        let block = port_manager.reserve_range(3).unwrap();
+        assert_eq!(block, vec![5004.into(), 5005.into(), 5006.into()]);
+        assert_eq!(port_manager.reserve_range(3), None);
+
+        port_manager.unlock_port(ServicePort::from(5000));
+        let block = port_manager.reserve_range(3).unwrap();
+        assert_eq!(block, vec![5000.into(), 5001.into(), 5002.into()]);
+        assert_eq!(port_manager.reserve_range(1), None);
+
+        port_manager.release_range(&block);
+        assert_eq!(port_manager.available_ports().count(), 3);
+        assert_eq!(port_manager.reserve_range(4), None);
+    }
+
+    #[test]
+    fn test_all_ports() {
+        let config: XenonConfig = serde_yaml::from_str("ports: [\"5004\", \"5000-5002\"]").unwrap();
+        let mut port_manager = PortManager::new(&config);
+        let ports = |x: &[u16]| x.iter().copied().map(ServicePort::from).collect::<Vec<_>>();
+        assert_eq!(
+            port_manager.all_ports().collect::<Vec<_>>(),
+            ports(&[5000, 5001, 5002, 5004])
+        );
+
+        port_manager.reserve_range(2).unwrap();
+        assert_eq!(
+            port_manager.available_ports().collect::<Vec<_>>(),
+            ports(&[5002, 5004])
+        );
+        assert_eq!(port_manager.all_ports().count(), 4);
+    }
+}
@@ -0,0 +1,322 @@
+use crate::error::{XenonError, XenonResult};
+use crate::response::XenonResponse;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+/// Queued requests re-check capacity at least this often, even if not notified.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The request is checking capacity. This is the state a request joins the queue in.
+const CHECKING: u8 = 0;
+/// The request is waiting to be notified.
+const WAITING: u8 = 1;
+/// The request has been notified and has not yet checked capacity again.
+const NOTIFIED: u8 = 2;
+
+/// The wake-up state of one queued request. A request can wait in several queues at
+/// once, if its capabilities match several service groups, but it is only woken once.
+#[derive(Debug, Default)]
+struct WaiterState {
+    notify: Notify,
+    state: AtomicU8,
+}
+
+impl WaiterState {
+    fn transition(&self, from: u8, to: u8) -> bool {
+        self.state
+            .compare_exchange(from, to, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+}
+
+#[derive(Debug)]
+struct QueueEntry {
+    id: u64,
+    waiter: Arc<WaiterState>,
+}
+
+#[derive(Debug, Default)]
+struct QueueInner {
+    entries: VecDeque<QueueEntry>,
+    next_id: u64,
+    closed: bool,
+}
+
+impl QueueInner {
+    /// Wake the longest-waiting request that is waiting. If none are waiting, the
+    /// notification is kept by the first request that is checking capacity, since
+    /// its check may have missed the session that was just released.
+    fn wake_next(&self) {
+        let waiting = self
+            .entries
+            .iter()
+            .find(|entry| entry.waiter.transition(WAITING, NOTIFIED));
+        match waiting {
+            Some(entry) => entry.waiter.notify.notify_one(),
+            None => {
+                self.entries
+                    .iter()
+                    .any(|entry| entry.waiter.transition(CHECKING, NOTIFIED));
+            }
+        }
+    }
+}
+
+/// New session requests wait in a SessionQueue while a service group is at capacity.
+/// Waiters are woken one at a time, in the order they joined the queue, whenever a
+/// session is released.
+#[derive(Debug, Default)]
+pub struct SessionQueue {
+    inner: Arc<Mutex<QueueInner>>,
+}
+
+impl SessionQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wake the longest-waiting request, so that it can check capacity again.
+    pub fn notify(&self) {
+        self.inner.lock().unwrap().wake_next();
+    }
+
+    /// Stop queuing requests. All current and future waiters fail with `ShuttingDown`.
+    pub fn close(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.closed = true;
+        for entry in &inner.entries {
+            entry.waiter.notify.notify_one();
+        }
+    }
+}
+
+/// A request waiting for capacity. It keeps its place in each queue it has joined
+/// until it is dropped.
+#[derive(Debug, Default)]
+pub struct QueueWaiter {
+    waiter: Arc<WaiterState>,
+    entries: Vec<(Arc<Mutex<QueueInner>>, u64)>,
+}
+
+impl QueueWaiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Join the back of the queue. Join before checking capacity, so that any session
+    /// released after the check is not missed.
+    pub fn join(&mut self, queue: &SessionQueue) {
+        let mut inner = queue.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.entries.push_back(QueueEntry {
+            id,
+            waiter: self.waiter.clone(),
+        });
+        self.entries.push((queue.inner.clone(), id));
+    }
+
+    fn is_closed(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|(inner, _)| inner.lock().unwrap().closed)
+    }
+
+    /// Wait until any of the joined queues wakes this request, or the poll interval or
+    /// deadline is reached. Fails if any of the queues has been closed.
+    pub async fn wait(&mut self, deadline: Instant) -> XenonResult<()> {
+        // A notification that arrived while checking capacity is used straight away.
+        let state = self.waiter.state.swap(WAITING, Ordering::SeqCst);
+        if state != NOTIFIED && !self.is_closed() {
+            let wake_at = deadline.min(Instant::now() + QUEUE_POLL_INTERVAL);
+            let _ = tokio::time::timeout_at(wake_at, self.waiter.notify.notified()).await;
+        }
+        self.waiter.state.store(CHECKING, Ordering::SeqCst);
+        if self.is_closed() {
+            return Err(XenonError::RespondWith(XenonResponse::ShuttingDown));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for QueueWaiter {
+    fn drop(&mut self) {
+        let was_notified = self.waiter.state.load(Ordering::SeqCst) == NOTIFIED;
+        for (inner, id) in &self.entries {
+            let mut inner = inner.lock().unwrap();
+            inner.entries.retain(|entry| entry.id != *id);
+            if was_notified {
+                // This request may not have used its notification, so pass it on
+                // rather than leave the next request waiting for the poll interval.
+                inner.wake_next();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::XenonError;
+    use crate::queue::{QueueWaiter, SessionQueue};
+    use crate::response::XenonResponse;
+    use std::sync::Arc;
+    use tokio::time::{Duration, Instant};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait() {
+        let queues = Arc::new(vec![SessionQueue::new(), SessionQueue::new()]);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let join_all = || {
+            let mut waiter = QueueWaiter::new();
+            for queue in queues.iter() {
+                waiter.join(queue);
+            }
+            waiter
+        };
+
+        // Not notified, so wait for the poll interval.
+        let start = Instant::now();
+        join_all().wait(deadline).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        // Notified by either queue.
+        let start = Instant::now();
+        let mut waiter = join_all();
+        let queues_clone = queues.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            queues_clone[1].notify();
+        });
+        waiter.wait(deadline).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        drop(waiter);
+
+        // A notification before waiting is not missed.
+        let start = Instant::now();
+        let mut waiter = join_all();
+        queues[0].notify();
+        waiter.wait(deadline).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        drop(waiter);
+
+        // Never wait past the deadline.
+        let start = Instant::now();
+        join_all()
+            .wait(start + Duration::from_millis(300))
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fifo_order() {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let two_waiters = |queue: &SessionQueue| {
+            let mut first = QueueWaiter::new();
+            first.join(queue);
+            let mut second = QueueWaiter::new();
+            second.join(queue);
+            (first, second)
+        };
+
+        // Only the first waiter is woken by one notification.
+        let queue = SessionQueue::new();
+        let (mut first, mut second) = two_waiters(&queue);
+        queue.notify();
+        let start = Instant::now();
+        first.wait(deadline).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        second.wait(deadline).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        // While both are checking capacity, the notification is kept for the first waiter.
+        queue.notify();
+        let start = Instant::now();
+        first.wait(deadline).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        second.wait(deadline).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        // The first waiter keeps its place when both are waiting.
+        let start = Instant::now();
+        let (first_woken, second_woken, _) = tokio::join!(
+            async {
+                first.wait(deadline).await.unwrap();
+                start.elapsed()
+            },
+            async {
+                second.wait(deadline).await.unwrap();
+                start.elapsed()
+            },
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                queue.notify();
+            }
+        );
+        assert_eq!(first_woken, Duration::from_millis(100));
+        assert_eq!(second_woken, Duration::from_secs(1));
+
+        // A waiting request is woken before one that is still checking capacity.
+        let start = Instant::now();
+        let (second_woken, _) = tokio::join!(
+            async {
+                second.wait(deadline).await.unwrap();
+                start.elapsed()
+            },
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                queue.notify();
+            }
+        );
+        assert_eq!(second_woken, Duration::from_millis(100));
+
+        // Two notifications wake both waiters.
+        let queue = SessionQueue::new();
+        let (mut first, mut second) = two_waiters(&queue);
+        queue.notify();
+        queue.notify();
+        let start = Instant::now();
+        first.wait(deadline).await.unwrap();
+        second.wait(deadline).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // A waiter that leaves passes on its notification.
+        let queue = SessionQueue::new();
+        let (first, mut second) = two_waiters(&queue);
+        queue.notify();
+        drop(first);
+        let start = Instant::now();
+        second.wait(deadline).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close() {
+        let queue = Arc::new(SessionQueue::new());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut waiter = QueueWaiter::new();
+        waiter.join(&queue);
+
+        let queue_clone = queue.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            queue_clone.close();
+        });
+        let start = Instant::now();
+        let result = waiter.wait(deadline).await;
+        assert!(matches!(
+            result,
+            Err(XenonError::RespondWith(XenonResponse::ShuttingDown))
+        ));
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+
+        // Requests that join after the queue is closed fail straight away.
+        let mut waiter = QueueWaiter::new();
+        waiter.join(&queue);
+        assert!(waiter.wait(deadline).await.is_err());
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+}
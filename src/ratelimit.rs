@@ -0,0 +1,142 @@
+use dashmap::DashMap;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    /// The number of requests per second each client IP address may sustain.
+    pub requests_per_second: f64,
+    /// The number of requests a client may make in a burst before being limited.
+    pub burst: u32,
+}
+
+/// A simple token bucket. Tokens are refilled continuously at the configured rate,
+/// up to the burst size, and each request consumes one token.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        Self {
+            tokens: config.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Returns true if the bucket would be full at the specified time.
+    /// A full bucket behaves exactly like a new one, so it can be discarded.
+    fn is_full(&self, config: &RateLimitConfig, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens + elapsed * config.requests_per_second >= config.burst as f64
+    }
+
+    /// Take a token if one is available, otherwise return the time until the next token.
+    fn try_take(&mut self, config: &RateLimitConfig, now: Instant) -> Result<(), Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.requests_per_second).min(config.burst as f64);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / config.requests_per_second,
+            ))
+        }
+    }
+}
+
+/// Rate limiter that tracks a separate token bucket for each client IP address.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: DashMap<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Check whether the client is allowed to make another request.
+    /// If not, returns the time the client should wait before retrying.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(&self.config, now))
+            .try_take(&self.config, now)
+    }
+
+    /// Discard the buckets of clients that have not made a request for long enough
+    /// that their bucket has refilled, so that buckets do not accumulate forever.
+    pub fn evict_full_buckets(&self) {
+        let now = Instant::now();
+        self.buckets
+            .retain(|_, bucket| !bucket.is_full(&self.config, now));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ratelimit::{RateLimitConfig, RateLimiter, TokenBucket};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_token_bucket() {
+        let config = RateLimitConfig {
+            requests_per_second: 2.0,
+            burst: 3,
+        };
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&config, start);
+        for _ in 0..3 {
+            assert!(bucket.try_take(&config, start).is_ok());
+        }
+        assert_eq!(
+            bucket.try_take(&config, start),
+            Err(Duration::from_millis(500))
+        );
+
+        // One token is refilled every 500ms.
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take(&config, later).is_ok());
+        assert!(bucket.try_take(&config, later).is_err());
+
+        // Tokens never exceed the burst size.
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_take(&config, much_later).is_ok());
+        }
+        assert!(bucket.try_take(&config, much_later).is_err());
+    }
+
+    #[test]
+    fn test_evict_full_buckets() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            requests_per_second: 1000.0,
+            burst: 1,
+        });
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(limiter.check(ip).is_ok());
+        limiter.evict_full_buckets();
+        assert_eq!(limiter.buckets.len(), 1);
+
+        std::thread::sleep(Duration::from_millis(5));
+        limiter.evict_full_buckets();
+        assert!(limiter.buckets.is_empty());
+    }
+}
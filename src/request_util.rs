@@ -0,0 +1,177 @@
+use crate::error::{XenonError, XenonResult};
+use crate::nodes::COMMS_ID_HEADER;
+use crate::session::Upstream;
+use hyper::header::{HeaderMap, HeaderName};
+use hyper::{Body, Request};
+
+/// The User-Agent sent with all requests to the upstream WebDriver.
+const USER_AGENT: &str = concat!("xenon/", env!("CARGO_PKG_VERSION"));
+
+/// Client headers that are forwarded to the upstream WebDriver, in addition to any
+/// vendor headers (starting with `x-`).
+const FORWARDED_HEADERS: [HeaderName; 3] = [
+    hyper::header::CONTENT_TYPE,
+    hyper::header::ACCEPT,
+    hyper::header::CONTENT_LENGTH,
+];
+
+/// Build a request to be sent to the upstream WebDriver (or remote node).
+///
+/// No client headers are copied to the new request. Use `copy_forwarded_headers()`
+/// to add the ones that are safe to forward.
+pub fn build_proxy_request(
+    method: hyper::Method,
+    upstream: &Upstream,
+    path: &str,
+    body: Body,
+) -> XenonResult<Request<Body>> {
+    let uri_out = hyper::Uri::builder()
+        .scheme(upstream.scheme.clone())
+        .authority(upstream.authority.clone())
+        .path_and_query(path)
+        .build()
+        .map_err(|e| XenonError::RequestError(e.to_string()))?;
+
+    let mut builder = Request::builder()
+        .method(method)
+        .uri(uri_out)
+        .header(hyper::header::USER_AGENT, USER_AGENT);
+    if let Some(token) = &upstream.auth_token {
+        builder = builder.header(hyper::header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    if let Some(comms_id) = &upstream.comms_id {
+        builder = builder.header(COMMS_ID_HEADER, comms_id);
+    }
+    builder
+        .body(body)
+        .map_err(|e| XenonError::RequestError(e.to_string()))
+}
+
+/// Copy the client headers that are safe to forward to the upstream WebDriver.
+///
+/// This is an allow-list, so hop-by-hop headers (`Connection`, `Keep-Alive`,
+/// `Transfer-Encoding`, `Upgrade`, `Proxy-Authorization`) are never forwarded, and
+/// neither is the client's `Authorization` header. The `X-Xenon-Comms-Id` header is
+/// a secret between this server and its hub, so it is never forwarded either.
+pub fn copy_forwarded_headers(from: &HeaderMap, to: &mut HeaderMap) {
+    for (name, value) in from {
+        if name == COMMS_ID_HEADER {
+            continue;
+        }
+        if FORWARDED_HEADERS.contains(name) || name.as_str().starts_with("x-") {
+            to.append(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Compare two secrets. Every byte is compared, so that the time taken does not
+/// reveal how much of the secret matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Is this a request to upgrade the connection to a WebSocket, e.g. for BiDi or CDP?
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    let has_token = |name: HeaderName, token: &str| {
+        headers.get_all(name).iter().any(|value| {
+            value
+                .to_str()
+                .map(|x| x.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+                .unwrap_or(false)
+        })
+    };
+    has_token(hyper::header::CONNECTION, "upgrade")
+        && has_token(hyper::header::UPGRADE, "websocket")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::request_util::{
+        build_proxy_request, constant_time_eq, copy_forwarded_headers, is_websocket_upgrade,
+        USER_AGENT,
+    };
+    use crate::session::Upstream;
+    use hyper::http::uri::Scheme;
+    use hyper::{Body, Method, Request};
+
+    #[test]
+    fn test_build_proxy_request() {
+        let mut upstream = Upstream::new(Scheme::HTTP, "localhost:4444".parse().unwrap());
+        let req =
+            build_proxy_request(Method::GET, &upstream, "/status?a=1", Body::empty()).unwrap();
+        assert_eq!(req.uri(), "http://localhost:4444/status?a=1");
+        assert_eq!(req.headers()["user-agent"], USER_AGENT);
+        assert!(req.headers().get("authorization").is_none());
+
+        upstream.auth_token = Some("secret".to_string());
+        upstream.comms_id = Some("abc123".to_string());
+        let req = build_proxy_request(Method::GET, &upstream, "/status", Body::empty()).unwrap();
+        assert_eq!(req.headers()["authorization"], "Bearer secret");
+        assert_eq!(req.headers()["x-xenon-comms-id"], "abc123");
+    }
+
+    #[test]
+    fn test_copy_forwarded_headers() {
+        let req_in = Request::post("/session/abc/url")
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .header("Content-Length", "2")
+            .header("X-Custom", "1")
+            .header("Connection", "keep-alive, Upgrade")
+            .header("Keep-Alive", "timeout=5")
+            .header("Transfer-Encoding", "chunked")
+            .header("Upgrade", "websocket")
+            .header("Proxy-Authorization", "Basic abc")
+            .header("Authorization", "Bearer client")
+            .header("Host", "localhost:4444")
+            .header("X-Xenon-Comms-Id", "abc123")
+            .body(Body::empty())
+            .unwrap();
+        let upstream = Upstream::new(Scheme::HTTP, "localhost:4444".parse().unwrap());
+        let mut req_out =
+            build_proxy_request(Method::POST, &upstream, "/session/1/url", Body::empty()).unwrap();
+        copy_forwarded_headers(req_in.headers(), req_out.headers_mut());
+
+        let mut names: Vec<&str> = req_out.headers().keys().map(|x| x.as_str()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "accept",
+                "content-length",
+                "content-type",
+                "user-agent",
+                "x-custom"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret1"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_is_websocket_upgrade() {
+        let headers = |connection: &str, upgrade: &str| {
+            Request::get("/session/abc/se/bidi")
+                .header("Connection", connection)
+                .header("Upgrade", upgrade)
+                .body(Body::empty())
+                .unwrap()
+                .headers()
+                .clone()
+        };
+        assert!(is_websocket_upgrade(&headers("Upgrade", "websocket")));
+        assert!(is_websocket_upgrade(&headers(
+            "keep-alive, upgrade",
+            "WebSocket"
+        )));
+        assert!(!is_websocket_upgrade(&headers("keep-alive", "websocket")));
+        assert!(!is_websocket_upgrade(&headers("Upgrade", "h2c")));
+        assert!(!is_websocket_upgrade(&hyper::HeaderMap::new()));
+    }
+}
@@ -1,53 +1,133 @@
 use hyper::{Body, Response, StatusCode};
-use serde::Serialize;
+use serde::{Serialize, Serializer};
+use std::fmt::{Display, Formatter};
+
+/// Details for a request that referenced an unknown session.
+#[derive(Debug)]
+pub struct SessionNotFoundError {
+    pub session_id: String,
+    pub available_session_count: usize,
+}
+
+impl Display for SessionNotFoundError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.session_id)
+    }
+}
+
+/// Details for a session that could not be created.
+#[derive(Debug)]
+pub struct CreateSessionError {
+    pub reason: String,
+    /// The browser the session was being created for, if known.
+    pub browser: Option<String>,
+}
+
+impl CreateSessionError {
+    pub fn new<S: Into<String>>(reason: S, browser: Option<&str>) -> Self {
+        Self {
+            reason: reason.into(),
+            browser: browser.map(|x| x.to_string()),
+        }
+    }
+}
+
+impl From<String> for CreateSessionError {
+    fn from(reason: String) -> Self {
+        Self::new(reason, None)
+    }
+}
+
+impl Display for CreateSessionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// Both error types serialize as their message, to keep the same output as a plain String.
+macro_rules! serialize_as_display {
+    ($t:ty) => {
+        impl Serialize for $t {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+    };
+}
+serialize_as_display!(SessionNotFoundError);
+serialize_as_display!(CreateSessionError);
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE", tag = "code", content = "message")]
 pub enum XenonResponse {
+    InvalidRequest(String),
     EndpointNotFound(String),
     MethodNotFound(String),
-    SessionNotFound(String),
-    ErrorCreatingSession(String),
+    SessionNotFound(SessionNotFoundError),
+    ErrorCreatingSession(CreateSessionError),
     NoMatchingBrowser,
     NoSessionsAvailable,
     InternalServerError(String),
     ErrorCreatingNode(String),
+    NodeNotFound(String),
+    NodeHasSessions(String),
+    NodeAlreadyExists(String),
+    NodeTimeout(String),
+    /// The client must wait the specified number of seconds before retrying.
+    RateLimitExceeded(u64),
+    Unauthorized,
+    ShuttingDown,
 }
 
 impl XenonResponse {
     pub fn status(&self) -> StatusCode {
         match self {
-            XenonResponse::EndpointNotFound(_) | XenonResponse::MethodNotFound(_) => {
-                StatusCode::BAD_REQUEST
-            }
+            XenonResponse::InvalidRequest(_)
+            | XenonResponse::EndpointNotFound(_)
+            | XenonResponse::MethodNotFound(_) => StatusCode::BAD_REQUEST,
             XenonResponse::NoMatchingBrowser | XenonResponse::NoSessionsAvailable => {
                 StatusCode::NOT_FOUND
             }
+            XenonResponse::NodeNotFound(_) => StatusCode::NOT_FOUND,
+            XenonResponse::NodeHasSessions(_) | XenonResponse::NodeAlreadyExists(_) => {
+                StatusCode::CONFLICT
+            }
+            XenonResponse::RateLimitExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            XenonResponse::Unauthorized => StatusCode::UNAUTHORIZED,
+            XenonResponse::NodeTimeout(_) | XenonResponse::ShuttingDown => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     pub fn into_response(self) -> Response<Body> {
-        Response::builder()
+        let body = self.to_json_string();
+        let mut builder = Response::builder()
             .status(self.status())
-            .body(self.into())
-            .unwrap_or_else(|_| {
-                Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::from("Xenon failed to serialize an error"))
-                    .unwrap()
-            })
+            .header("Content-Length", body.len());
+        if let XenonResponse::RateLimitExceeded(retry_after) = self {
+            builder = builder.header("Retry-After", retry_after);
+        }
+        if let XenonResponse::Unauthorized = self {
+            builder = builder.header("WWW-Authenticate", "Bearer");
+        }
+        builder.body(Body::from(body)).unwrap_or_else(|_| {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("Xenon failed to serialize an error"))
+                .unwrap()
+        })
     }
-}
 
-impl Into<Body> for XenonResponse {
-    fn into(self) -> Body {
-        // Construct WebDriver-compatible JSON output.
-        let (error_code, message) = match &self {
+    /// Construct WebDriver-compatible JSON output.
+    fn to_json_string(&self) -> String {
+        let (error_code, message) = match self {
+            XenonResponse::InvalidRequest(x) => ("invalid argument", x.clone()),
             XenonResponse::EndpointNotFound(x) => ("unknown method", x.clone()),
             XenonResponse::MethodNotFound(x) => ("unknown method", x.clone()),
-            XenonResponse::SessionNotFound(x) => ("invalid session id", x.clone()),
-            XenonResponse::ErrorCreatingSession(x) => ("session not created", x.clone()),
+            XenonResponse::SessionNotFound(x) => ("invalid session id", x.to_string()),
+            XenonResponse::ErrorCreatingSession(x) => ("session not created", x.to_string()),
             XenonResponse::NoMatchingBrowser => (
                 "session not created",
                 String::from("No browser was found to match the desired capabilities"),
@@ -58,9 +138,25 @@ impl Into<Body> for XenonResponse {
             ),
             XenonResponse::InternalServerError(x) => ("unknown error", x.clone()),
             XenonResponse::ErrorCreatingNode(x) => ("error creating node", x.clone()),
+            XenonResponse::NodeNotFound(x) => ("node not found", x.clone()),
+            XenonResponse::NodeHasSessions(x) => ("node has active sessions", x.clone()),
+            XenonResponse::NodeAlreadyExists(x) => ("node already exists", x.clone()),
+            XenonResponse::NodeTimeout(x) => ("timeout", x.clone()),
+            XenonResponse::RateLimitExceeded(x) => (
+                "rate limit exceeded",
+                format!("Too many requests. Retry after {} second(s)", x),
+            ),
+            XenonResponse::Unauthorized => (
+                "unauthorized",
+                String::from("A valid bearer token is required"),
+            ),
+            XenonResponse::ShuttingDown => (
+                "session not created",
+                String::from("Xenon is shutting down. No new sessions can be created"),
+            ),
         };
 
-        let json_body = serde_json::json!({
+        let mut json_body = serde_json::json!({
             "status": self.status().as_u16(),
             "state": error_code,
             "value": {
@@ -68,10 +164,52 @@ impl Into<Body> for XenonResponse {
                 "error": error_code,
             }
         });
+        if let Some(data) = self.data() {
+            json_body["value"]["data"] = data;
+        }
+
+        serde_json::to_string(&json_body)
+            .unwrap_or_else(|e| format!("JSON error message conversion failed: {}", e))
+    }
+}
 
-        Body::from(
-            serde_json::to_string(&json_body)
-                .unwrap_or_else(|e| format!("JSON error message conversion failed: {}", e)),
-        )
+impl XenonResponse {
+    /// Structured error data, for clients that want to handle errors programmatically.
+    /// This is included as `value.data` in the JSON output.
+    fn data(&self) -> Option<serde_json::Value> {
+        match self {
+            XenonResponse::SessionNotFound(x) => Some(serde_json::json!({
+                "sessionId": x.session_id,
+                "availableSessionCount": x.available_session_count,
+            })),
+            XenonResponse::ErrorCreatingSession(x) => Some(serde_json::json!({
+                "reason": x.reason,
+                "browser": x.browser,
+            })),
+            _ => None,
+        }
+    }
+}
+
+impl From<XenonResponse> for Body {
+    fn from(response: XenonResponse) -> Self {
+        Body::from(response.to_json_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::response::{SessionNotFoundError, XenonResponse};
+
+    #[test]
+    fn test_session_not_found_json() {
+        let response = XenonResponse::SessionNotFound(SessionNotFoundError {
+            session_id: "abc".to_string(),
+            available_session_count: 2,
+        });
+        let json: serde_json::Value = serde_json::from_str(&response.to_json_string()).unwrap();
+        assert_eq!(json["value"]["message"], "abc");
+        assert_eq!(json["value"]["error"], "invalid session id");
+        assert_eq!(json["value"]["data"]["availableSessionCount"], 2);
     }
 }
@@ -1,43 +1,105 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use hyper::http::uri::{Authority, Scheme};
+use clap::Parser;
+use hyper::client::HttpConnector;
+use hyper::http::uri::{Authority, InvalidUri, Scheme};
 use hyper::server::conn::AddrStream;
+#[cfg(feature = "tls")]
+use hyper::server::conn::Http;
 use hyper::server::Server;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Client, Request, Response, StatusCode};
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
 use log::*;
-
-use structopt::StructOpt;
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 use tokio::time::{sleep, Duration};
+use tracing::{info_span, Instrument};
 
+use crate::access_log::{AccessLogRecord, AccessLogger};
 use crate::browser::{Capabilities, W3CCapabilities};
-use crate::config::load_config;
+use crate::config::{load_config, XenonConfig};
 use crate::error::{XenonError, XenonResult};
-use crate::nodes::{NodeId, RemoteNode, RemoteServiceGroup};
-use crate::response::XenonResponse;
-use crate::service::ServiceGroup;
-use crate::session::{Session, XenonSessionId};
-use crate::state::XenonState;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::nodes::{
+    NodeHealthStatus, NodeId, RemoteNode, RemoteNodeCreate, RemoteServiceGroup, COMMS_ID_HEADER,
+};
+use crate::portmanager::{PortManager, ServicePort};
+use crate::queue::QueueWaiter;
+use crate::request_util::{build_proxy_request, constant_time_eq, is_websocket_upgrade};
+use crate::response::{CreateSessionError, SessionNotFoundError, XenonResponse};
+use crate::service::terminate_services;
+use crate::session::{Session, SessionCreate, SessionInfo, Upstream, XenonSessionId};
+use crate::state::{groups_and_nodes, state_write, XenonState};
+use crate::status::{ServiceGroupStatus, StatusResponse};
+#[cfg(feature = "tls")]
+use crate::tls::load_tls_acceptor;
 use indexmap::map::IndexMap;
 
-#[derive(Debug, StructOpt)]
-#[structopt(name = "Xenon", about = "A powerful WebDriver proxy")]
+#[derive(Debug, Parser)]
+#[command(name = "Xenon", about = "A powerful WebDriver proxy")]
 pub struct Opt {
     /// The port to listen on. Default is 4444.
-    #[structopt(short, long, env = "XENON_PORT")]
+    #[arg(short, long, env = "XENON_PORT")]
     port: Option<u16>,
 
-    /// The path to the YAML config file. Default is xenon.yml.
-    #[structopt(short, long, parse(from_os_str), env = "XENON_CFG")]
+    /// The IP address to listen on. Use 0.0.0.0 to listen on all interfaces.
+    #[arg(long, default_value = "127.0.0.1", env = "XENON_BIND_ADDR")]
+    bind_addr: String,
+
+    /// The path to the YAML or JSON config file. Default is xenon.yml.
+    #[arg(short, long, env = "XENON_CFG")]
     cfg: Option<PathBuf>,
+
+    /// Require clients to send `Authorization: Bearer <token>` on every request
+    /// except GET /status.
+    #[arg(long, env = "XENON_AUTH_TOKEN")]
+    auth_token: Option<String>,
+
+    /// The maximum number of sessions across all browsers and nodes.
+    /// Each browser is still limited by its own max_sessions.
+    #[arg(long, env = "XENON_MAX_SESSIONS")]
+    max_sessions: Option<usize>,
+
+    /// Write a JSON access log entry for every request to this file.
+    #[arg(long, env = "XENON_ACCESS_LOG")]
+    access_log: Option<PathBuf>,
+
+    /// Start without a config file, using an empty config.
+    #[arg(long, conflicts_with = "cfg")]
+    no_config: bool,
+
+    /// Load and validate the config, print the port allocation table and exit
+    /// without starting the server.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// The number of seconds to wait for active sessions to finish during shutdown.
+    #[arg(
+        long,
+        default_value = "30",
+        env = "XENON_GRACEFUL_SHUTDOWN_TIMEOUT_SECS"
+    )]
+    graceful_shutdown_timeout_secs: u64,
+
+    /// The path to a PEM-encoded certificate chain. Requires --tls-key.
+    #[cfg(feature = "tls")]
+    #[arg(long, env = "XENON_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// The path to a PEM-encoded private key. Requires --tls-cert.
+    #[cfg(feature = "tls")]
+    #[arg(long, env = "XENON_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
 }
 
 pub async fn start_server() -> XenonResult<()> {
-    let opt = Opt::from_args();
+    let opt = Opt::parse();
 
     // Prefer CLI arg, otherwise environment variable, otherwise 4444.
     let port: u16 = opt.port.unwrap_or(4444);
@@ -45,33 +107,152 @@ pub async fn start_server() -> XenonResult<()> {
         return Err(XenonError::InvalidPort);
     }
 
-    let addr: SocketAddr = format!("127.0.0.1:{}", port)
-        .parse()
-        .map_err(|_| XenonError::InvalidPort)?;
+    let addr = socket_addr(&opt.bind_addr, port)?;
+
+    #[cfg(feature = "tls")]
+    let tls_acceptor = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => Some(load_tls_acceptor(cert, key)?),
+        (None, None) => None,
+        _ => {
+            return Err(XenonError::TlsError(
+                "--tls-cert and --tls-key must be specified together".to_string(),
+            ))
+        }
+    };
 
     // Read config.
-    let config_filename = opt.cfg.unwrap_or_else(|| PathBuf::from("xenon.yml"));
-    let config = load_config(&config_filename)?;
+    let config = if opt.no_config {
+        XenonConfig::default()
+    } else {
+        let config_filename = opt.cfg.unwrap_or_else(|| PathBuf::from("xenon.yml"));
+        load_config(&config_filename)?
+    };
     debug!("Config loaded:\n{:#?}", config);
-    let using_nodes = config.has_nodes();
-    let state = Arc::new(RwLock::new(XenonState::new(config)?));
+    let timeout_check_interval = Duration::from_secs(config.timeout_check_interval_secs());
+    let node_health_check_interval = Duration::from_secs(config.node_health_check_interval_secs());
+    if opt.dry_run {
+        // Check the browsers and nodes without creating the state, so that no
+        // WebDrivers are pre-warmed.
+        groups_and_nodes(config.clone())?;
+        println!(
+            "Config OK. Ports:\n{}",
+            port_allocation_table(&PortManager::new(&config))
+        );
+        return Ok(());
+    }
+    let mut state = XenonState::new(config).await?;
+    {
+        let rwlock_port_manager = state.port_manager();
+        let port_manager = rwlock_port_manager.read().await;
+        debug!(
+            "Ports configured: {}, available: {}",
+            port_manager.all_ports().count(),
+            port_manager.available_ports().count()
+        );
+    }
+    state.set_auth_token(opt.auth_token);
+    #[cfg(feature = "tls")]
+    state.set_tls(tls_acceptor.is_some());
+    state.set_max_sessions(opt.max_sessions);
+    if let Some(path) = &opt.access_log {
+        state.set_access_logger(AccessLogger::open(path).await?);
+    }
+    let state = Arc::new(RwLock::new(state));
+    let state_shutdown = state.clone();
+
+    // Once a shutdown signal is received, stop creating sessions and give the
+    // active ones a chance to finish before the server stops.
+    let shutdown = {
+        let state = state.clone();
+        let timeout = Duration::from_secs(opt.graceful_shutdown_timeout_secs);
+        async move {
+            shutdown_signal().await;
+            info!("Shutting down. No new sessions will be accepted");
+            state.read().await.start_draining().await;
+            drain_and_wait(state, timeout).await;
+        }
+    };
 
     let (tx_terminator, rx_terminator) = tokio::sync::oneshot::channel();
 
     // Spawn session timeout task.
     let state_clone = state.clone();
     tokio::spawn(async move {
-        process_session_timeout(state_clone, rx_terminator).await;
+        process_session_timeout(state_clone, timeout_check_interval, rx_terminator).await;
     });
-    if using_nodes {
-        // Spawn config getter.
-        let state_clone = state.clone();
-        tokio::spawn(async move {
-            process_node_init(state_clone).await;
-        });
+    // Spawn config getter and node health checker. The health checker runs even
+    // without any configured nodes, so that nodes registered later are also checked.
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        process_node_init(state_clone, node_health_check_interval).await;
+    });
+
+    #[cfg(feature = "tls")]
+    let result = match tls_acceptor {
+        Some(acceptor) => serve_tls(addr, acceptor, state, shutdown).await,
+        None => serve_http(addr, state, shutdown).await,
+    };
+    #[cfg(not(feature = "tls"))]
+    let result = serve_http(addr, state, shutdown).await;
+
+    terminate_all_services(state_shutdown).await;
+
+    if let Err(e) = tx_terminator.send(true) {
+        error!("Error terminating timeout task: {:?}", e);
+    }
+
+    result
+}
+
+/// Format the managed ports as a table, one line per range of consecutive ports
+/// with the same status.
+fn port_allocation_table(port_manager: &PortManager) -> String {
+    let available: Vec<ServicePort> = port_manager.available_ports().collect();
+    let mut ranges: Vec<(u16, u16, bool)> = Vec::new();
+    for port in port_manager.all_ports() {
+        let is_available = available.binary_search(&port).is_ok();
+        let port = u16::from(port);
+        match ranges.last_mut() {
+            Some((_, end, status)) if *end + 1 == port && *status == is_available => {
+                *end = port;
+            }
+            _ => ranges.push((port, port, is_available)),
+        }
     }
 
-    // And a MakeService to handle each connection...
+    if ranges.is_empty() {
+        return "(none)".to_string();
+    }
+    ranges
+        .into_iter()
+        .map(|(start, end, is_available)| {
+            let range = if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            };
+            let status = if is_available { "available" } else { "taken" };
+            format!("{:<12} {}", range, status)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Build the address to listen on.
+fn socket_addr(bind_addr: &str, port: u16) -> XenonResult<SocketAddr> {
+    let ip: IpAddr = bind_addr
+        .parse()
+        .map_err(|_| XenonError::InvalidBindAddress(bind_addr.to_string()))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Serve plain HTTP until the shutdown future completes.
+async fn serve_http(
+    addr: SocketAddr,
+    state: Arc<RwLock<XenonState>>,
+    shutdown: impl Future<Output = ()>,
+) -> XenonResult<()> {
+    // A MakeService to handle each connection...
     let make_service = make_service_fn(move |conn: &AddrStream| {
         // Clone state.
         let state = state.clone();
@@ -88,18 +269,140 @@ pub async fn start_server() -> XenonResult<()> {
 
     // Then bind and serve...
     info!("Server running at {}", addr);
-    let server = Server::bind(&addr).serve(make_service);
+    let server = Server::bind(&addr)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown);
 
-    // And run forever...
-    let result = server
+    // And run until shutdown...
+    server
         .await
-        .map_err(|e| XenonError::ServerError(e.to_string()));
+        .map_err(|e| XenonError::ServerError(e.to_string()))
+}
 
-    if let Err(e) = tx_terminator.send(true) {
-        error!("Error terminating timeout task: {:?}", e);
+/// How long a client has to complete the TLS handshake.
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait after an error accepting a connection (e.g. too many open files),
+/// so that the accept loop does not spin.
+#[cfg(feature = "tls")]
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Serve HTTPS until the shutdown future completes.
+/// hyper's `Server` only accepts plain TCP streams, so each connection is
+/// accepted and handshaked here before being handed to hyper.
+#[cfg(feature = "tls")]
+async fn serve_tls(
+    addr: SocketAddr,
+    acceptor: tokio_rustls::TlsAcceptor,
+    state: Arc<RwLock<XenonState>>,
+    shutdown: impl Future<Output = ()>,
+) -> XenonResult<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Server running at https://{}", addr);
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            _ = &mut shutdown => return Ok(()),
+            accepted = listener.accept() => match accepted {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Error accepting connection: {}", e);
+                    sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+        };
+
+        let acceptor = acceptor.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(s)) => s,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", remote_addr);
+                        return;
+                    }
+                };
+            let service = service_fn(move |req| handle(req, remote_addr, state.clone()));
+            if let Err(e) = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                debug!("Error serving connection from {}: {}", remote_addr, e);
+            }
+        });
     }
+}
 
-    result
+/// Wait for SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {},
+                    _ = tokio::signal::ctrl_c() => {},
+                }
+                return;
+            }
+            Err(e) => error!("Unable to listen for SIGTERM: {:?}", e),
+        }
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Unable to listen for Ctrl-C: {:?}", e);
+        // Never shut down rather than shutting down immediately.
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Terminate all remaining WebDriver processes. Any sessions still active are dropped.
+async fn terminate_all_services(state: Arc<RwLock<XenonState>>) {
+    let (rwlock_port_manager, rwlock_groups) = {
+        let s = state.read().await;
+        (s.port_manager(), s.service_groups())
+    };
+    let (mut port_manager, mut groups) =
+        tokio::join!(rwlock_port_manager.write(), rwlock_groups.write());
+    for group in groups.values_mut() {
+        group.terminate_all(&mut port_manager).await;
+    }
+}
+
+/// Wait for all active sessions to finish, up to the specified timeout.
+async fn drain_and_wait(state: Arc<RwLock<XenonState>>, timeout: Duration) {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let remaining = state.read().await.session_ids();
+        if remaining.is_empty() {
+            debug!("All sessions closed");
+            return;
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            warn!(
+                "Shutting down with {} active session(s): {:?}",
+                remaining.len(),
+                remaining
+            );
+            return;
+        }
+
+        info!(
+            "Waiting for {} active session(s) to finish...",
+            remaining.len()
+        );
+        sleep(Duration::new(1, 0)).await;
+    }
 }
 
 async fn handle(
@@ -107,80 +410,276 @@ async fn handle(
     remote_addr: SocketAddr,
     state: Arc<RwLock<XenonState>>,
 ) -> Result<Response<Body>, Infallible> {
+    let access_logger = state.read().await.access_logger();
+    let access_logger = match access_logger {
+        Some(x) => x,
+        None => return handle_request(req, remote_addr, state).await,
+    };
+
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let session_id = session_id_from_path(&path);
+    let start = tokio::time::Instant::now();
+    let response = handle_request(req, remote_addr, state).await?;
+    let record = AccessLogRecord::new(
+        remote_addr,
+        method,
+        path,
+        session_id,
+        response.status().as_u16(),
+        start.elapsed().as_millis() as u64,
+    );
+    access_logger.log(&record).await;
+    Ok(response)
+}
+
+/// Get the session id from a request path, for logging.
+fn session_id_from_path(path: &str) -> Option<String> {
+    let mut elements = path.split('/').filter(|s| !s.is_empty());
+    elements.by_ref().find(|x| *x == "session")?;
+    elements.next().map(|x| x.to_string())
+}
+
+async fn handle_request(
+    mut req: Request<Body>,
+    remote_addr: SocketAddr,
+    state: Arc<RwLock<XenonState>>,
+) -> Result<Response<Body>, Infallible> {
+    info!("{} {}", req.method(), req.uri());
+
+    let (base_path, cors_allow_origin, auth_token, expose_internal_errors) = {
+        let s = state.read().await;
+        let rwlock_config = s.config();
+        let config = rwlock_config.read().await;
+        if let Err(e) = s.check_rate_limit(remote_addr.ip()) {
+            return Ok(no_store(error_response(e, config.expose_internal_errors())));
+        }
+        (
+            config.base_path().map(|x| x.to_string()),
+            config.cors_allow_origin().map(|x| x.to_string()),
+            s.auth_token().map(|x| x.to_string()),
+            config.expose_internal_errors(),
+        )
+    };
+
+    // Strip the base path (if any) so that all routes below see the same paths
+    // regardless of where Xenon is mounted.
+    if let Some(base_path) = base_path {
+        if let Err(e) = strip_base_path(&mut req, &base_path) {
+            return Ok(no_store(error_response(e, expose_internal_errors)));
+        }
+    }
+
     let top_level_path: &str = req
         .uri()
         .path()
         .trim_matches('/')
         .split('/')
         .next()
-        .unwrap_or_else(|| "");
-
-    info!("{} {}", req.method(), req.uri());
+        .unwrap_or("");
 
     // Routing for top-level path.
+    let is_options = req.method() == hyper::Method::OPTIONS;
     let result = match top_level_path {
-        x if x.is_empty() => Ok(Response::new(Body::from("TODO: show status page"))),
-        "session" => handle_session(req, state, false).await,
-        "wd" => handle_session(req, state, true).await,
+        "" | "status" if is_options => options_response("GET, OPTIONS"),
+        "status" => build_status_response(state).await,
+        // CORS preflight requests never carry credentials.
+        _ if !is_options && !is_authorized(&req, auth_token.as_deref()) => {
+            Err(XenonError::RespondWith(XenonResponse::Unauthorized))
+        }
+        "" => Ok(Response::new(Body::from("TODO: show status page"))),
+        "session" => handle_session(req, remote_addr, state, false).await,
+        "wd" => handle_session(req, remote_addr, state, true).await,
         "node" => handle_node(req, remote_addr, state).await,
-        "status" => Ok(Response::builder().status(200).body("OK".into()).unwrap()),
+        "admin" => handle_admin(req, state).await,
+        #[cfg(feature = "metrics")]
+        "metrics" => handle_metrics(state).await,
         p => Err(XenonError::RespondWith(XenonResponse::EndpointNotFound(
             p.to_string(),
         ))),
     };
 
-    match result {
-        Ok(x) => Ok(x),
-        Err(XenonError::RespondWith(r)) => {
+    let mut response = match result {
+        Ok(x) => x,
+        Err(e) => error_response(e, expose_internal_errors),
+    };
+    if let Some(origin) = cors_allow_origin {
+        add_cors_headers(&mut response, &origin, is_options);
+    }
+    Ok(no_store(response))
+}
+
+/// Prevent browsers and proxies from caching any WebDriver response.
+fn no_store(mut response: Response<Body>) -> Response<Body> {
+    response.headers_mut().insert(
+        hyper::header::CACHE_CONTROL,
+        hyper::header::HeaderValue::from_static("no-store, no-cache, must-revalidate"),
+    );
+    response
+}
+
+/// Response for an OPTIONS request, listing the allowed methods for the endpoint.
+fn options_response(allow: &'static str) -> XenonResult<Response<Body>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::ALLOW, allow)
+        .body(Body::empty())
+        .map_err(|e| XenonError::ServerError(e.to_string()))
+}
+
+/// Add CORS headers to the response. For pre-flight (OPTIONS) requests, the allowed
+/// methods are taken from the `Allow` header set by `options_response()`.
+fn add_cors_headers(response: &mut Response<Body>, origin: &str, is_options: bool) {
+    let headers = response.headers_mut();
+    if let Ok(value) = origin.parse() {
+        headers.insert(hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+    }
+    if is_options {
+        if let Some(allow) = headers.get(hyper::header::ALLOW).cloned() {
+            headers.insert(hyper::header::ACCESS_CONTROL_ALLOW_METHODS, allow);
+        }
+        headers.insert(
+            hyper::header::ACCESS_CONTROL_ALLOW_HEADERS,
+            hyper::header::HeaderValue::from_static("Authorization, Content-Type"),
+        );
+    }
+}
+
+/// Remove the base path from the start of the request uri.
+fn strip_base_path(req: &mut Request<Body>, base_path: &str) -> XenonResult<()> {
+    let path = req.uri().path();
+    let stripped = match path.trim_start_matches('/').strip_prefix(base_path) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => {
+            return Err(XenonError::RespondWith(XenonResponse::EndpointNotFound(
+                path.to_string(),
+            )));
+        }
+    };
+
+    let path_and_query = match req.uri().query() {
+        Some(q) => format!("/{}?{}", stripped.trim_start_matches('/'), q),
+        None => format!("/{}", stripped.trim_start_matches('/')),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(
+        path_and_query
+            .parse()
+            .map_err(|e: InvalidUri| XenonError::RequestError(e.to_string()))?,
+    );
+    *req.uri_mut() = Uri::from_parts(parts).map_err(|e| XenonError::RequestError(e.to_string()))?;
+    Ok(())
+}
+
+/// Convert an error into a WebDriver-compatible response.
+fn error_response(e: XenonError, expose_internal_errors: bool) -> Response<Body> {
+    match e {
+        XenonError::RespondWith(XenonResponse::InternalServerError(message)) => {
+            internal_error_response(message.clone(), &message, expose_internal_errors)
+        }
+        XenonError::RespondWith(r) => {
             debug!("Xenon replied with error: {:#?}", r);
-            Ok(r.into_response())
+            r.into_response()
         }
-        Err(e) => {
+        XenonError::NodeTimeout(message) => {
+            warn!("Node timeout: {}", message);
+            XenonResponse::NodeTimeout(message).into_response()
+        }
+        e => {
             // Coerce all errors into WebDriver-compatible response.
-            error!("Internal Error: {:#?}", e);
-            let r = XenonResponse::InternalServerError(e.to_string());
-            Ok(r.into_response())
+            internal_error_response(e.to_string(), &format!("{:#?}", e), expose_internal_errors)
         }
     }
 }
 
+/// Log an internal error with a reference id. Unless `expose_internal_errors` is set, the
+/// client only gets the reference, since the message may contain internal details.
+fn internal_error_response(
+    message: String,
+    details: &str,
+    expose_internal_errors: bool,
+) -> Response<Body> {
+    let reference = uuid::Uuid::new_v4();
+    error!("Internal Error [{}]: {}", reference, details);
+    let message = if expose_internal_errors {
+        message
+    } else {
+        format!("An internal error occurred (reference: {})", reference)
+    };
+    XenonResponse::InternalServerError(message).into_response()
+}
+
 async fn handle_session(
     req: Request<Body>,
+    remote_addr: SocketAddr,
     state: Arc<RwLock<XenonState>>,
     selenium_compatibility: bool,
 ) -> XenonResult<Response<Body>> {
-    let mut path_elements: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+    let path = req.uri().path();
+    if !is_valid_path(path) {
+        return Err(XenonError::RespondWith(XenonResponse::EndpointNotFound(
+            path.to_string(),
+        )));
+    }
+    let path_elements = session_path_elements(path, selenium_compatibility);
 
-    // We can mimic selenium by ignoring the path /wd/hub if it exists.
-    if selenium_compatibility
-        && path_elements.len() > 2
-        && path_elements[0] == "wd"
-        && path_elements[1] == "hub"
-    {
-        // Selenium endpoint. Just remove these from the path.
-        path_elements = path_elements.split_off(2);
+    if req.method() == hyper::Method::OPTIONS {
+        return match path_elements.len() {
+            1 => options_response("GET, POST, OPTIONS"),
+            _ => options_response("GET, POST, DELETE, OPTIONS"),
+        };
     }
 
     match path_elements.len() {
-        0 => unreachable!(),
+        0 => Err(XenonError::RespondWith(XenonResponse::EndpointNotFound(
+            path.to_string(),
+        ))),
         1 => match *req.method() {
+            hyper::Method::GET => handle_list_sessions(state).await,
             hyper::Method::POST => {
                 // Create session.
+                let websocket_base = {
+                    let s = state.read().await;
+                    if s.is_draining() {
+                        return Err(XenonError::RespondWith(XenonResponse::ShuttingDown));
+                    }
+                    let rwlock_config = s.config();
+                    let config = rwlock_config.read().await;
+                    websocket_base_url(&req, s.is_tls(), config.base_path())
+                };
                 let body_bytes = hyper::body::to_bytes(req).await.map_err(|e| {
-                    XenonError::RespondWith(XenonResponse::ErrorCreatingSession(e.to_string()))
+                    XenonError::RespondWith(XenonResponse::ErrorCreatingSession(
+                        e.to_string().into(),
+                    ))
                 })?;
 
                 let w3c_capabilities: W3CCapabilities = serde_json::from_slice(&body_bytes)
                     .map_err(|e| {
-                        XenonError::RespondWith(XenonResponse::ErrorCreatingSession(e.to_string()))
+                        XenonError::RespondWith(XenonResponse::ErrorCreatingSession(
+                            e.to_string().into(),
+                        ))
                     })?;
                 info!("Request new session :: {:#?}", &w3c_capabilities);
                 let capabilities: Capabilities =
-                    serde_json::from_value(w3c_capabilities.capabilities.clone()).map_err(|e| {
-                        XenonError::RespondWith(XenonResponse::ErrorCreatingSession(e.to_string()))
-                    })?;
-
-                match handle_create_session(&capabilities, &w3c_capabilities, state.clone()).await {
+                    serde_json::from_value(w3c_capabilities.capabilities.as_value().clone())
+                        .map_err(|e| {
+                            XenonError::RespondWith(XenonResponse::ErrorCreatingSession(
+                                e.to_string().into(),
+                            ))
+                        })?;
+
+                let span = info_span!("create_session", browser = capabilities.browser_name());
+                let websocket_base = websocket_base.as_deref();
+                match handle_create_session(
+                    &capabilities,
+                    &w3c_capabilities,
+                    websocket_base,
+                    remote_addr,
+                    state.clone(),
+                )
+                .instrument(span)
+                .await
+                {
                     Ok(x) => Ok(x),
                     Err(XenonError::RespondWith(XenonResponse::NoSessionsAvailable)) => {
                         // In this case there is at least 1 matching browser locally, so even if
@@ -189,6 +688,8 @@ async fn handle_session(
                         match handle_create_session_node(
                             &capabilities,
                             &w3c_capabilities,
+                            websocket_base,
+                            remote_addr,
                             state.clone(),
                         )
                         .await
@@ -201,8 +702,14 @@ async fn handle_session(
                         }
                     }
                     Err(XenonError::RespondWith(XenonResponse::NoMatchingBrowser)) => {
-                        handle_create_session_node(&capabilities, &w3c_capabilities, state.clone())
-                            .await
+                        handle_create_session_node(
+                            &capabilities,
+                            &w3c_capabilities,
+                            websocket_base,
+                            remote_addr,
+                            state.clone(),
+                        )
+                        .await
                     }
                     Err(e) => Err(e),
                 }
@@ -230,7 +737,10 @@ async fn handle_session(
                     Some(x) => x,
                     None => {
                         return Err(XenonError::RespondWith(XenonResponse::SessionNotFound(
-                            xsession_id.to_string(),
+                            SessionNotFoundError {
+                                session_id: xsession_id.to_string(),
+                                available_session_count: s.num_sessions(),
+                            },
                         )))
                     }
                 }
@@ -238,7 +748,45 @@ async fn handle_session(
 
             let remaining_path: String = path_elements[2..].join("/");
             let mut session = mutex_session.lock().await;
-            let response = session.forward_request(req, &remaining_path).await?;
+            if path_elements.len() == 2
+                && path_elements[0] == "session"
+                && req.method() == hyper::Method::GET
+            {
+                return handle_session_metadata(req, &mut session).await;
+            }
+            if path_elements.len() == 3
+                && path_elements[0] == "session"
+                && path_elements[2] == "refresh-timeout"
+                && req.method() == hyper::Method::POST
+                && session.service_group().is_some()
+            {
+                // Remote sessions are forwarded below instead, since the node
+                // has its own timeout for the session.
+                session.refresh_timeout();
+                return json_response(StatusCode::OK, &serde_json::json!({ "value": null }));
+            }
+
+            if is_websocket_upgrade(req.headers()) {
+                let span = info_span!(
+                    "forward_websocket",
+                    session_id = %xsession_id,
+                    endpoint = %remaining_path
+                );
+                return session
+                    .forward_websocket(req, &remaining_path)
+                    .instrument(span)
+                    .await;
+            }
+
+            let span = info_span!(
+                "forward_request",
+                session_id = %xsession_id,
+                endpoint = %remaining_path
+            );
+            let mut response = session
+                .forward_request(req, &remaining_path)
+                .instrument(span)
+                .await?;
 
             if is_delete && response.status().is_success() {
                 info!(
@@ -246,28 +794,41 @@ async fn handle_session(
                     xsession_id,
                     session.port()
                 );
+                // The response body is streamed from the WebDriver, so it must be read
+                // before the service is terminated below.
+                let (parts, body) = response.into_parts();
+                let bytes = hyper::body::to_bytes(body)
+                    .await
+                    .map_err(|e| XenonError::RequestError(e.to_string()))?;
+                response = Response::from_parts(parts, Body::from(bytes));
+
                 // Remove the actual session under write-lock. This should be fast.
                 {
-                    let mut s = state.write().await;
+                    let mut s = state_write!(state, "session delete");
                     s.delete_session(&xsession_id);
                 }
 
                 // For local sessions, remove the session from its service group.
                 if let Some(session_group) = session.service_group() {
-                    // Remove the session reference under read-lock on state and write-lock on
-                    // service group. The service may self-destruct if this was the last connection
-                    // to it.
-                    let s = state.read().await;
-                    let rwlock_groups = s.service_groups();
-                    let rwlock_port_manager = s.port_manager();
-                    let (mut port_manager, mut groups) =
-                        tokio::join!(rwlock_port_manager.write(), rwlock_groups.write());
-
-                    if let Some(group) = groups.get_mut(session_group) {
-                        group
-                            .delete_session(session.port(), &xsession_id, &mut port_manager)
+                    // Remove the session reference under write-lock on the service groups.
+                    // If this was the last connection to the service, it is terminated after
+                    // the lock has been released.
+                    let (rwlock_groups, rwlock_port_manager) = {
+                        let s = state.read().await;
+                        (s.service_groups(), s.port_manager())
+                    };
+                    let detached = rwlock_groups
+                        .write()
+                        .await
+                        .get_mut(session_group)
+                        .and_then(|group| group.delete_session(session.port(), &xsession_id));
+                    if let Some(detached) = detached {
+                        terminate_services(vec![detached], &rwlock_port_manager)
+                            .instrument(info_span!("delete_session", session_id = %xsession_id))
                             .await;
                     }
+                } else {
+                    state.read().await.release_node_session(&xsession_id).await;
                 }
             }
 
@@ -276,120 +837,401 @@ async fn handle_session(
     }
 }
 
+/// The base URL that the client can use to open WebSockets to this server,
+/// based on the `Host` header of its request.
+fn websocket_base_url(req: &Request<Body>, tls: bool, base_path: Option<&str>) -> Option<String> {
+    let host = req
+        .headers()
+        .get(hyper::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().authority().map(|x| x.as_str()))?;
+    let scheme = if tls { "wss" } else { "ws" };
+    let base_path = base_path
+        .map(|x| x.trim_matches('/'))
+        .filter(|x| !x.is_empty())
+        .map(|x| format!("/{}", x))
+        .unwrap_or_default();
+    Some(format!("{}://{}{}", scheme, host, base_path))
+}
+
+/// Split a session path into its elements. All elements after the session id
+/// (e.g. `screenshot` or `element/{id}/screenshot`) are forwarded to the WebDriver as-is.
+fn session_path_elements(path: &str, selenium_compatibility: bool) -> Vec<&str> {
+    // Ignore empty elements, so that trailing or repeated slashes don't affect routing.
+    let mut path_elements: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    // We can mimic selenium by ignoring the path /wd/hub if it exists.
+    if selenium_compatibility
+        && path_elements.len() > 2
+        && path_elements[0] == "wd"
+        && path_elements[1] == "hub"
+    {
+        // Selenium endpoint. Just remove these from the path.
+        path_elements = path_elements.split_off(2);
+    }
+    path_elements
+}
+
+/// Reject paths containing relative components or null bytes, including percent-encoded ones.
+/// This is a defence-in-depth measure, since these should never be used by WebDriver clients.
+fn is_valid_path(path: &str) -> bool {
+    let decoded = percent_decode(path);
+    decoded
+        .split('/')
+        .all(|x| x != "." && x != ".." && !x.contains('\0'))
+}
+
+/// Decode `%XX` escapes. Invalid escapes are left as they are.
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|x| std::str::from_utf8(x).ok());
+            if let Some(b) = hex.and_then(|x| u8::from_str_radix(x, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// GET /session
+///
+/// List all active sessions, both local and remote.
+async fn handle_list_sessions(state: Arc<RwLock<XenonState>>) -> XenonResult<Response<Body>> {
+    // Release the state lock before locking each session, since a session
+    // could be busy with a slow request.
+    let sessions = state.read().await.sessions();
+    let mut session_list = Vec::with_capacity(sessions.len());
+    for mutex_session in sessions {
+        let session = mutex_session.lock().await;
+        session_list.push(SessionInfo::from((&*session, session.xsession_id())));
+    }
+    session_list.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    json_response(
+        StatusCode::OK,
+        &serde_json::json!({ "value": session_list }),
+    )
+}
+
+/// Handle GET /session/{id} by adding Xenon metadata to the WebDriver response.
+/// If the `X-Xenon-Metadata-Only: true` header is present, the WebDriver is not
+/// contacted and only the Xenon metadata is returned.
+async fn handle_session_metadata(
+    req: Request<Body>,
+    session: &mut Session,
+) -> XenonResult<Response<Body>> {
+    let metadata = serde_json::to_value(SessionInfo::from((&*session, session.xsession_id())))
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InternalServerError(e.to_string())))?;
+    let metadata_only = req
+        .headers()
+        .get("X-Xenon-Metadata-Only")
+        .map(|v| v.as_bytes().eq_ignore_ascii_case(b"true"))
+        .unwrap_or(false);
+
+    let (status, json_out) = if metadata_only {
+        (
+            StatusCode::OK,
+            serde_json::json!({ "value": { "xenon": metadata } }),
+        )
+    } else {
+        let response = session.forward_request(req, "").await?;
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+
+        let (parts, body) = response.into_parts();
+        let body_bytes = hyper::body::to_bytes(body)
+            .await
+            .map_err(|e| XenonError::RequestError(e.to_string()))?;
+        let mut json: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+            Ok(x) => x,
+            Err(_) => return Ok(Response::from_parts(parts, Body::from(body_bytes))),
+        };
+        match json.get_mut("value").and_then(|v| v.as_object_mut()) {
+            Some(value) => {
+                value.insert("xenon".to_string(), metadata);
+            }
+            None => return Ok(Response::from_parts(parts, Body::from(body_bytes))),
+        }
+        (parts.status, json)
+    };
+
+    json_response(status, &json_out)
+}
+
+/// Check the `Authorization: Bearer <token>` header against the expected token, if any.
+fn is_authorized(req: &Request<Body>, auth_token: Option<&str>) -> bool {
+    let expected = match auth_token {
+        Some(x) => x,
+        None => return true,
+    };
+    let provided = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        // The auth scheme is case-insensitive (RFC 7235).
+        .and_then(|v| v.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .map(|(_, token)| token);
+    match provided {
+        Some(token) => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+        None => false,
+    }
+}
+
+/// GET /metrics
+#[cfg(feature = "metrics")]
+async fn handle_metrics(state: Arc<RwLock<XenonState>>) -> XenonResult<Response<Body>> {
+    let snapshot = {
+        let s = state.read().await;
+        let rwlock_groups = s.service_groups();
+        let groups = rwlock_groups.read().await;
+        metrics::MetricsSnapshot {
+            sessions_active: s.num_sessions(),
+            services_active: groups.values().map(|group| group.num_services()).sum(),
+            peak_concurrent_sessions: groups
+                .values()
+                .map(|group| (group.name().to_string(), group.peak_concurrent_sessions()))
+                .collect(),
+            spawn_failures: groups
+                .values()
+                .map(|group| (group.name().to_string(), group.total_spawn_failures()))
+                .collect(),
+            nodes: s.node_health_summary().await,
+            totals: s.session_totals(),
+        }
+    };
+    let body = metrics::render(&snapshot)?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", prometheus::TEXT_FORMAT)
+        .body(Body::from(body))
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InternalServerError(e.to_string())))
+}
+
+/// GET /status
+///
+/// Always returns 200 while the server is up. Whether new sessions can be created
+/// is reported via `value.ready`. This only takes read locks.
+async fn build_status_response(state: Arc<RwLock<XenonState>>) -> XenonResult<Response<Body>> {
+    let s = state.read().await;
+    let service_groups: Vec<ServiceGroupStatus> = s
+        .service_groups()
+        .read()
+        .await
+        .values()
+        .map(ServiceGroupStatus::from)
+        .collect();
+    let nodes = s.node_health_summary().await;
+    let status = StatusResponse::new(
+        s.num_sessions(),
+        s.session_totals(),
+        service_groups,
+        nodes,
+        s.remaining_session_slots(),
+    );
+    json_response(StatusCode::OK, &serde_json::json!({ "value": status }))
+}
+
+/// Build a JSON response from any serializable value.
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> XenonResult<Response<Body>> {
+    let body_str = serde_json::to_string(value)
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InternalServerError(e.to_string())))?;
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body_str))
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InternalServerError(e.to_string())))
+}
+
 pub async fn handle_create_session(
     capabilities: &Capabilities,
     w3c_capabilities: &W3CCapabilities,
+    websocket_base: Option<&str>,
+    remote_addr: SocketAddr,
     state: Arc<RwLock<XenonState>>,
 ) -> XenonResult<Response<Body>> {
     let (xsession_id, port, group_name) =
         reserve_available_session(state.clone(), capabilities).await?;
-
-    // Create the session. No locks are held at all here.
-    info!("Session Create {:?} :: port {}", xsession_id, port);
     let authority: Authority = match format!("localhost:{}", port).parse() {
         Ok(a) => a,
         Err(e) => {
+            release_local_session(&state, &group_name, port, &xsession_id).await;
             return Err(XenonError::RespondWith(
-                XenonResponse::ErrorCreatingSession(format!("Invalid port '{}': {}", port, e)),
+                XenonResponse::ErrorCreatingSession(CreateSessionError::new(
+                    format!("Invalid port '{}': {}", port, e),
+                    Some(&group_name),
+                )),
             ));
         }
     };
-    match Session::create(
-        Scheme::HTTP,
-        authority,
-        Some(group_name.clone()),
-        &w3c_capabilities.capabilities,
-        &w3c_capabilities.desired_capabilities,
-        xsession_id.clone(),
-    )
-    .await
+    let upstream = Upstream::new(Scheme::HTTP, authority);
+    let (browser, process, client) = {
+        let s = state.read().await;
+        let rwlock_groups = s.service_groups();
+        let groups = rwlock_groups.read().await;
+        let client = s.client(&upstream);
+        match groups.get(&group_name) {
+            Some(group) => (Some(group.browser.clone()), group.process(port), client),
+            None => (None, None, client),
+        }
+    };
+
+    // Create the session. No locks are held at all here.
+    info!("Session Create {:?} :: port {}", xsession_id, port);
+    let span = info_span!("session_create", session_id = %xsession_id, port = %port);
+    let options = SessionCreate {
+        upstream,
+        client,
+        service_group: Some(group_name.clone()),
+        browser,
+        process,
+        xsession_id: xsession_id.clone(),
+        websocket_base: websocket_base.map(|x| x.to_string()),
+    };
+    match Session::create(options, w3c_capabilities)
+        .instrument(span)
+        .await
     {
-        Ok((session, response)) => {
+        Ok((mut session, response)) => {
             // Add session to pool.
-            let mut s = state.write().await;
-            s.add_session(xsession_id, session);
+            session.set_client_addr(remote_addr);
+            let mut s = state_write!(state, "session create");
+            s.add_reserved_session(xsession_id, session);
             // Forward the response back to the client.
             Ok(response)
         }
         Err(XenonError::ResponsePassThrough(response)) => {
             // Delete session from service.
-            let s = state.read().await;
-            let rwlock_groups = s.service_groups();
-            let rwlock_port_manager = s.port_manager();
-            let (mut port_manager, mut groups) =
-                tokio::join!(rwlock_port_manager.write(), rwlock_groups.write());
-            if let Some(group) = groups.get_mut(&group_name) {
-                group
-                    .delete_session(port, &xsession_id, &mut port_manager)
-                    .await;
-            }
-            Ok(response)
+            release_local_session(&state, &group_name, port, &xsession_id).await;
+            Ok(*response)
         }
         Err(e) => {
             // Delete session from service.
-            let s = state.read().await;
-            let rwlock_groups = s.service_groups();
-            let rwlock_port_manager = s.port_manager();
-            let (mut port_manager, mut groups) =
-                tokio::join!(rwlock_port_manager.write(), rwlock_groups.write());
-            if let Some(group) = groups.get_mut(&group_name) {
-                group
-                    .delete_session(port, &xsession_id, &mut port_manager)
-                    .await;
-            }
+            release_local_session(&state, &group_name, port, &xsession_id).await;
             Err(e)
         }
     }
 }
 
+/// Remove a session that could not be created from its service group, terminating
+/// the service if it is no longer needed. This also gives back its slot under the
+/// global session cap.
+async fn release_local_session(
+    state: &RwLock<XenonState>,
+    group_name: &str,
+    port: ServicePort,
+    xsession_id: &XenonSessionId,
+) {
+    let (rwlock_groups, rwlock_port_manager) = {
+        let s = state.read().await;
+        s.release_session_slot();
+        (s.service_groups(), s.port_manager())
+    };
+    let detached = rwlock_groups
+        .write()
+        .await
+        .get_mut(group_name)
+        .and_then(|group| group.delete_session(port, xsession_id));
+    if let Some(detached) = detached {
+        terminate_services(vec![detached], &rwlock_port_manager).await;
+    }
+}
+
+/// Reserve a session in the first matching service group with capacity.
+/// If `queue_timeout_secs` is set, wait up to that long for capacity to free up.
 pub async fn reserve_available_session(
     state: Arc<RwLock<XenonState>>,
     capabilities: &Capabilities,
-) -> XenonResult<(XenonSessionId, u16, String)> {
-    let s = state.read().await;
-    let rwlock_groups = s.service_groups();
+) -> XenonResult<(XenonSessionId, ServicePort, String)> {
+    let queue_timeout = {
+        let s = state.read().await;
+        let rwlock_config = s.config();
+        let timeout = rwlock_config.read().await.queue_timeout_secs();
+        Duration::from_secs(timeout)
+    };
+    if queue_timeout.is_zero() {
+        return try_reserve_session(state, capabilities).await;
+    }
 
-    // We can do the capability matching under a read lock.
-    let group_names = {
+    let deadline = tokio::time::Instant::now() + queue_timeout;
+    // Join the queues once, so that this request keeps its place while it waits.
+    let mut waiter = QueueWaiter::new();
+    {
+        let s = state.read().await;
+        let rwlock_groups = s.service_groups();
         let groups = rwlock_groups.read().await;
-
-        let matching_groups: Vec<&ServiceGroup> = groups
+        for group in groups
             .values()
             .filter(|v| v.matches_capabilities(capabilities))
-            .collect();
-        if matching_groups.is_empty() {
-            return Err(XenonError::RespondWith(XenonResponse::NoMatchingBrowser));
+        {
+            waiter.join(group.queue());
         }
+    }
 
-        let matching_group_names: Vec<String> = matching_groups
-            .iter()
-            .filter(|v| v.has_capacity())
-            .map(|v| v.name().to_string())
-            .collect();
-        if matching_group_names.is_empty() {
-            return Err(XenonError::RespondWith(XenonResponse::NoSessionsAvailable));
+    loop {
+        match try_reserve_session(state.clone(), capabilities).await {
+            Err(XenonError::RespondWith(XenonResponse::NoSessionsAvailable))
+                if tokio::time::Instant::now() < deadline =>
+            {
+                debug!("No sessions available. Waiting for a session to be released...");
+                waiter.wait(deadline).await?;
+            }
+            result => return result,
         }
+    }
+}
 
-        matching_group_names
-    };
+async fn try_reserve_session(
+    state: Arc<RwLock<XenonState>>,
+    capabilities: &Capabilities,
+) -> XenonResult<(XenonSessionId, ServicePort, String)> {
+    let s = state.read().await;
+    let rwlock_groups = s.service_groups();
 
-    // Now we get a write lock to add the new session/service.
+    // Get a write lock to add the new session/service.
     // This only holds a write lock on the port manager and service groups,
     // so it only blocks the creation or deletion of other services or sessions.
     // This will not block any in-progress sessions.
+    // The capacity check must also happen under this lock, otherwise two concurrent
+    // requests could both see capacity for the last available session in a group.
     let rwlock_port_manager = s.port_manager();
     let (mut port_manager, mut groups) =
         tokio::join!(rwlock_port_manager.write(), rwlock_groups.write());
 
+    let group_names: Vec<String> = groups
+        .values()
+        .filter(|v| v.matches_capabilities(capabilities))
+        .map(|v| v.name().to_string())
+        .collect();
+    if group_names.is_empty() {
+        return Err(XenonError::RespondWith(XenonResponse::NoMatchingBrowser));
+    }
+    if !s.try_reserve_session_slot() {
+        return Err(XenonError::RespondWith(XenonResponse::NoSessionsAvailable));
+    }
+
     // Note that a new session request might match several groups.
     // If any session fails to start, fallback to the next available group.
     let mut first_error: Option<XenonError> = None;
     for group_name in group_names {
         let group = groups.get_mut(&group_name).unwrap();
+        if !group.has_capacity() {
+            continue;
+        }
 
         match group.get_or_start_service(&mut port_manager).await {
             Ok(service) => {
-                let xsession_id = XenonSessionId::new();
+                let xsession_id = s.new_session_id().await;
                 service.add_session(xsession_id.clone());
                 return Ok((xsession_id, service.port(), group_name));
             }
@@ -401,108 +1243,493 @@ pub async fn reserve_available_session(
         }
     }
 
+    s.release_session_slot();
     Err(first_error.unwrap_or(XenonError::RespondWith(XenonResponse::NoSessionsAvailable)))
 }
 
 pub async fn handle_create_session_node(
     capabilities: &Capabilities,
     w3c_capabilities: &W3CCapabilities,
+    websocket_base: Option<&str>,
+    remote_addr: SocketAddr,
     state: Arc<RwLock<XenonState>>,
 ) -> XenonResult<Response<Body>> {
     // Note we need to get the node data under read lock but we need to give that up
     // asap because we need a write lock later once a session is created.
-    let (node_data, matched_caps) = {
+    let (node_data, matched_caps, xsession_id, node_timeout) = {
         let s = state.read().await;
         let rwlock_nodes = s.remote_nodes();
         let nodes = rwlock_nodes.read().await;
         let mut node_data = Vec::new();
         let mut matched_caps = false;
         for node in nodes.values() {
-            for group in &node.service_groups {
+            for (group_index, group) in node.service_groups.iter().enumerate() {
                 if group.browser.matches_capabilities(capabilities) {
                     matched_caps = true;
-                    if group.remaining_sessions > 0 {
+                    if !node.is_disabled() && group.remaining() > 0 {
+                        let upstream = node.upstream();
+                        let client = s.client(&upstream);
                         node_data.push((
+                            node.id(),
+                            group_index,
                             node.display_name(),
-                            node.scheme.clone(),
-                            node.authority.clone(),
+                            upstream,
+                            client,
                         ));
                     }
                 }
             }
         }
-        (node_data, matched_caps)
+        let node_timeout = s.config().read().await.node_timeout_secs();
+        (
+            node_data,
+            matched_caps,
+            s.new_session_id().await,
+            Duration::from_secs(node_timeout),
+        )
     };
 
-    let xsession_id = XenonSessionId::new();
-    for (name, scheme, authority) in node_data {
+    // Reserve a slot under the global session cap before contacting any node.
+    if matched_caps && !state.read().await.try_reserve_session_slot() {
+        return Err(XenonError::RespondWith(XenonResponse::NoSessionsAvailable));
+    }
+
+    let mut timed_out = None;
+    for (node_id, group_index, name, upstream, client) in node_data {
         info!(
             "Attempt Session Create {:?} :: Node '{}'",
             xsession_id, name
         );
-        if let Ok((session, response)) = Session::create(
-            scheme,
-            authority,
-            None,
-            &w3c_capabilities.capabilities,
-            &w3c_capabilities.desired_capabilities,
-            xsession_id.clone(),
-        )
-        .await
-        {
+        let span = info_span!("session_create", session_id = %xsession_id, node = %name);
+        let options = SessionCreate {
+            upstream,
+            client,
+            service_group: None,
+            browser: None,
+            process: None,
+            xsession_id: xsession_id.clone(),
+            websocket_base: websocket_base.map(|x| x.to_string()),
+        };
+        // The create is spawned so that it can run to completion even after the timeout.
+        let mut create = {
+            let w3c_capabilities = w3c_capabilities.clone();
+            tokio::spawn(
+                async move { Session::create(options, &w3c_capabilities).await }.instrument(span),
+            )
+        };
+        let result = match tokio::time::timeout(node_timeout, &mut create).await {
+            Ok(joined) => joined.unwrap_or_else(|e| Err(XenonError::RequestError(e.to_string()))),
+            Err(_) => {
+                delete_late_node_session(create, name.clone());
+                Err(XenonError::NodeTimeout(format!(
+                    "Node '{}' did not create a session within {} seconds",
+                    name,
+                    node_timeout.as_secs()
+                )))
+            }
+        };
+        if let Err(XenonError::NodeTimeout(message)) = result {
+            // Try the next node, but report the timeout if no node succeeds.
+            warn!("Session Create {:?} :: {}", xsession_id, message);
+            timed_out = Some(message);
+        } else if let Ok((mut session, response)) = result {
             // Add session to pool. Write lock here.
-            let mut s = state.write().await;
-            s.add_session(xsession_id, session);
+            session.set_client_addr(remote_addr);
+            let mut s = state_write!(state, "node session create");
+            if let Some(node) = s.remote_nodes().write().await.get_mut(&node_id) {
+                node.add_session(group_index, xsession_id.clone());
+            }
+            s.add_reserved_session(xsession_id, session);
             // Forward the response back to the client.
             return Ok(response);
         }
     }
 
     if matched_caps {
+        state.read().await.release_session_slot();
+    }
+    if let Some(message) = timed_out {
+        Err(XenonError::NodeTimeout(message))
+    } else if matched_caps {
         Err(XenonError::RespondWith(XenonResponse::NoSessionsAvailable))
     } else {
         Err(XenonError::RespondWith(XenonResponse::NoMatchingBrowser))
     }
 }
 
+/// How long to wait for a node to delete a timed out session.
+const REMOTE_DELETE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait in the background for a node session create that timed out. If the node creates
+/// the session after all, delete it, since the client has already moved on and the
+/// session would otherwise hold the node's capacity until it times out there.
+fn delete_late_node_session(
+    create: tokio::task::JoinHandle<XenonResult<(Session, Response<Body>)>>,
+    node_name: String,
+) {
+    tokio::spawn(async move {
+        let session = match create.await {
+            Ok(Ok((session, _))) => session,
+            _ => return,
+        };
+        info!(
+            "Session Delete {:?} :: Node '{}' created it after the timeout",
+            session.xsession_id(),
+            node_name
+        );
+        let result = tokio::time::timeout(REMOTE_DELETE_TIMEOUT, session.delete_remote_session())
+            .await
+            .unwrap_or_else(|_| Err(XenonError::RequestError("timed out".to_string())));
+        if let Err(e) = result {
+            warn!(
+                "Failed to delete late session {:?} on node '{}': {}",
+                session.xsession_id(),
+                node_name,
+                e
+            );
+        }
+    });
+}
+
 async fn process_session_timeout(
     state: Arc<RwLock<XenonState>>,
+    interval: Duration,
     mut rx: tokio::sync::oneshot::Receiver<bool>,
 ) {
     while rx.try_recv().is_err() {
-        let timedout_sessions = {
-            let s = state.read().await;
-            s.get_timeout_sessions().await
-        };
+        // The read lock is only held while checking timestamps, which never
+        // waits on a session that is busy with a request.
+        let timedout_sessions = state.read().await.get_timeout_sessions().await;
 
         if !timedout_sessions.is_empty() {
-            let mut s = state.write().await;
-            let rwlock_groups = s.service_groups();
-            let rwlock_port_manager = s.port_manager();
-            let (mut port_manager, mut groups) =
-                tokio::join!(rwlock_port_manager.write(), rwlock_groups.write());
+            // Remove the sessions under write-lock. This should be fast.
+            // The write-lock is released before touching the service groups so that
+            // other sessions are not blocked while services are being terminated.
+            let (deleted_sessions, rwlock_groups, rwlock_port_manager) = {
+                let mut s = state_write!(state, "session timeout");
+                let deleted_sessions: Vec<_> = timedout_sessions
+                    .into_iter()
+                    .filter_map(|xsession_id| {
+                        s.expire_session(&xsession_id)
+                            .map(|mutex_session| (xsession_id, mutex_session))
+                    })
+                    .collect();
+                (deleted_sessions, s.service_groups(), s.port_manager())
+            };
 
-            for xsession_id in timedout_sessions {
-                if let Some(mutex_session) = s.delete_session(&xsession_id) {
-                    let session = mutex_session.lock().await;
+            // Collect the port and group for each local session.
+            let mut local_sessions = Vec::new();
+            for (xsession_id, mutex_session) in deleted_sessions {
+                let session = mutex_session.lock().await;
+                info!(
+                    "Session Timeout {:?} :: port {}",
+                    xsession_id,
+                    session.port()
+                );
+                if let Some(session_group) = session.service_group() {
+                    local_sessions.push((xsession_id, session.port(), session_group.clone()));
+                } else {
+                    // Otherwise the node keeps the session until it times out there too.
+                    // This is done in the background so that an unresponsive node does not
+                    // hold up the reaper.
+                    let state = state.clone();
+                    let mutex_session = mutex_session.clone();
+                    tokio::spawn(async move {
+                        let session = mutex_session.lock().await;
+                        let result = tokio::time::timeout(
+                            REMOTE_DELETE_TIMEOUT,
+                            session.delete_remote_session(),
+                        )
+                        .await
+                        .unwrap_or_else(|_| Err(XenonError::RequestError("timed out".to_string())));
+                        if let Err(e) = result {
+                            warn!(
+                                "Failed to delete timed out session {:?} on node {}: {}",
+                                xsession_id,
+                                session.authority(),
+                                e
+                            );
+                        }
+                        state.read().await.release_node_session(&xsession_id).await;
+                    });
+                }
+            }
 
-                    info!(
-                        "Session Timeout {:?} :: port {}",
-                        xsession_id,
-                        session.port()
-                    );
-                    if let Some(session_group) = session.service_group() {
-                        if let Some(group) = groups.get_mut(session_group) {
-                            group
-                                .delete_session(session.port(), &xsession_id, &mut port_manager)
-                                .await;
+            if !local_sessions.is_empty() {
+                let mut detached = Vec::new();
+                {
+                    let mut groups = rwlock_groups.write().await;
+                    for (xsession_id, port, session_group) in local_sessions {
+                        if let Some(group) = groups.get_mut(&session_group) {
+                            detached.extend(group.delete_session(port, &xsession_id));
                         }
                     }
                 }
+                terminate_services(detached, &rwlock_port_manager).await;
+            }
+        }
+
+        // Forget idle rate limit buckets and terminate any services that have
+        // been idle for too long.
+        {
+            let (rwlock_groups, rwlock_port_manager) = {
+                let s = state.read().await;
+                s.evict_idle_rate_limits();
+                (s.service_groups(), s.port_manager())
+            };
+            let detached: Vec<_> = rwlock_groups
+                .write()
+                .await
+                .values_mut()
+                .flat_map(|group| group.gc_idle_services())
+                .collect();
+            terminate_services(detached, &rwlock_port_manager).await;
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Request body for POST /admin/session.
+#[derive(Debug, Deserialize)]
+struct AdminSessionCreate {
+    xenon_session_id: XenonSessionId,
+    driver_session_id: String,
+    authority: String,
+    #[serde(default)]
+    service_group: Option<String>,
+}
+
+/// Handle requests to /admin endpoints.
+async fn handle_admin(
+    req: Request<Body>,
+    state: Arc<RwLock<XenonState>>,
+) -> XenonResult<Response<Body>> {
+    let path_elements: Vec<String> = req
+        .uri()
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .map(|x| x.to_string())
+        .collect();
+
+    if path_elements.len() < 2 {
+        return Err(XenonError::RespondWith(XenonResponse::EndpointNotFound(
+            path_elements.join("/"),
+        )));
+    }
+
+    if req.method() == hyper::Method::OPTIONS {
+        return match path_elements[1].as_str() {
+            "session" => options_response("POST, OPTIONS"),
+            _ => options_response("GET, OPTIONS"),
+        };
+    }
+
+    match path_elements[1].as_str() {
+        "port-map" if path_elements.len() == 2 => match *req.method() {
+            hyper::Method::GET => handle_admin_port_map(state).await,
+            _ => Err(XenonError::RespondWith(XenonResponse::MethodNotFound(
+                path_elements.join("/"),
+            ))),
+        },
+        "nodes" if path_elements.len() == 3 && path_elements[2] == "stats" => match *req.method() {
+            hyper::Method::GET => handle_admin_node_stats(state).await,
+            _ => Err(XenonError::RespondWith(XenonResponse::MethodNotFound(
+                path_elements.join("/"),
+            ))),
+        },
+        "session" => match *req.method() {
+            hyper::Method::POST => handle_admin_create_session(req, state).await,
+            _ => Err(XenonError::RespondWith(XenonResponse::MethodNotFound(
+                path_elements.join("/"),
+            ))),
+        },
+        _p => Err(XenonError::RespondWith(XenonResponse::EndpointNotFound(
+            path_elements.join("/"),
+        ))),
+    }
+}
+
+/// Aggregated statistics across all remote nodes.
+#[derive(Debug, Serialize)]
+struct NodesStatsSummary {
+    total_nodes: usize,
+    reachable_nodes: usize,
+    total_capacity: u32,
+    total_sessions: usize,
+    nodes: Vec<NodeHealthStatus>,
+}
+
+/// GET /admin/nodes/stats
+///
+/// Return statistics for all remote nodes. This uses only local state and
+/// does not make any requests to the nodes.
+async fn handle_admin_node_stats(state: Arc<RwLock<XenonState>>) -> XenonResult<Response<Body>> {
+    let node_stats = state.read().await.node_health_summary().await;
+
+    let summary = NodesStatsSummary {
+        total_nodes: node_stats.len(),
+        reachable_nodes: node_stats.iter().filter(|x| x.reachable).count(),
+        total_capacity: node_stats.iter().map(|x| x.capacity).sum(),
+        total_sessions: node_stats.iter().map(|x| x.sessions).sum(),
+        nodes: node_stats,
+    };
+    json_response(StatusCode::OK, &serde_json::json!({ "value": summary }))
+}
+
+/// A single port in the port map.
+#[derive(Debug, Serialize)]
+struct PortMapEntry {
+    port: ServicePort,
+    status: &'static str,
+    /// The service group using this port, if any.
+    service_group: Option<String>,
+    /// The number of sessions on the WebDriver using this port.
+    sessions: usize,
+}
+
+/// The allocation status of all ports managed by Xenon.
+#[derive(Debug, Serialize)]
+struct PortMap {
+    total: usize,
+    available: usize,
+    taken: usize,
+    ports: Vec<PortMapEntry>,
+}
+
+/// GET /admin/port-map
+///
+/// Return every port managed by Xenon, along with the service group and
+/// number of sessions for the WebDriver using it (if any).
+async fn handle_admin_port_map(state: Arc<RwLock<XenonState>>) -> XenonResult<Response<Body>> {
+    let (rwlock_port_manager, rwlock_groups) = {
+        let s = state.read().await;
+        (s.port_manager(), s.service_groups())
+    };
+    let (port_manager, groups) = tokio::join!(rwlock_port_manager.read(), rwlock_groups.read());
+
+    // Extra ports belong to the same service as the main port.
+    let mut services = HashMap::new();
+    for group in groups.values() {
+        for service in group.services() {
+            for port in service.ports() {
+                services.insert(port, (group.name(), service.num_active_sessions()));
+            }
+        }
+    }
+
+    let available: Vec<ServicePort> = port_manager.available_ports().collect();
+    let ports: Vec<PortMapEntry> = port_manager
+        .all_ports()
+        .map(|port| {
+            let (service_group, sessions) = match services.get(&port) {
+                Some((name, sessions)) => (Some(name.to_string()), *sessions),
+                None => (None, 0),
+            };
+            PortMapEntry {
+                port,
+                status: if available.binary_search(&port).is_ok() {
+                    "available"
+                } else {
+                    "taken"
+                },
+                service_group,
+                sessions,
+            }
+        })
+        .collect();
+
+    let port_map = PortMap {
+        total: ports.len(),
+        available: available.len(),
+        taken: ports.len() - available.len(),
+        ports,
+    };
+    json_response(StatusCode::OK, &serde_json::json!({ "value": port_map }))
+}
+
+/// POST /admin/session
+///
+/// Inject an existing WebDriver session (that was not created via Xenon) into Xenon.
+/// This is intended for disaster recovery and testing.
+async fn handle_admin_create_session(
+    req: Request<Body>,
+    state: Arc<RwLock<XenonState>>,
+) -> XenonResult<Response<Body>> {
+    let body_bytes = hyper::body::to_bytes(req)
+        .await
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InvalidRequest(e.to_string())))?;
+    let session_info: AdminSessionCreate = serde_json::from_slice(&body_bytes)
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InvalidRequest(e.to_string())))?;
+    let authority: Authority = session_info.authority.parse().map_err(|e| {
+        XenonError::RespondWith(XenonResponse::InvalidRequest(format!(
+            "Invalid authority '{}': {}",
+            session_info.authority, e
+        )))
+    })?;
+
+    let xsession_id = session_info.xenon_session_id;
+    let upstream = Upstream::new(Scheme::HTTP, authority);
+    let client = state.read().await.client(&upstream);
+    let session = Session::adopt(
+        upstream,
+        client,
+        session_info.service_group.clone(),
+        session_info.driver_session_id,
+        xsession_id.clone(),
+    )?;
+    let metadata = serde_json::to_value(SessionInfo::from((&session, &xsession_id)))
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InternalServerError(e.to_string())))?;
+
+    let mut s = state_write!(state, "admin session create");
+    if s.has_session(&xsession_id) {
+        return Err(XenonError::RespondWith(XenonResponse::InvalidRequest(
+            format!("Session '{}' already exists", xsession_id),
+        )));
+    }
+
+    if session_info.service_group.is_none() {
+        // Track the session on its node, if any, so that the node is not removed from under it.
+        let rwlock_nodes = s.remote_nodes();
+        let mut nodes = rwlock_nodes.write().await;
+        if let Some(node) = nodes
+            .values_mut()
+            .find(|node| node.authority == *session.authority())
+        {
+            node.adopt_session(xsession_id.clone());
+        }
+    }
+
+    if let Some(group_name) = &session_info.service_group {
+        let rwlock_groups = s.service_groups();
+        let mut groups = rwlock_groups.write().await;
+        match groups.get_mut(group_name) {
+            Some(group) => {
+                if !group.adopt_session(session.port(), xsession_id.clone()) {
+                    warn!(
+                        "No service in group '{}' on port {}. Session {:?} will not be tracked by the service",
+                        group_name,
+                        session.port(),
+                        xsession_id
+                    );
+                }
+            }
+            None => {
+                return Err(XenonError::RespondWith(XenonResponse::InvalidRequest(
+                    format!("Service group '{}' not found", group_name),
+                )));
             }
         }
-        sleep(Duration::new(60, 0)).await;
     }
+
+    info!(
+        "Session Inject {:?} :: port {}",
+        xsession_id,
+        session.port()
+    );
+    s.add_session(xsession_id, session);
+
+    json_response(StatusCode::OK, &serde_json::json!({ "value": metadata }))
 }
 
 /// Handle requests to /node endpoints.
@@ -525,6 +1752,14 @@ async fn handle_node(
         )));
     }
 
+    if req.method() == hyper::Method::OPTIONS {
+        return match path_elements[1].as_str() {
+            "config" => options_response("GET, OPTIONS"),
+            "register" => options_response("POST, OPTIONS"),
+            _ => options_response("DELETE, OPTIONS"),
+        };
+    }
+
     match path_elements[1].as_str() {
         "config" => match *req.method() {
             hyper::Method::GET => {
@@ -536,7 +1771,8 @@ async fn handle_node(
                 for group in rwlock_groups.read().await.values() {
                     let remote_group = RemoteServiceGroup {
                         browser: group.browser.clone(),
-                        remaining_sessions: group.browser.max_sessions(),
+                        initial_capacity: group.browser.max_sessions(),
+                        sessions_used: 0,
                     };
                     groups_out.push(remote_group);
                 }
@@ -549,13 +1785,29 @@ async fn handle_node(
                     }
                 }
 
-                let body = Body::from(
-                    serde_json::to_string(&groups_out)
-                        .unwrap_or_else(|e| format!("Xenon failed to serialize node id: {}", e)),
-                );
+                // Serialize as JSON unless another format was requested.
+                let (content_type, body) = match query_param(&req, "format") {
+                    Some("yaml") => (
+                        "application/yaml",
+                        serde_yaml::to_string(&groups_out).map_err(|e| e.to_string()),
+                    ),
+                    None | Some("json") => (
+                        "application/json",
+                        serde_json::to_string(&groups_out).map_err(|e| e.to_string()),
+                    ),
+                    Some(format) => {
+                        return Err(XenonError::RespondWith(XenonResponse::InvalidRequest(
+                            format!("Unsupported format '{}'", format),
+                        )));
+                    }
+                };
+                let body = Body::from(body.unwrap_or_else(|e| {
+                    format!("Xenon failed to serialize node configuration: {}", e)
+                }));
 
                 Ok(Response::builder()
                     .status(StatusCode::OK)
+                    .header("Content-Type", content_type)
                     .body(body)
                     .unwrap_or_else(|_| {
                         Response::builder()
@@ -568,22 +1820,201 @@ async fn handle_node(
                 path_elements.join("/"),
             ))),
         },
+        "register" if path_elements.len() == 2 => match *req.method() {
+            // POST /node/register
+            hyper::Method::POST => handle_node_register(req, state).await,
+            _ => Err(XenonError::RespondWith(XenonResponse::MethodNotFound(
+                path_elements.join("/"),
+            ))),
+        },
+        node_id if path_elements.len() == 2 && req.method() == hyper::Method::DELETE => {
+            // DELETE /node/{id}
+            let force = query_param(&req, "force")
+                .map(|v| v.is_empty() || v == "true")
+                .unwrap_or(false);
+            let comms_id = req
+                .headers()
+                .get(COMMS_ID_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            delete_node(&NodeId::from(node_id), force, comms_id, state).await
+        }
         _p => Err(XenonError::RespondWith(XenonResponse::EndpointNotFound(
             path_elements.join("/"),
         ))),
     }
 }
 
-/// Fetch config for each node.
-async fn process_node_init(state: Arc<RwLock<XenonState>>) {
-    debug!("Downstream node configuration starting");
-    let mut nodes_remaining: IndexMap<NodeId, RemoteNode> = {
+/// Get the value of the specified query parameter, if present.
+/// A parameter without a value (e.g. `?force`) returns an empty string.
+fn query_param<'a>(req: &'a Request<Body>, key: &str) -> Option<&'a str> {
+    req.uri().query()?.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        match parts.next() {
+            Some(k) if k == key => Some(parts.next().unwrap_or("")),
+            _ => None,
+        }
+    })
+}
+
+/// POST /node/register
+///
+/// Add a remote node at runtime. The body is the same as a node in the config file.
+/// The node config is then fetched in the background, and sessions are only routed
+/// to the node once that succeeds.
+async fn handle_node_register(
+    req: Request<Body>,
+    state: Arc<RwLock<XenonState>>,
+) -> XenonResult<Response<Body>> {
+    let body_bytes = hyper::body::to_bytes(req)
+        .await
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InvalidRequest(e.to_string())))?;
+    let node_info: RemoteNodeCreate = serde_json::from_slice(&body_bytes)
+        .map_err(|e| XenonError::RespondWith(XenonResponse::InvalidRequest(e.to_string())))?;
+    let node = RemoteNode::new(node_info)?;
+
+    {
+        let s = state.read().await;
+        let rwlock_nodes = s.remote_nodes();
+        let mut nodes = rwlock_nodes.write().await;
+        if nodes.values().any(|x| x.url == node.url) {
+            return Err(XenonError::RespondWith(XenonResponse::NodeAlreadyExists(
+                format!("A node with url '{}' is already registered", node.url),
+            )));
+        }
+        nodes.insert(node.id(), node.clone());
+    }
+    info!("Node Register '{}' :: {}", node.display_name(), node.url);
+
+    let body = serde_json::json!({
+        "value": {
+            "id": node.id(),
+            "name": node.name(),
+            "url": node.url,
+            "comms_id": node.comms_id()
+        }
+    });
+    let mut nodes_remaining = IndexMap::new();
+    nodes_remaining.insert(node.id(), node);
+    tokio::spawn(async move {
+        fetch_node_configs(state, nodes_remaining).await;
+    });
+    json_response(StatusCode::CREATED, &body)
+}
+
+/// Remove a remote node so that no new sessions will be routed to it.
+/// Unless `force` is set, the node will only be removed if it has no active sessions.
+///
+/// The request must present the node's comms_id, which is only known to the node
+/// and to whoever registered it.
+async fn delete_node(
+    node_id: &NodeId,
+    force: bool,
+    comms_id: &str,
+    state: Arc<RwLock<XenonState>>,
+) -> XenonResult<Response<Body>> {
+    let s = state.read().await;
+    let rwlock_nodes = s.remote_nodes();
+    // The node is checked and removed under the same write-lock, so that no
+    // session can be routed to it in between.
+    let mut nodes = rwlock_nodes.write().await;
+    let node = nodes
+        .get(node_id)
+        .ok_or_else(|| XenonError::RespondWith(XenonResponse::NodeNotFound(node_id.to_string())))?;
+
+    if !node.verify_comms_id(comms_id) {
+        return Err(XenonError::RespondWith(XenonResponse::Unauthorized));
+    }
+
+    let active_sessions = node.num_sessions();
+    if !force && active_sessions > 0 {
+        return Err(XenonError::RespondWith(XenonResponse::NodeHasSessions(
+            format!(
+                "Node '{}' has {} active session(s)",
+                node.display_name(),
+                active_sessions
+            ),
+        )));
+    }
+
+    if let Some(node) = nodes.shift_remove(node_id) {
+        info!("Node Delete '{}'", node.display_name());
+    }
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Fetch the service groups from the specified node.
+async fn fetch_node_config(
+    client: &Client<HttpConnector, Body>,
+    node: &RemoteNode,
+) -> Result<Vec<RemoteServiceGroup>, String> {
+    let req_out = build_proxy_request(
+        hyper::Method::GET,
+        &node.upstream(),
+        "/node/config",
+        Body::empty(),
+    )
+    .map_err(|e| {
+        format!(
+            "Invalid URI '{}' for node '{}': {}",
+            node.url,
+            node.display_name(),
+            e
+        )
+    })?;
+    let res = client.request(req_out).await.map_err(|e| {
+        format!(
+            "Unable to fetch configuration for node '{}': {}",
+            node.display_name(),
+            e
+        )
+    })?;
+    let bytes = hyper::body::to_bytes(res).await.map_err(|e| {
+        format!(
+            "Failed to receive configuration for node '{}': {}",
+            node.display_name(),
+            e
+        )
+    })?;
+    serde_json::from_slice(&bytes).map_err(|e| {
+        format!(
+            "Failed to parse configuration from node '{}': {}",
+            node.display_name(),
+            e
+        )
+    })
+}
+
+/// Fetch config for each node in the background, while periodically checking the
+/// nodes that have been configured. A node that never comes up does not hold up
+/// the health checks of the others.
+async fn process_node_init(state: Arc<RwLock<XenonState>>, health_check_interval: Duration) {
+    let nodes: IndexMap<NodeId, RemoteNode> = {
         let s = state.read().await;
         let rwlock_nodes = s.remote_nodes();
         let nodes = rwlock_nodes.read().await.clone();
         nodes
     };
+    if !nodes.is_empty() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            debug!("Downstream node configuration starting");
+            fetch_node_configs(state, nodes).await;
+            debug!("Downstream node configuration complete");
+        });
+    }
+    process_node_health(state, health_check_interval).await;
+}
 
+/// Fetch config for each of the specified nodes, retrying until all have succeeded
+/// (or have been removed).
+async fn fetch_node_configs(
+    state: Arc<RwLock<XenonState>>,
+    mut nodes_remaining: IndexMap<NodeId, RemoteNode>,
+) {
     let client = Client::new();
 
     while !nodes_remaining.is_empty() {
@@ -593,84 +2024,959 @@ async fn process_node_init(state: Arc<RwLock<XenonState>>) {
                 "Fetching config from downstream node '{}'...",
                 node.display_name()
             );
-            let uri_out = match hyper::Uri::builder()
-                .scheme(node.scheme.clone())
-                .authority(node.authority.clone())
-                .path_and_query("/node/config")
-                .build()
-            {
-                Ok(uri) => uri,
-                Err(e) => {
-                    error!(
-                        "Invalid URI '{}' for node '{}': {}",
-                        node.url,
-                        node.display_name(),
-                        e
+            let result = fetch_node_config(&client, node).await;
+
+            // Update the node. Read lock on state. Write lock on nodes.
+            let s = state.read().await;
+            let rwlock_nodes = s.remote_nodes();
+            let mut nodes = rwlock_nodes.write().await;
+            if !nodes.contains_key(&node.id()) {
+                // The node was deleted in the meantime.
+                nodes_done.push(node.id());
+                continue;
+            }
+            match result {
+                Ok(remote_groups) => {
+                    if let Some(node) = nodes.get_mut(&node.id()) {
+                        node.set_service_groups(remote_groups.clone());
+                        node.record_contact_success();
+                    }
+                    info!(
+                        "Configuration for downstream node '{}' fetched successfully",
+                        node.display_name()
                     );
-                    continue;
+                    info!("{:#?}", remote_groups);
+                    nodes_done.push(node.id());
                 }
-            };
+                Err(e) => {
+                    warn!("{}", e);
+                    if let Some(node) = nodes.get_mut(&node.id()) {
+                        node.record_contact_failure();
+                    }
+                }
+            }
+        }
 
-            match client.get(uri_out).await {
-                Ok(res) => match hyper::body::to_bytes(res).await {
-                    Ok(bytes) => {
-                        let remote_groups: Vec<RemoteServiceGroup> =
-                            match serde_json::from_slice(&bytes) {
-                                Ok(x) => x,
-                                Err(e) => {
-                                    error!(
-                                        "Failed to parse configuration from node '{}': {}",
-                                        node.display_name(),
-                                        e
-                                    );
-                                    continue;
-                                }
-                            };
-
-                        // Update these. Read lock on state. Write lock on nodes.
-                        let s = state.read().await;
-                        let rwlock_nodes = s.remote_nodes();
-                        let mut nodes = rwlock_nodes.write().await;
-                        if let Some(node) = nodes.get_mut(&node.id()) {
-                            node.service_groups = remote_groups.clone();
-                        }
+        // Remove the ones we found.
+        for node_id in nodes_done {
+            nodes_remaining.remove(&node_id);
+        }
+
+        if !nodes_remaining.is_empty() {
+            // Wait 60 seconds before trying again.
+            sleep(Duration::new(60, 0)).await;
+        }
+    }
+}
+
+/// The number of consecutive failed health checks before a node is disabled.
+const NODE_HEALTH_CHECK_MAX_FAILURES: u32 = 3;
+
+/// Periodically fetch the config from each remote node, to check that it is still
+/// reachable and to pick up any changes to its service groups.
+///
+/// Nodes that fail several checks in a row are disabled so that no new sessions
+/// are routed to them. They are re-enabled once they respond again.
+async fn process_node_health(state: Arc<RwLock<XenonState>>, interval: Duration) {
+    let client = Client::new();
+
+    loop {
+        sleep(interval).await;
+
+        let nodes: Vec<RemoteNode> = {
+            let s = state.read().await;
+            let rwlock_nodes = s.remote_nodes();
+            let nodes = rwlock_nodes.read().await;
+            // Nodes that have not been configured yet are handled by process_node_init().
+            nodes
+                .values()
+                .filter(|node| node.last_successful_contact.is_some())
+                .cloned()
+                .collect()
+        };
+
+        for node in nodes {
+            let result = fetch_node_config(&client, &node).await;
+
+            // Update the node. Read lock on state. Write lock on nodes.
+            let s = state.read().await;
+            let rwlock_nodes = s.remote_nodes();
+            let mut nodes = rwlock_nodes.write().await;
+            let node = match nodes.get_mut(&node.id()) {
+                Some(x) => x,
+                // The node was deleted in the meantime.
+                None => continue,
+            };
+            match result {
+                Ok(remote_groups) => {
+                    if node.is_disabled() {
+                        info!(
+                            "Downstream node '{}' is responding again and has been re-enabled",
+                            node.display_name()
+                        );
+                    } else if node.consecutive_failures() > 0 {
                         info!(
-                            "Configuration for downstream node '{}' fetched successfully",
+                            "Downstream node '{}' is reachable again",
                             node.display_name()
                         );
-                        info!("{:#?}", remote_groups);
-                        nodes_done.push(node.id());
                     }
-                    Err(e) => {
-                        error!(
-                            "Failed to receive configuration for node '{}': {}",
+                    node.set_service_groups(remote_groups);
+                    node.record_contact_success();
+                }
+                Err(e) => {
+                    if node.consecutive_failures() == 0 {
+                        warn!(
+                            "Downstream node '{}' is unreachable: {}",
                             node.display_name(),
                             e
                         );
-                        continue;
+                    } else {
+                        debug!("{}", e);
+                    }
+                    node.record_contact_failure();
+                    if !node.is_disabled()
+                        && node.consecutive_failures() >= NODE_HEALTH_CHECK_MAX_FAILURES
+                    {
+                        node.disable();
+                        warn!(
+                            "Downstream node '{}' failed {} health checks and has been disabled",
+                            node.display_name(),
+                            node.consecutive_failures()
+                        );
                     }
-                },
-                Err(e) => {
-                    warn!(
-                        "Unable to fetch configuration for node '{}': {}",
-                        node.display_name(),
-                        e
-                    );
-                    continue;
                 }
             }
         }
+    }
+}
 
-        // Remove the ones we found.
-        for node_id in nodes_done {
-            nodes_remaining.remove(&node_id);
+#[cfg(test)]
+mod test {
+    use crate::browser::Capabilities;
+    use crate::config::XenonConfig;
+    use crate::error::XenonError;
+    use crate::portmanager::PortManager;
+    use crate::response::XenonResponse;
+    use crate::server::{
+        add_cors_headers, build_status_response, error_response, handle, is_valid_path,
+        options_response, port_allocation_table, reserve_available_session, session_id_from_path,
+        session_path_elements, socket_addr, strip_base_path, websocket_base_url, Opt,
+    };
+    use crate::session::{Session, Upstream, XenonSessionId};
+    use crate::state::XenonState;
+    use clap::{CommandFactory, Parser};
+    use futures_util::{SinkExt, StreamExt};
+    use hyper::http::uri::Scheme;
+    use hyper::server::Server;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Client, Request};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+    use tokio::time::Duration;
+    use tokio_tungstenite::tungstenite::handshake::server::{
+        Request as WsRequest, Response as WsResponse,
+    };
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[test]
+    fn test_is_valid_path() {
+        assert!(is_valid_path("/session"));
+        assert!(is_valid_path("/session/abc/url"));
+        assert!(is_valid_path("/wd/hub/session/"));
+        assert!(!is_valid_path("/session/../node/config"));
+        assert!(!is_valid_path("/session/./abc"));
+        assert!(!is_valid_path("/session/ab\0c"));
+        assert!(!is_valid_path("/session/%2e%2e/node/config"));
+        assert!(!is_valid_path("/session/%2E./node/config"));
+        assert!(!is_valid_path("/session/abc%2f..%2fnode"));
+        assert!(!is_valid_path("/session/ab%00c"));
+        assert!(is_valid_path("/session/abc%2"));
+        assert!(is_valid_path("/session/a%20b"));
+    }
+
+    #[test]
+    fn test_websocket_base_url() {
+        let req = Request::post("/session")
+            .header("Host", "xenon:4444")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            websocket_base_url(&req, false, None).as_deref(),
+            Some("ws://xenon:4444")
+        );
+        assert_eq!(
+            websocket_base_url(&req, true, Some("/selenium/")).as_deref(),
+            Some("wss://xenon:4444/selenium")
+        );
+        let req = Request::post("/session").body(Body::empty()).unwrap();
+        assert_eq!(websocket_base_url(&req, false, None), None);
+    }
+
+    #[test]
+    fn test_strip_base_path() {
+        let mut req = Request::get("/selenium/wd/hub/session?a=1")
+            .body(Body::empty())
+            .unwrap();
+        assert!(strip_base_path(&mut req, "selenium").is_ok());
+        assert_eq!(req.uri(), "/wd/hub/session?a=1");
+
+        let mut req = Request::get("/selenium").body(Body::empty()).unwrap();
+        assert!(strip_base_path(&mut req, "selenium").is_ok());
+        assert_eq!(req.uri(), "/");
+
+        let mut req = Request::get("/seleniumx/session")
+            .body(Body::empty())
+            .unwrap();
+        assert!(strip_base_path(&mut req, "selenium").is_err());
+    }
+
+    #[test]
+    fn test_cors_preflight() {
+        let mut response = options_response("POST, OPTIONS").unwrap();
+        add_cors_headers(&mut response, "*", true);
+        let headers = response.headers();
+        assert_eq!(headers["allow"], "POST, OPTIONS");
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        assert_eq!(headers["access-control-allow-methods"], "POST, OPTIONS");
+    }
+
+    #[test]
+    fn test_opt() {
+        Opt::command().debug_assert();
+
+        let opt = Opt::try_parse_from(["xenon", "-p", "5555", "--cfg", "/tmp/xenon.yml"]).unwrap();
+        assert_eq!(opt.port, Some(5555));
+        assert_eq!(opt.cfg, Some("/tmp/xenon.yml".into()));
+        assert_eq!(opt.graceful_shutdown_timeout_secs, 30);
+        assert_eq!(opt.bind_addr, "127.0.0.1");
+        assert_eq!(opt.max_sessions, None);
+        assert!(!opt.no_config);
+
+        let opt = Opt::try_parse_from(["xenon", "--bind-addr", "0.0.0.0"]).unwrap();
+        assert_eq!(opt.bind_addr, "0.0.0.0");
+
+        let opt = Opt::try_parse_from(["xenon", "--max-sessions", "6"]).unwrap();
+        assert_eq!(opt.max_sessions, Some(6));
+
+        let opt = Opt::try_parse_from(["xenon", "--no-config"]).unwrap();
+        assert!(opt.no_config);
+        assert!(Opt::try_parse_from(["xenon", "--no-config", "--cfg", "/tmp/xenon.yml"]).is_err());
+    }
+
+    #[test]
+    fn test_port_allocation_table() {
+        let config: XenonConfig =
+            serde_yaml::from_str("ports: [\"5000-5003\", \"5005\", \"5007-5008\"]").unwrap();
+        let mut port_manager = PortManager::new(&config);
+        port_manager.reserve_range(2).unwrap();
+        assert_eq!(
+            port_allocation_table(&port_manager),
+            "5000-5001    taken\n5002-5003    available\n5005         available\n5007-5008    available"
+        );
+
+        let port_manager = PortManager::new(&XenonConfig::default());
+        assert_eq!(port_allocation_table(&port_manager), "(none)");
+    }
+
+    #[test]
+    fn test_socket_addr() {
+        assert_eq!(
+            socket_addr("127.0.0.1", 4444).unwrap(),
+            "127.0.0.1:4444".parse().unwrap()
+        );
+        assert_eq!(
+            socket_addr("0.0.0.0", 4444).unwrap(),
+            "0.0.0.0:4444".parse().unwrap()
+        );
+        assert_eq!(
+            socket_addr("::", 4444).unwrap(),
+            "[::]:4444".parse().unwrap()
+        );
+        for addr in ["localhost", "127.0.0.1:4444", "256.0.0.1", ""] {
+            assert!(matches!(
+                socket_addr(addr, 4444),
+                Err(XenonError::InvalidBindAddress(_))
+            ));
         }
+    }
 
-        if !nodes_remaining.is_empty() {
-            // Wait 60 seconds before trying again.
-            sleep(Duration::new(60, 0)).await;
+    #[test]
+    fn test_session_id_from_path() {
+        assert_eq!(session_id_from_path("/session"), None);
+        assert_eq!(session_id_from_path("/status"), None);
+        assert_eq!(
+            session_id_from_path("/session/abc"),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            session_id_from_path("/wd/hub/session/abc/element/1/click"),
+            Some("abc".to_string())
+        );
+    }
+
+    #[test]
+    fn test_session_path_elements() {
+        assert_eq!(
+            session_path_elements("/session/abc/screenshot", false),
+            vec!["session", "abc", "screenshot"]
+        );
+        assert_eq!(
+            session_path_elements("/wd/hub/session/abc/element/e1/screenshot", true),
+            vec!["session", "abc", "element", "e1", "screenshot"]
+        );
+        assert_eq!(
+            session_path_elements("/wd/hub/session/abc/source/", true),
+            vec!["session", "abc", "source"]
+        );
+        assert_eq!(
+            session_path_elements("/wd/hub/session/", true),
+            vec!["session"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_status_response() {
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+browsers:
+  - name: chrome
+    max_sessions: 3
+  - name: firefox
+    max_sessions: 0
+"#,
+        )
+        .unwrap();
+        let state = Arc::new(RwLock::new(XenonState::new(config).await.unwrap()));
+        let response = build_status_response(state).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let value = &json["value"];
+        assert_eq!(value["ready"], true);
+        assert_eq!(value["total_sessions"], 0);
+        assert_eq!(value["total_capacity"], 3);
+        assert_eq!(value["service_groups"][0]["name"], "chrome-any");
+        assert_eq!(value["service_groups"][0]["active_services"], 0);
+        assert_eq!(value["service_groups"][0]["peak_concurrent_sessions"], 0);
+        assert_eq!(value["service_groups"][1]["max_sessions"], 0);
+        assert_eq!(value["nodes"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_internal_error_response() {
+        for (error, expose) in [
+            (XenonError::ServerError("/secret/path".to_string()), false),
+            (
+                XenonError::RespondWith(XenonResponse::InternalServerError(
+                    "/secret/path".to_string(),
+                )),
+                false,
+            ),
+            (XenonError::ServerError("/secret/path".to_string()), true),
+        ] {
+            let response = error_response(error, expose);
+            assert_eq!(response.status(), 500);
+            let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            let message = json["value"]["message"].as_str().unwrap();
+            assert_eq!(message.contains("/secret/path"), expose);
+            assert_eq!(
+                message.starts_with("An internal error occurred (reference: "),
+                !expose
+            );
+        }
+
+        // Other errors are unaffected.
+        let response = error_response(
+            XenonError::RespondWith(XenonResponse::EndpointNotFound("abc".to_string())),
+            false,
+        );
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["value"]["message"], "abc");
+
+        let response = error_response(XenonError::NodeTimeout("slow".to_string()), false);
+        assert_eq!(response.status(), 503);
+    }
+
+    #[tokio::test]
+    async fn test_cache_control() {
+        let config: XenonConfig = serde_yaml::from_str("base_path: /selenium").unwrap();
+        let state = Arc::new(RwLock::new(XenonState::new(config).await.unwrap()));
+        for (path, status) in [
+            ("/selenium/status", 200),
+            ("/selenium/unknown", 400),
+            ("/status", 400),
+        ] {
+            let response = handle(
+                Request::get(path).body(Body::empty()).unwrap(),
+                "127.0.0.1:50000".parse().unwrap(),
+                state.clone(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), status);
+            assert_eq!(
+                response.headers()["cache-control"],
+                "no-store, no-cache, must-revalidate"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_token() {
+        let mut state = XenonState::new(XenonConfig::default()).await.unwrap();
+        state.set_auth_token(Some("secret".to_string()));
+        let state = Arc::new(RwLock::new(state));
+        let remote_addr = "127.0.0.1:50000".parse().unwrap();
+        let request = |path: &str, auth: Option<&str>| {
+            let mut builder = Request::get(path);
+            if let Some(auth) = auth {
+                builder = builder.header("Authorization", auth);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        // Missing header.
+        let response = handle(
+            request("/admin/nodes/stats", None),
+            remote_addr,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 401);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["value"]["error"], "unauthorized");
+
+        // Wrong token.
+        for auth in ["Bearer wrong", "Bearer secre", "secret", "Basic secret"] {
+            let response = handle(
+                request("/admin/nodes/stats", Some(auth)),
+                remote_addr,
+                state.clone(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), 401);
+        }
+
+        // Correct token.
+        for auth in ["Bearer secret", "bearer secret", "BEARER secret"] {
+            let response = handle(
+                request("/admin/nodes/stats", Some(auth)),
+                remote_addr,
+                state.clone(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), 200);
+        }
+
+        // Status is always allowed.
+        let response = handle(request("/status", None), remote_addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_no_new_sessions_while_draining() {
+        let state = XenonState::new(XenonConfig::default()).await.unwrap();
+        state.start_draining().await;
+        let state = Arc::new(RwLock::new(state));
+        let req = Request::post("/session")
+            .body(Body::from(r#"{"capabilities": {}}"#))
+            .unwrap();
+        let response = handle(req, "127.0.0.1:50000".parse().unwrap(), state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+
+        let response = handle(
+            Request::get("/status").body(Body::empty()).unwrap(),
+            "127.0.0.1:50000".parse().unwrap(),
+            state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_node_register() {
+        let state = Arc::new(RwLock::new(
+            XenonState::new(XenonConfig::default()).await.unwrap(),
+        ));
+        let remote_addr = "127.0.0.1:50000".parse().unwrap();
+        let register = |body: &str| {
+            Request::post("/node/register")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        // Nothing is listening on port 1, so the config fetch never succeeds.
+        let body = r#"{"name": "node1", "url": "http://127.0.0.1:1"}"#;
+        let response = handle(register(body), remote_addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 201);
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["value"]["name"], "node1");
+        let node_id = json["value"]["id"].as_str().unwrap().to_string();
+        let comms_id = json["value"]["comms_id"].as_str().unwrap().to_string();
+        {
+            let s = state.read().await;
+            let rwlock_nodes = s.remote_nodes();
+            let nodes = rwlock_nodes.read().await;
+            assert_eq!(nodes.len(), 1);
+            assert!(!nodes.values().next().unwrap().is_reachable());
+        }
+
+        // Duplicate url.
+        let response = handle(register(body), remote_addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 409);
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["value"]["error"], "node already exists");
+
+        // Invalid body.
+        let response = handle(register(r#"{"name": "x"}"#), remote_addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        // Removing a node requires its comms_id.
+        let response = handle(
+            Request::delete(format!("/node/{}", node_id))
+                .body(Body::empty())
+                .unwrap(),
+            remote_addr,
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 401);
+        let deregister = |comms_id: &str| {
+            Request::delete(format!("/node/{}", node_id))
+                .header("X-Xenon-Comms-Id", comms_id)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = handle(deregister("1234"), remote_addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+
+        // A node with an active session is not removed unless forced.
+        let xsession_id = XenonSessionId::from("adopted");
+        let adopt = Request::post("/admin/session")
+            .body(Body::from(
+                serde_json::json!({
+                    "xenon_session_id": xsession_id,
+                    "driver_session_id": "driver",
+                    "authority": "127.0.0.1:1",
+                })
+                .to_string(),
+            ))
+            .unwrap();
+        let response = handle(adopt, remote_addr, state.clone()).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let response = handle(deregister(&comms_id), remote_addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 409);
+        state.read().await.release_node_session(&xsession_id).await;
+
+        let response = handle(deregister(&comms_id), remote_addr, state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 204);
+        let s = state.read().await;
+        assert!(s.remote_nodes().read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions() {
+        let mut state = XenonState::new(XenonConfig::default()).await.unwrap();
+        for (id, group, port) in [("local", Some("chrome-any"), 5000), ("remote", None, 8888)] {
+            let xsession_id = XenonSessionId::from(id);
+            let session = Session::adopt(
+                Upstream::new(Scheme::HTTP, format!("localhost:{}", port).parse().unwrap()),
+                Client::new(),
+                group.map(|x| x.to_string()),
+                format!("driver-{}", id),
+                xsession_id.clone(),
+            )
+            .unwrap();
+            state.add_session(xsession_id, session);
+        }
+        let state = Arc::new(RwLock::new(state));
+
+        let response = handle(
+            Request::get("/session").body(Body::empty()).unwrap(),
+            "127.0.0.1:50000".parse().unwrap(),
+            state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let mut sessions = json["value"].as_array().unwrap().clone();
+        sessions.sort_by_key(|x| x["xenon_session_id"].as_str().unwrap().to_string());
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0]["xenon_session_id"], "local");
+        assert_eq!(sessions[0]["session_id"], "driver-local");
+        assert_eq!(sessions[0]["service_group"], "chrome-any");
+        assert_eq!(sessions[0]["port"], 5000);
+        assert_eq!(sessions[0]["seconds_since_last_request"], 0);
+        assert_eq!(sessions[1]["xenon_session_id"], "remote");
+        assert_eq!(sessions[1]["service_group"], "remote");
+        assert_eq!(sessions[1]["session_id"], "driver-remote");
+        assert_eq!(sessions[1]["port"], 8888);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refresh_timeout() {
+        let mut state = XenonState::new(XenonConfig::default()).await.unwrap();
+        let xsession_id = XenonSessionId::from("local");
+        let session = Session::adopt(
+            Upstream::new(Scheme::HTTP, "localhost:5000".parse().unwrap()),
+            Client::new(),
+            Some("chrome-any".to_string()),
+            "driver-local".to_string(),
+            xsession_id.clone(),
+        )
+        .unwrap();
+        state.add_session(xsession_id.clone(), session);
+        let state = Arc::new(RwLock::new(state));
+        let mutex_session = state.read().await.get_session(&xsession_id).unwrap();
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(mutex_session.lock().await.seconds_since_last_request(), 10);
+
+        let response = handle(
+            Request::post("/session/local/refresh-timeout")
+                .body(Body::empty())
+                .unwrap(),
+            "127.0.0.1:50000".parse().unwrap(),
+            state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json, serde_json::json!({ "value": null }));
+        assert_eq!(mutex_session.lock().await.seconds_since_last_request(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_forward_websocket() {
+        // A WebDriver that echoes all WebSocket messages.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let driver_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // The callback signature is defined by tungstenite.
+            #[allow(clippy::result_large_err)]
+            let check_path = |req: &WsRequest, resp: WsResponse| {
+                assert_eq!(req.uri().path(), "/session/driver-ws/se/bidi");
+                Ok(resp)
+            };
+            let ws = tokio_tungstenite::accept_hdr_async(stream, check_path)
+                .await
+                .unwrap();
+            let (tx, rx) = ws.split();
+            let _ = rx.forward(tx).await;
+        });
+
+        let mut state = XenonState::new(XenonConfig::default()).await.unwrap();
+        let xsession_id = XenonSessionId::from("ws");
+        let session = Session::adopt(
+            Upstream::new(Scheme::HTTP, driver_addr.to_string().parse().unwrap()),
+            Client::new(),
+            None,
+            "driver-ws".to_string(),
+            xsession_id.clone(),
+        )
+        .unwrap();
+        state.add_session(xsession_id, session);
+        let state = Arc::new(RwLock::new(state));
+
+        let make_service = make_service_fn(move |_| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle(req, "127.0.0.1:50000".parse().unwrap(), state.clone())
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let url = format!("ws://{}/session/ws/se/bidi", server.local_addr());
+        tokio::spawn(server);
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        ws.send(Message::Text("hello".to_string())).await.unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::Text("hello".to_string())
+        );
+        ws.close(None).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queue_timeout() {
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+queue_timeout_secs: 3
+browsers:
+  - name: chrome
+    max_sessions: 0
+"#,
+        )
+        .unwrap();
+        let state = Arc::new(RwLock::new(XenonState::new(config).await.unwrap()));
+        let capabilities: Capabilities =
+            serde_json::from_str(r#"{"alwaysMatch": {"browserName": "chrome"}}"#).unwrap();
+
+        let start = tokio::time::Instant::now();
+        let result = reserve_available_session(state, &capabilities).await;
+        assert!(matches!(
+            result,
+            Err(XenonError::RespondWith(XenonResponse::NoSessionsAvailable))
+        ));
+        assert!(start.elapsed() >= Duration::from_secs(3));
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queue_fails_when_draining() {
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+queue_timeout_secs: 30
+browsers:
+  - name: chrome
+    max_sessions: 0
+"#,
+        )
+        .unwrap();
+        let state = Arc::new(RwLock::new(XenonState::new(config).await.unwrap()));
+        let capabilities: Capabilities =
+            serde_json::from_str(r#"{"alwaysMatch": {"browserName": "chrome"}}"#).unwrap();
+
+        let start = tokio::time::Instant::now();
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(2500)).await;
+            state_clone.read().await.start_draining().await;
+        });
+        let result = reserve_available_session(state, &capabilities).await;
+        assert!(matches!(
+            result,
+            Err(XenonError::RespondWith(XenonResponse::ShuttingDown))
+        ));
+        assert_eq!(start.elapsed(), Duration::from_millis(2500));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_admin_port_map() {
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+browsers:
+  - name: chrome
+    driver_path: sleep
+    args: ["{PORT}"]
+    sessions_per_driver: 2
+ports: ["auto:3"]
+"#,
+        )
+        .unwrap();
+        let state = Arc::new(RwLock::new(XenonState::new(config).await.unwrap()));
+        let capabilities: Capabilities =
+            serde_json::from_value(serde_json::json!({"alwaysMatch": {"browserName": "chrome"}}))
+                .unwrap();
+        reserve_available_session(state.clone(), &capabilities)
+            .await
+            .unwrap();
+
+        let response = handle(
+            Request::get("/admin/port-map").body(Body::empty()).unwrap(),
+            "127.0.0.1:50000".parse().unwrap(),
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let value = &json["value"];
+        assert_eq!(value["total"], 3);
+        assert_eq!(value["available"], 2);
+        assert_eq!(value["taken"], 1);
+        let ports = value["ports"].as_array().unwrap();
+        assert_eq!(ports.len(), 3);
+        let taken: Vec<_> = ports.iter().filter(|x| x["status"] == "taken").collect();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0]["service_group"], "chrome-any");
+        assert_eq!(taken[0]["sessions"], 1);
+        let available = ports.iter().find(|x| x["status"] == "available").unwrap();
+        assert_eq!(available["service_group"], serde_json::Value::Null);
+        assert_eq!(available["sessions"], 0);
+
+        let s = state.read().await;
+        let rwlock_groups = s.service_groups();
+        let rwlock_port_manager = s.port_manager();
+        let (mut port_manager, mut groups) =
+            tokio::join!(rwlock_port_manager.write(), rwlock_groups.write());
+        for group in groups.values_mut() {
+            group.terminate_all(&mut port_manager).await;
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_max_sessions() {
+        // A long sleep stands in for the WebDriver, since it is never connected to here.
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+browsers:
+  - name: chrome
+    driver_path: sleep
+    args: ["{PORT}"]
+  - name: firefox
+    driver_path: sleep
+    args: ["{PORT}"]
+ports: ["auto:3"]
+"#,
+        )
+        .unwrap();
+        let mut state = XenonState::new(config).await.unwrap();
+        state.set_max_sessions(Some(2));
+        let state = Arc::new(RwLock::new(state));
+        let capabilities = |browser: &str| -> Capabilities {
+            serde_json::from_value(serde_json::json!({"alwaysMatch": {"browserName": browser}}))
+                .unwrap()
+        };
+        let status = |state: Arc<RwLock<XenonState>>| async move {
+            let response = handle(
+                Request::get("/status").body(Body::empty()).unwrap(),
+                "127.0.0.1:50000".parse().unwrap(),
+                state,
+            )
+            .await
+            .unwrap();
+            let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            json["value"].clone()
+        };
+
+        let value = status(state.clone()).await;
+        assert_eq!(value["ready"], true);
+        assert_eq!(value["total_capacity"], 2);
+
+        reserve_available_session(state.clone(), &capabilities("chrome"))
+            .await
+            .unwrap();
+        reserve_available_session(state.clone(), &capabilities("firefox"))
+            .await
+            .unwrap();
+        let result = reserve_available_session(state.clone(), &capabilities("firefox")).await;
+        assert!(matches!(
+            result,
+            Err(XenonError::RespondWith(XenonResponse::NoSessionsAvailable))
+        ));
+
+        // The groups still have room, but the global cap has been reached.
+        let value = status(state.clone()).await;
+        assert_eq!(value["ready"], false);
+        assert_eq!(value["total_capacity"], 0);
+
+        // No matching browser still takes precedence.
+        let result = reserve_available_session(state.clone(), &capabilities("safari")).await;
+        assert!(matches!(
+            result,
+            Err(XenonError::RespondWith(XenonResponse::NoMatchingBrowser))
+        ));
+
+        let s = state.read().await;
+        let rwlock_groups = s.service_groups();
+        let rwlock_port_manager = s.port_manager();
+        let (mut port_manager, mut groups) =
+            tokio::join!(rwlock_port_manager.write(), rwlock_groups.write());
+        for group in groups.values_mut() {
+            group.terminate_all(&mut port_manager).await;
         }
     }
 
-    debug!("Downstream node configuration complete");
+    #[tokio::test]
+    async fn test_node_timeout_deletes_late_session() {
+        // A node that takes 2 seconds to create a session.
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let body = match (req.method(), req.uri().path()) {
+                            (&hyper::Method::POST, "/session") => {
+                                tokio::time::sleep(Duration::from_secs(2)).await;
+                                r#"{"value": {"sessionId": "late", "capabilities": {}}}"#
+                            }
+                            (&hyper::Method::DELETE, path) => {
+                                tx.send(path.to_string()).unwrap();
+                                r#"{"value": null}"#
+                            }
+                            _ => r#"{"value": {"ready": true}}"#,
+                        };
+                        Ok::<_, Infallible>(hyper::Response::new(Body::from(body)))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let config: XenonConfig = serde_yaml::from_str(&format!(
+            r#"
+node_timeout_secs: 1
+nodes:
+  - url: http://{}
+    service_groups:
+      - browser:
+          name: chrome
+        remaining_sessions: 1
+"#,
+            addr
+        ))
+        .unwrap();
+        let state = Arc::new(RwLock::new(XenonState::new(config).await.unwrap()));
+        let request = Request::post("/session")
+            .body(Body::from(
+                r#"{"capabilities": {"alwaysMatch": {"browserName": "chrome"}}}"#,
+            ))
+            .unwrap();
+        let response = handle(request, "127.0.0.1:50000".parse().unwrap(), state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(state.read().await.num_sessions(), 0);
+
+        let deleted = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .unwrap();
+        assert_eq!(deleted.as_deref(), Some("/session/late"));
+    }
 }
@@ -1,53 +1,207 @@
 use crate::browser::{BrowserConfig, Capabilities};
 use crate::error::{XenonError, XenonResult};
 use crate::portmanager::{PortManager, ServicePort};
+use crate::queue::SessionQueue;
 use crate::response::XenonResponse;
 use crate::session::XenonSessionId;
 use log::*;
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::process::{Child, Command};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::{Duration, Instant};
+
+/// The maximum time to wait for a pre-stop hook to complete.
+const PRE_STOP_HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A shared handle to a WebDriver process, allowing other tasks to check
+/// whether the process is still running.
+#[derive(Debug, Clone)]
+pub struct ProcessHandle {
+    child: Arc<Mutex<Child>>,
+    /// Incremented each time the process is restarted. This is only changed while
+    /// the child mutex is held.
+    generation: Arc<AtomicU64>,
+    /// Any extra ports reserved for the process, in addition to its main port.
+    extra_ports: Vec<ServicePort>,
+}
+
+impl ProcessHandle {
+    /// Returns the exit status if the process has exited, otherwise None.
+    pub async fn exit_status(&self) -> Option<ExitStatus> {
+        match self.child.lock().await.try_wait() {
+            Ok(status) => status,
+            Err(e) => {
+                warn!("Unable to get status of WebDriver process: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// The number of times the process has been restarted.
+    /// Read this before using the process, and pass it to `restart()` if it fails.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Spawn a new WebDriver process in place of one that has exited.
+    /// Anything else holding this handle will see the new process.
+    ///
+    /// `generation` is the generation the caller saw before the process failed. If the
+    /// process has been restarted since then, e.g. by another session sharing it, it is
+    /// not restarted again. Returns false if the process is still running, in which case
+    /// it is left alone.
+    pub async fn restart(
+        &self,
+        generation: u64,
+        port: ServicePort,
+        path: &Path,
+        args: &Option<Vec<String>>,
+    ) -> XenonResult<bool> {
+        let mut process = self.child.lock().await;
+        if self.generation() != generation {
+            return Ok(true);
+        }
+        if let Ok(None) = process.try_wait() {
+            return Ok(false);
+        }
+        info!("Restart WebDriver on port {}", port);
+        *process = spawn_process(port, &self.extra_ports, path, args)?;
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        Ok(true)
+    }
+}
+
+/// Spawn a WebDriver process on the specified port.
+fn spawn_process(
+    port: ServicePort,
+    extra_ports: &[ServicePort],
+    path: &Path,
+    args: &Option<Vec<String>>,
+) -> XenonResult<Child> {
+    let args = driver_args(port, extra_ports, args.as_deref().unwrap_or_default());
+    debug!(
+        "Spawn new WebDriver on port {}: {}",
+        port,
+        command_line(path, &args)
+    );
+    Ok(Command::new(path).args(args).kill_on_drop(true).spawn()?)
+}
+
+/// Build the arguments for a WebDriver process.
+/// Any `{PORT}` placeholders are replaced with the port. If there are none,
+/// `--port={port}` is appended instead. `{EXTRA_PORT_1}` etc. are replaced with
+/// the extra ports, if any.
+fn driver_args(port: ServicePort, extra_ports: &[ServicePort], args: &[String]) -> Vec<String> {
+    let args: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            let mut arg = arg.clone();
+            for (i, extra_port) in extra_ports.iter().enumerate() {
+                arg = arg.replace(
+                    &format!("{{EXTRA_PORT_{}}}", i + 1),
+                    &extra_port.to_string(),
+                );
+            }
+            arg
+        })
+        .collect();
+    if args.iter().any(|arg| arg.contains("{PORT}")) {
+        args.iter()
+            .map(|arg| arg.replace("{PORT}", &port.to_string()))
+            .collect()
+    } else {
+        args.iter()
+            .cloned()
+            .chain(std::iter::once(format!("--port={}", port)))
+            .collect()
+    }
+}
+
+/// Format a command and its arguments as they would be typed into a shell,
+/// so that the command can be copied from the log and run manually.
+fn command_line(path: &Path, args: &[String]) -> String {
+    std::iter::once(path.to_string_lossy().into_owned())
+        .chain(args.iter().cloned())
+        .map(|x| {
+            if x.is_empty() || x.contains(char::is_whitespace) {
+                format!("{:?}", x)
+            } else {
+                x
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
 /// A WebDriverService represents one instance of a webdriver binary such
 /// as chromedriver, to which one or more sessions can attach.
 #[derive(Debug)]
 pub struct WebDriverService {
     port: ServicePort,
-    process: Child,
+    process: ProcessHandle,
     sessions: HashSet<XenonSessionId>,
+    /// The highest number of sessions this service has had at once.
+    peak_concurrent_sessions: usize,
+    /// The last time a session was added to or removed from this service.
+    last_used: Instant,
 }
 
 impl WebDriverService {
     pub async fn spawn(
         port: ServicePort,
+        extra_ports: Vec<ServicePort>,
         path: &Path,
         args: &Option<Vec<String>>,
     ) -> XenonResult<Self> {
-        let port_arg = &[format!("--port={}", port)];
-        let args = args
-            .as_ref()
-            .map(|args| args.as_slice())
-            .unwrap_or_else(|| &[])
-            .iter()
-            .chain(port_arg);
-
-        debug!(
-            "Spawn new WebDriver on port {} with args {:?}: {:?}",
-            port, args, path
-        );
-        let process = Command::new(path).args(args).kill_on_drop(true).spawn()?;
+        let process = spawn_process(port, &extra_ports, path, args)?;
         Ok(Self {
             port,
-            process,
+            process: ProcessHandle {
+                child: Arc::new(Mutex::new(process)),
+                generation: Arc::new(AtomicU64::new(0)),
+                extra_ports,
+            },
             sessions: HashSet::new(),
+            peak_concurrent_sessions: 0,
+            last_used: Instant::now(),
         })
     }
 
-    pub async fn terminate(mut self) {
+    /// Replace the WebDriver process with a new one, if it has exited.
+    /// Sessions attached to the old process are kept, but the new process will not know them.
+    pub async fn restart(&mut self, path: &Path, args: &Option<Vec<String>>) -> XenonResult<()> {
+        self.process
+            .restart(self.process.generation(), self.port, path, args)
+            .await?;
+        Ok(())
+    }
+
+    /// Terminate the WebDriver process.
+    /// Returns true if the process had already exited abnormally before being terminated.
+    pub async fn terminate(self, pre_stop_hook: Option<&[String]>) -> bool {
         assert!(self.sessions.is_empty());
 
+        // Check whether the process already exited by itself.
+        let early_exit = self.process.exit_status().await;
+        if let Some(status) = early_exit {
+            warn!(
+                "WebDriver on port {} exited unexpectedly with status {:?}",
+                self.port, status
+            );
+            return !status.success();
+        }
+
+        if let Some(hook) = pre_stop_hook {
+            self.run_pre_stop_hook(hook).await;
+        }
+
         debug!("Terminate WebDriver on port {}", self.port);
-        if let Err(e) = self.process.kill().await {
+        let mut process = self.process.child.lock().await;
+        if let Err(e) = process.kill().await {
             // What to do? For now just log the error but let everything proceed.
             // TODO: Options:
             //       1. Ignore all such errors indefinitely (but still log them) <-- Current
@@ -56,6 +210,58 @@ impl WebDriverService {
             //       3. Quit if safe - only if session count happens to hit 0 organically
             //       4. Add process to a retry list and keep trying periodically
             error!("Error terminating WebDriver on port {}: {:?}", self.port, e);
+            return false;
+        }
+
+        match process.wait().await {
+            Ok(status) => debug!(
+                "WebDriver on port {} exited with status {:?}",
+                self.port, status
+            ),
+            Err(e) => warn!(
+                "Unable to get exit status of WebDriver on port {}: {:?}",
+                self.port, e
+            ),
+        }
+        false
+    }
+
+    /// Run the pre-stop hook and wait for it to complete (up to a timeout).
+    /// Errors are logged but will not prevent the WebDriver from being terminated.
+    async fn run_pre_stop_hook(&self, hook: &[String]) {
+        let (cmd, args) = match hook.split_first() {
+            Some(x) => x,
+            None => return,
+        };
+
+        debug!(
+            "Run pre-stop hook for WebDriver on port {}: {:?}",
+            self.port, hook
+        );
+        let status = Command::new(cmd).args(args).kill_on_drop(true).status();
+        match tokio::time::timeout(PRE_STOP_HOOK_TIMEOUT, status).await {
+            Ok(Ok(status)) if status.success() => {
+                debug!("Pre-stop hook for port {} completed", self.port);
+            }
+            Ok(Ok(status)) => {
+                warn!(
+                    "Pre-stop hook for port {} exited with status {}",
+                    self.port, status
+                );
+            }
+            Ok(Err(e)) => {
+                error!(
+                    "Error running pre-stop hook for port {}: {:?}",
+                    self.port, e
+                );
+            }
+            Err(_) => {
+                error!(
+                    "Pre-stop hook for port {} timed out after {} seconds",
+                    self.port,
+                    PRE_STOP_HOOK_TIMEOUT.as_secs()
+                );
+            }
         }
     }
 
@@ -63,16 +269,39 @@ impl WebDriverService {
         self.port
     }
 
+    /// The main port, followed by any extra ports reserved for this service.
+    pub fn ports(&self) -> Vec<ServicePort> {
+        std::iter::once(self.port)
+            .chain(self.process.extra_ports.iter().copied())
+            .collect()
+    }
+
+    pub fn process(&self) -> ProcessHandle {
+        self.process.clone()
+    }
+
     pub fn num_active_sessions(&self) -> usize {
         self.sessions.len()
     }
 
+    pub fn peak_concurrent_sessions(&self) -> usize {
+        self.peak_concurrent_sessions
+    }
+
     pub fn add_session(&mut self, session_id: XenonSessionId) {
         self.sessions.insert(session_id);
+        self.peak_concurrent_sessions = self.peak_concurrent_sessions.max(self.sessions.len());
+        self.last_used = Instant::now();
     }
 
     pub fn delete_session(&mut self, session_id: &XenonSessionId) {
         self.sessions.remove(session_id);
+        self.last_used = Instant::now();
+    }
+
+    /// Has this service had no sessions for at least the specified duration?
+    fn is_idle_for(&self, duration: Duration) -> bool {
+        self.sessions.is_empty() && self.last_used.elapsed() >= duration
     }
 }
 
@@ -85,26 +314,65 @@ pub type ServiceGroupName = String;
 /// connection, up to the max_sessions limit in the BrowserConfig struct.
 #[derive(Debug)]
 pub struct ServiceGroup {
+    /// The unique name of this group, in the form `{browser}-{version}`, where the
+    /// version is `any` if not specified. This allows several versions of the same
+    /// browser to be configured.
+    name: ServiceGroupName,
     pub browser: BrowserConfig,
     services: HashMap<ServicePort, WebDriverService>,
+    /// The number of consecutive failed attempts to spawn a service.
+    /// This is reset whenever a service is spawned successfully.
+    spawn_failures: u64,
+    /// The total number of failed attempts to spawn a service, since startup.
+    total_spawn_failures: u64,
+    /// The number of WebDriver processes in this group that exited abnormally
+    /// by themselves, rather than being terminated by Xenon.
+    total_abnormal_exits: Arc<AtomicU64>,
+    /// The highest peak_concurrent_sessions of any service that has been terminated.
+    terminated_peak_concurrent_sessions: usize,
+    /// Requests waiting for a session to become available in this group.
+    queue: SessionQueue,
 }
 
 impl ServiceGroup {
     pub fn new(browser: BrowserConfig) -> Self {
         Self {
+            name: format!("{}-{}", browser.name(), browser.version().unwrap_or("any")),
             browser,
             services: HashMap::new(),
+            spawn_failures: 0,
+            total_spawn_failures: 0,
+            total_abnormal_exits: Arc::new(AtomicU64::new(0)),
+            terminated_peak_concurrent_sessions: 0,
+            queue: SessionQueue::new(),
         }
     }
 
     pub fn name(&self) -> &str {
-        &self.browser.name()
+        &self.name
+    }
+
+    pub fn queue(&self) -> &SessionQueue {
+        &self.queue
     }
 
     pub fn matches_capabilities(&self, capabilities: &Capabilities) -> bool {
         self.browser.matches_capabilities(capabilities)
     }
 
+    /// Get the process handle for the service on the specified port.
+    pub fn process(&self, port: ServicePort) -> Option<ProcessHandle> {
+        self.services.get(&port).map(|service| service.process())
+    }
+
+    pub fn num_services(&self) -> usize {
+        self.services.len()
+    }
+
+    pub fn services(&self) -> impl Iterator<Item = &WebDriverService> {
+        self.services.values()
+    }
+
     pub fn total_sessions(&self) -> usize {
         let mut count = 0;
         for service in self.services.values() {
@@ -113,6 +381,22 @@ impl ServiceGroup {
         count
     }
 
+    /// The highest number of sessions any one service in this group has had at once,
+    /// since startup. If this is always `sessions_per_driver`, it may need to be increased.
+    pub fn peak_concurrent_sessions(&self) -> usize {
+        self.services
+            .values()
+            .map(|service| service.peak_concurrent_sessions())
+            .fold(self.terminated_peak_concurrent_sessions, usize::max)
+    }
+
+    /// The total number of failed attempts to spawn a service, since startup.
+    /// Unlike `spawn_failures`, this is never reset.
+    #[cfg(feature = "metrics")]
+    pub fn total_spawn_failures(&self) -> u64 {
+        self.total_spawn_failures
+    }
+
     pub fn has_capacity(&self) -> bool {
         let max_sessions = self.browser.max_sessions() as usize;
         self.total_sessions() < max_sessions
@@ -125,13 +409,13 @@ impl ServiceGroup {
         let max_per_service = self.browser.sessions_per_driver() as usize;
         let max_sessions = self.browser.max_sessions() as usize;
         let mut overall_session_count = 0;
-        let mut next_port: Option<u16> = None;
+        let mut next_port: Option<ServicePort> = None;
         let mut best = max_per_service;
         for (k, v) in self.services.iter() {
             let num_sessions_for_service = v.sessions.len();
             overall_session_count += num_sessions_for_service;
             if overall_session_count >= max_sessions {
-                return Err(XenonError::NoSessionsAvailable);
+                return Err(XenonError::RespondWith(XenonResponse::NoSessionsAvailable));
             }
             if num_sessions_for_service < best {
                 best = num_sessions_for_service;
@@ -140,22 +424,58 @@ impl ServiceGroup {
         }
 
         let next_port = match next_port {
-            Some(p) => p,
+            Some(p) => {
+                // Don't add a session to a WebDriver that has crashed.
+                let service = self
+                    .services
+                    .get_mut(&p)
+                    .unwrap_or_else(|| panic!("No service for port '{}'", p));
+                if let Some(status) = service.process.exit_status().await {
+                    warn!(
+                        "WebDriver on port {} exited unexpectedly with status {:?}",
+                        p, status
+                    );
+                    self.total_abnormal_exits.fetch_add(1, Ordering::Relaxed);
+                    service
+                        .restart(self.browser.driver_path(), self.browser.args())
+                        .await?;
+                }
+                p
+            }
             None => {
                 // Spawn new service.
-                let newport = match port_manager.lock_next_port() {
+                let num_ports = 1 + self.browser.extra_ports() as usize;
+                let ports = match port_manager.reserve_range(num_ports) {
                     Some(p) => p,
                     None => {
                         // We're all out of ports.
                         return Err(XenonError::RespondWith(XenonResponse::NoSessionsAvailable));
                     }
                 };
-                let service = WebDriverService::spawn(
+                let newport = ports[0];
+                let service = match WebDriverService::spawn(
                     newport,
-                    &self.browser.driver_path(),
+                    ports[1..].to_vec(),
+                    self.browser.driver_path(),
                     self.browser.args(),
                 )
-                .await?;
+                .await
+                {
+                    Ok(service) => service,
+                    Err(e) => {
+                        port_manager.release_range(&ports);
+                        self.spawn_failures += 1;
+                        self.total_spawn_failures += 1;
+                        warn!(
+                            "Failed to spawn WebDriver for group '{}' ({} consecutive failures): {}",
+                            self.name(),
+                            self.spawn_failures,
+                            e
+                        );
+                        return Err(e);
+                    }
+                };
+                self.spawn_failures = 0;
                 self.services.insert(newport, service);
                 newport
             }
@@ -168,27 +488,372 @@ impl ServiceGroup {
             .unwrap_or_else(|| panic!("No service for port '{}'", next_port)))
     }
 
-    pub async fn delete_session(
+    /// Attach an existing session to the service running on the specified port.
+    /// Returns false if there is no service on that port.
+    pub fn adopt_session(&mut self, port: ServicePort, xsession_id: XenonSessionId) -> bool {
+        match self.services.get_mut(&port) {
+            Some(service) => {
+                service.add_session(xsession_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Terminate every service in this group, including any that still have
+    /// active sessions. This is only intended for use during shutdown.
+    pub async fn terminate_all(&mut self, port_manager: &mut PortManager) {
+        let services: Vec<_> = self.services.drain().collect();
+        for (port, mut service) in services {
+            let ports = service.ports();
+            self.terminated_peak_concurrent_sessions = self
+                .terminated_peak_concurrent_sessions
+                .max(service.peak_concurrent_sessions());
+            if !service.sessions.is_empty() {
+                warn!(
+                    "Terminating WebDriver on port {} with {} active session(s)",
+                    port,
+                    service.sessions.len()
+                );
+                service.sessions.clear();
+            }
+            if service.terminate(self.browser.pre_stop_hook()).await {
+                self.total_abnormal_exits.fetch_add(1, Ordering::Relaxed);
+            }
+            port_manager.release_range(&ports);
+        }
+        self.queue.notify();
+    }
+
+    /// Remove the session from the service on the specified port.
+    /// If the service should now be terminated, it is detached from the group and returned.
+    #[must_use]
+    pub fn delete_session(
         &mut self,
         port: ServicePort,
         xsession_id: &XenonSessionId,
-        port_manager: &mut PortManager,
-    ) {
+    ) -> Option<DetachedService> {
         let mut should_terminate = false;
         if let Some(service) = self.services.get_mut(&port) {
             service.delete_session(xsession_id);
 
-            // Should we terminate this session?
-            if service.sessions.is_empty() {
+            // Should we terminate this session? If there is an idle timeout,
+            // the service is kept for new sessions until gc_idle_services() is called.
+            if service.sessions.is_empty() && self.browser.idle_service_timeout_secs().is_none() {
                 should_terminate = true;
             }
         }
 
-        if should_terminate {
-            if let Some(service) = self.services.remove(&port) {
-                service.terminate().await;
-                port_manager.unlock_port(port);
-            }
+        let detached = if should_terminate {
+            self.detach_service(port)
+        } else {
+            None
+        };
+        self.queue.notify();
+        detached
+    }
+
+    /// Detach services that have had no sessions for longer than the
+    /// configured `idle_service_timeout_secs`, so that they can be terminated.
+    #[must_use]
+    pub fn gc_idle_services(&mut self) -> Vec<DetachedService> {
+        let timeout = match self.browser.idle_service_timeout_secs() {
+            Some(x) => Duration::from_secs(x),
+            None => return Vec::new(),
+        };
+        let idle_ports: Vec<ServicePort> = self
+            .services
+            .values()
+            .filter(|service| service.is_idle_for(timeout))
+            .map(|service| service.port())
+            .collect();
+        idle_ports
+            .into_iter()
+            .filter_map(|port| {
+                debug!(
+                    "WebDriver on port {} has been idle for {} seconds",
+                    port,
+                    timeout.as_secs()
+                );
+                self.detach_service(port)
+            })
+            .collect()
+    }
+
+    /// Remove the service on the specified port from this group.
+    /// Its ports stay reserved until it has been terminated.
+    fn detach_service(&mut self, port: ServicePort) -> Option<DetachedService> {
+        let service = self.services.remove(&port)?;
+        self.terminated_peak_concurrent_sessions = self
+            .terminated_peak_concurrent_sessions
+            .max(service.peak_concurrent_sessions());
+        Some(DetachedService {
+            service,
+            group_name: self.name.clone(),
+            pre_stop_hook: self.browser.pre_stop_hook().map(|x| x.to_vec()),
+            total_abnormal_exits: self.total_abnormal_exits.clone(),
+        })
+    }
+}
+
+/// A service that has been removed from its group but not yet terminated.
+/// Terminating a service may run its pre-stop hook, which can take a while, so this
+/// is done after the service groups and port manager locks have been released.
+#[derive(Debug)]
+#[must_use]
+pub struct DetachedService {
+    service: WebDriverService,
+    group_name: ServiceGroupName,
+    pre_stop_hook: Option<Vec<String>>,
+    total_abnormal_exits: Arc<AtomicU64>,
+}
+
+impl DetachedService {
+    /// Terminate the WebDriver process and return the ports that can now be released.
+    pub async fn terminate(self) -> Vec<ServicePort> {
+        let ports = self.service.ports();
+        if self.service.terminate(self.pre_stop_hook.as_deref()).await {
+            let total = self.total_abnormal_exits.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "WebDriver for group '{}' exited abnormally ({} abnormal exits in total)",
+                self.group_name, total
+            );
+        }
+        ports
+    }
+}
+
+/// Terminate the detached services and release their ports.
+/// The caller must not hold the port manager lock.
+pub async fn terminate_services(
+    services: Vec<DetachedService>,
+    port_manager: &RwLock<PortManager>,
+) {
+    let released =
+        futures_util::future::join_all(services.into_iter().map(|service| service.terminate()))
+            .await;
+    if !released.is_empty() {
+        let mut port_manager = port_manager.write().await;
+        for ports in released {
+            port_manager.release_range(&ports);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::browser::BrowserConfig;
+    use crate::config::XenonConfig;
+    use crate::portmanager::{PortManager, ServicePort};
+    use crate::service::{
+        command_line, driver_args, terminate_services, ServiceGroup, WebDriverService,
+    };
+    use crate::session::XenonSessionId;
+    use std::path::Path;
+    use tokio::sync::RwLock;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_driver_args() {
+        let port = ServicePort::from(5000);
+        assert_eq!(driver_args(port, &[], &[]), vec!["--port=5000"]);
+
+        let args = vec!["--verbose".to_string()];
+        assert_eq!(
+            driver_args(port, &[], &args),
+            vec!["--verbose", "--port=5000"]
+        );
+
+        let args = vec![
+            "-p".to_string(),
+            "{PORT}".to_string(),
+            "--log=/tmp/{PORT}.log".to_string(),
+        ];
+        assert_eq!(
+            driver_args(port, &[], &args),
+            vec!["-p", "5000", "--log=/tmp/5000.log"]
+        );
+
+        let extra_ports = [ServicePort::from(5001), ServicePort::from(5002)];
+        let args = vec![
+            "--bootstrap-port={EXTRA_PORT_1}".to_string(),
+            "--adb-port={EXTRA_PORT_2}".to_string(),
+        ];
+        assert_eq!(
+            driver_args(port, &extra_ports, &args),
+            vec!["--bootstrap-port=5001", "--adb-port=5002", "--port=5000"]
+        );
+    }
+
+    #[test]
+    fn test_command_line() {
+        let args = driver_args(
+            ServicePort::from(5000),
+            &[],
+            &["--log-path=/tmp/my log".to_string()],
+        );
+        assert_eq!(
+            command_line(Path::new("/usr/local/bin/chromedriver"), &args),
+            r#"/usr/local/bin/chromedriver "--log-path=/tmp/my log" --port=5000"#
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_restart() {
+        // A long sleep stands in for the WebDriver process.
+        let path = Path::new("sleep");
+        let args = Some(vec!["{PORT}".to_string()]);
+        let mut service = WebDriverService::spawn(ServicePort::from(5000), Vec::new(), path, &args)
+            .await
+            .unwrap();
+        let process = service.process();
+        assert!(process.exit_status().await.is_none());
+
+        // Simulate a crash.
+        process.child.lock().await.kill().await.unwrap();
+        assert!(process.exit_status().await.is_some());
+
+        let generation = process.generation();
+        service.restart(path, &args).await.unwrap();
+        assert!(process.exit_status().await.is_none());
+        assert_eq!(process.generation(), generation + 1);
+
+        // A process that was already restarted by someone else is not restarted again.
+        let pid = process.child.lock().await.id();
+        assert!(process
+            .restart(generation, service.port(), path, &args)
+            .await
+            .unwrap());
+        assert_eq!(process.child.lock().await.id(), pid);
+
+        // A running process is never restarted.
+        assert!(!process
+            .restart(process.generation(), service.port(), path, &args)
+            .await
+            .unwrap());
+        assert_eq!(process.child.lock().await.id(), pid);
+
+        assert!(!service.terminate(None).await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(start_paused = true)]
+    async fn test_gc_idle_services() {
+        let config: XenonConfig = serde_yaml::from_str("ports: [\"auto:1\"]").unwrap();
+        let mut port_manager = PortManager::new(&config);
+        let browser: BrowserConfig = serde_yaml::from_str(
+            r#"
+name: chrome
+driver_path: sleep
+args: ["{PORT}"]
+sessions_per_driver: 2
+idle_service_timeout_secs: 60
+"#,
+        )
+        .unwrap();
+        let mut group = ServiceGroup::new(browser);
+        let xsession_id = XenonSessionId::from("session1");
+        let port = {
+            let service = group.get_or_start_service(&mut port_manager).await.unwrap();
+            service.add_session(xsession_id.clone());
+            service.port()
+        };
+
+        // The service is kept after its last session ends.
+        assert!(group.delete_session(port, &xsession_id).is_none());
+        assert_eq!(group.num_services(), 1);
+        assert_eq!(port_manager.available_ports().count(), 0);
+
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert!(group.gc_idle_services().is_empty());
+        assert_eq!(group.num_services(), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let detached = group.gc_idle_services();
+        assert_eq!(detached.len(), 1);
+        assert_eq!(group.num_services(), 0);
+        let port_manager = RwLock::new(port_manager);
+        terminate_services(detached, &port_manager).await;
+        assert_eq!(port_manager.read().await.available_ports().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_extra_ports() {
+        let config: XenonConfig = serde_yaml::from_str("ports: [\"5000-5004\"]").unwrap();
+        let mut port_manager = PortManager::new(&config);
+        let browser: BrowserConfig = serde_yaml::from_str(
+            r#"
+name: chrome
+driver_path: sleep
+args: ["{PORT}"]
+extra_ports: 2
+"#,
+        )
+        .unwrap();
+        let mut group = ServiceGroup::new(browser);
+        let xsession_id = XenonSessionId::from("session1");
+        let (port, ports) = {
+            let service = group.get_or_start_service(&mut port_manager).await.unwrap();
+            service.add_session(xsession_id.clone());
+            (service.port(), service.ports())
+        };
+        assert_eq!(
+            ports,
+            vec![
+                ServicePort::from(5000),
+                ServicePort::from(5001),
+                ServicePort::from(5002)
+            ]
+        );
+        assert_eq!(port_manager.available_ports().count(), 2);
+
+        // Not enough consecutive ports for another service.
+        let result = group.get_or_start_service(&mut port_manager).await;
+        assert!(result.is_err());
+
+        // The ports stay reserved until the service has been terminated.
+        let detached = group.delete_session(port, &xsession_id).unwrap();
+        assert_eq!(group.num_services(), 0);
+        assert_eq!(port_manager.available_ports().count(), 2);
+        port_manager.release_range(&detached.terminate().await);
+        assert_eq!(port_manager.available_ports().count(), 5);
+        group.terminate_all(&mut port_manager).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_peak_concurrent_sessions() {
+        let config: XenonConfig = serde_yaml::from_str("ports: [\"auto:1\"]").unwrap();
+        let mut port_manager = PortManager::new(&config);
+        let browser: BrowserConfig = serde_yaml::from_str(
+            r#"
+name: chrome
+driver_path: sleep
+args: ["{PORT}"]
+sessions_per_driver: 3
+"#,
+        )
+        .unwrap();
+        let mut group = ServiceGroup::new(browser);
+        assert_eq!(group.peak_concurrent_sessions(), 0);
+
+        let ids: Vec<XenonSessionId> = ["a", "b"].into_iter().map(XenonSessionId::from).collect();
+        let mut port = None;
+        for id in &ids {
+            let service = group.get_or_start_service(&mut port_manager).await.unwrap();
+            service.add_session(id.clone());
+            port = Some(service.port());
         }
+        let port = port.unwrap();
+        assert!(group.delete_session(port, &ids[0]).is_none());
+        assert_eq!(group.peak_concurrent_sessions(), 2);
+
+        // The peak is kept after the service is terminated.
+        let detached = group.delete_session(port, &ids[1]).unwrap();
+        port_manager.release_range(&detached.terminate().await);
+        assert_eq!(group.num_services(), 0);
+        assert_eq!(group.peak_concurrent_sessions(), 2);
     }
 }
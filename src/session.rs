@@ -1,57 +1,176 @@
+use crate::browser::{BrowserConfig, W3CCapabilities};
 use crate::error::{XenonError, XenonResult};
+use crate::metrics;
 use crate::portmanager::ServicePort;
-use crate::response::XenonResponse;
+use crate::request_util::{build_proxy_request, copy_forwarded_headers};
+use crate::response::{CreateSessionError, XenonResponse};
+use crate::service::ProcessHandle;
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
 use hyper::client::HttpConnector;
 use hyper::http::uri::{Authority, Scheme};
 use hyper::{Body, Client, Request, Response};
 use log::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+pub use xenon_webdriver::XenonSessionId;
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-pub struct XenonSessionId(String);
+/// The number of times to check whether a restarted WebDriver is ready, 500ms apart.
+const DRIVER_RESTART_ATTEMPTS: u32 = 20;
 
-impl<T> From<T> for XenonSessionId
+/// Capabilities in a new session response that contain a WebSocket URL, and the
+/// session endpoint that Xenon exposes each of them on.
+const WEBSOCKET_CAPABILITIES: [(&str, &str); 2] =
+    [("webSocketUrl", "se/bidi"), ("se:cdp", "se/cdp")];
+
+/// The `value` of a new session response. The driver responds with its own session id,
+/// which is replaced with the Xenon session id before being returned to the client.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConnectionData<Id> {
+    #[serde(default, rename = "sessionId")]
+    session_id: Id,
+    #[serde(default)]
+    capabilities: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ConnectionResp<Id> {
+    #[serde(default, rename = "sessionId")]
+    session_id: Id,
+    /// Some drivers return `"value": null` when session creation fails.
+    #[serde(default, deserialize_with = "null_as_default")]
+    value: ConnectionData<Id>,
+}
+
+/// Deserialize a value that may be null, using the default value in place of null.
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
 where
-    T: Into<String>,
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
 {
-    fn from(value: T) -> Self {
-        XenonSessionId(value.into())
-    }
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
 }
 
-impl Default for XenonSessionId {
-    fn default() -> Self {
-        Self(uuid::Uuid::new_v4().to_string())
+impl ConnectionResp<String> {
+    /// Get the driver session id, which may be at the top level (legacy) or in the value.
+    fn driver_session_id(&self) -> &str {
+        if self.session_id.is_empty() {
+            &self.value.session_id
+        } else {
+            &self.session_id
+        }
+    }
+
+    /// Switch out the session ids in the response with the one from Xenon.
+    fn with_session_id(self, xsession_id: &XenonSessionId) -> ConnectionResp<XenonSessionId> {
+        ConnectionResp {
+            session_id: xsession_id.clone(),
+            value: ConnectionData {
+                session_id: xsession_id.clone(),
+                capabilities: self.value.capabilities,
+            },
+        }
     }
 }
 
-impl XenonSessionId {
-    pub fn new() -> Self {
-        Self::default()
+/// The upstream WebDriver (or remote node) that requests are sent to.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub scheme: Scheme,
+    pub authority: Authority,
+    /// Bearer token to send with each request, for remote nodes that require auth.
+    pub auth_token: Option<String>,
+    /// The comms_id to send with each request to a remote node.
+    pub comms_id: Option<String>,
+}
+
+impl Upstream {
+    pub fn new(scheme: Scheme, authority: Authority) -> Self {
+        Self {
+            scheme,
+            authority,
+            auth_token: None,
+            comms_id: None,
+        }
     }
 }
 
-impl ToString for XenonSessionId {
-    fn to_string(&self) -> String {
-        self.0.clone()
+/// The time of the last activity on a session. This is shared with any WebSocket
+/// relay tasks, so that messages keep the session alive.
+#[derive(Debug, Clone)]
+struct ActivityTimer {
+    start: Instant,
+    /// Milliseconds from `start` to the last activity.
+    last_ms: Arc<AtomicU64>,
+}
+
+impl ActivityTimer {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_ms: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn touch(&self) {
+        self.last_ms
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.start
+            .elapsed()
+            .saturating_sub(Duration::from_millis(self.last_ms.load(Ordering::Relaxed)))
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ConnectionData {
-    #[serde(default, rename = "sessionId")]
-    session_id: String,
-    #[serde(default)]
-    capabilities: serde_json::Value,
+/// Point the WebSocket URLs in the new session capabilities at Xenon, so that clients
+/// connect through Xenon rather than directly to the WebDriver.
+/// Returns the original URL for each session endpoint.
+fn rewrite_websocket_urls(
+    capabilities: &mut serde_json::Value,
+    websocket_base: Option<&str>,
+    xsession_id: &XenonSessionId,
+) -> HashMap<String, String> {
+    let mut urls = HashMap::new();
+    for (key, endpoint) in WEBSOCKET_CAPABILITIES {
+        let value = match capabilities.get_mut(key) {
+            Some(x) => x,
+            None => continue,
+        };
+        if let Some(url) = value.as_str() {
+            urls.insert(endpoint.to_string(), url.to_string());
+            if let Some(base) = websocket_base {
+                *value = format!("{}/session/{}/{}", base, xsession_id, endpoint).into();
+            }
+        }
+    }
+    urls
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ConnectionResp {
-    #[serde(default, rename = "sessionId")]
-    session_id: String,
-    value: ConnectionData,
+/// Where and how to create a new session.
+pub struct SessionCreate {
+    pub upstream: Upstream,
+    pub client: Client<HttpConnector, Body>,
+    /// The service group for a local session, or None for a remote session.
+    pub service_group: Option<String>,
+    /// The configured browser for a local session.
+    pub browser: Option<BrowserConfig>,
+    /// The local WebDriver process, so that it can be restarted if it crashes.
+    pub process: Option<ProcessHandle>,
+    pub xsession_id: XenonSessionId,
+    /// The base URL that clients use for WebSockets to this server, e.g. `ws://localhost:4444`.
+    pub websocket_base: Option<String>,
 }
 
 /// A Session represents one browser session with one webdriver.
@@ -59,64 +178,106 @@ struct ConnectionResp {
 /// sessions and parallel requests, so the Http client needs to go here
 /// in the session and not on the service. This allows multiple Xenon clients
 /// to make requests to the same webdriver concurrently if needed.
-#[derive(Debug)]
 pub struct Session {
     /// NOTE: This is the internal session id for the target WebDriver session itself.
     /// It starts out as None since it is just a placeholder for a session.
     /// This will be updated once the session actually connects.
     session_id: String,
+    /// The session id exposed to Xenon clients.
+    xsession_id: XenonSessionId,
     /// The service group this session belongs to, or None for a remote session.
     service_group: Option<String>,
-    scheme: Scheme,
-    authority: Authority,
+    upstream: Upstream,
     port: ServicePort,
     client: Client<HttpConnector, Body>,
+    /// The browser name reported by the WebDriver.
+    browser: String,
     // Timestamp of last request, for handling timeouts.
-    last_timestamp: Instant,
+    last_timestamp: ActivityTimer,
+    created_at: DateTime<Utc>,
+    /// The number of requests forwarded to the WebDriver for this session.
+    request_count: u64,
+    /// Optional limit on the number of requests per second for this session.
+    max_requests_per_second: Option<u32>,
+    /// The start of the current one-second rate limiting window.
+    window_start: Instant,
+    /// The number of requests since `window_start`.
+    window_request_count: u32,
+    /// The address of the client that created the session, if known.
+    client_addr: Option<SocketAddr>,
+    /// The WebDriver's own URL for each WebSocket endpoint, e.g. `se/bidi`.
+    websocket_urls: HashMap<String, String>,
+    /// The local WebDriver process and its config, so that it can be restarted if it crashes.
+    /// This is None for remote sessions.
+    driver: Option<(ProcessHandle, BrowserConfig)>,
+}
+
+impl Debug for Session {
+    /// Label both session ids explicitly, so that the Xenon id and the WebDriver id
+    /// are not confused in log output.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("xenon_id", &self.xsession_id.to_string())
+            .field("driver_id", &self.session_id)
+            .field("port", &self.port)
+            .field("group", &self.service_group)
+            .finish()
+    }
 }
 
 impl Session {
     pub async fn create(
-        scheme: Scheme,
-        authority: Authority,
-        service_group: Option<String>,
-        capabilities: &serde_json::Value,
-        desired_capabilities: &serde_json::Value,
-        xsession_id: XenonSessionId,
+        options: SessionCreate,
+        w3c_capabilities: &W3CCapabilities,
     ) -> XenonResult<(Self, Response<Body>)> {
-        let client = Client::new();
+        let SessionCreate {
+            upstream,
+            client,
+            service_group,
+            browser,
+            process,
+            xsession_id,
+            websocket_base,
+        } = options;
+        let browser = browser.as_ref();
+        // Errors include the configured browser (if any) so clients can tell what failed.
+        let config_browser = browser.map(|b| b.name());
+        let create_error = |reason: String| {
+            XenonError::RespondWith(XenonResponse::ErrorCreatingSession(
+                CreateSessionError::new(reason, config_browser),
+            ))
+        };
+
+        let start = Instant::now();
 
         // Wait for port to be ready.
-        let port = match authority.port_u16() {
-            Some(p) => p,
-            None => {
-                return Err(XenonError::RespondWith(
-                    XenonResponse::ErrorCreatingSession("Port not recognised".to_string()),
-                ))
-            }
+        let port = match upstream.authority.port_u16() {
+            Some(p) => ServicePort::from(p),
+            None => return Err(create_error("Port not recognised".to_string())),
         };
         let mut count = 0;
         loop {
-            let status_req = Session::build_request(
-                hyper::Method::GET,
-                &scheme,
-                &authority,
-                "/status",
-                Body::empty(),
-            )?;
+            let status_req =
+                build_proxy_request(hyper::Method::GET, &upstream, "/status", Body::empty())?;
             if let Ok(response) = client.request(status_req).await {
                 if response.status().is_success() {
                     break;
                 }
             }
 
+            // If the WebDriver process has already exited there is no point waiting for it.
+            if let Some(process) = &process {
+                if let Some(status) = process.exit_status().await {
+                    return Err(create_error(format!(
+                        "WebDriver on port {} exited unexpectedly with {}",
+                        port, status
+                    )));
+                }
+            }
+
             count += 1;
             if count > 30 {
-                return Err(XenonError::RespondWith(
-                    XenonResponse::ErrorCreatingSession(
-                        "Timed out waiting for WebDriver".to_string(),
-                    ),
-                ));
+                return Err(create_error("Timed out waiting for WebDriver".to_string()));
             }
 
             debug!(
@@ -126,18 +287,22 @@ impl Session {
             tokio::time::sleep(Duration::new(1, 0)).await;
         }
 
-        // Send capabilities to driver verbatim.
+        // Send capabilities to driver verbatim, apart from any browser-specific
+        // filtering and defaults.
+        let mut capabilities = w3c_capabilities.capabilities.as_value().clone();
+        let mut desired_capabilities = w3c_capabilities.desired_capabilities.clone();
+        if let Some(browser) = browser {
+            browser.filter_capabilities(&mut capabilities, &mut desired_capabilities);
+            browser.apply_default_capabilities(&mut capabilities);
+        }
         let caps = serde_json::json!({
             "capabilities": capabilities,
             "desiredCapabilities": desired_capabilities
         });
-        let body_str = serde_json::to_string(&caps).map_err(|e| {
-            XenonError::RespondWith(XenonResponse::ErrorCreatingSession(e.to_string()))
-        })?;
-        let req_out = Session::build_request(
+        let body_str = serde_json::to_string(&caps).map_err(|e| create_error(e.to_string()))?;
+        let req_out = build_proxy_request(
             hyper::Method::POST,
-            &scheme,
-            &authority,
+            &upstream,
             "/session",
             Body::from(body_str),
         )?;
@@ -147,60 +312,133 @@ impl Session {
             .await
             .map_err(|e| XenonError::RequestError(e.to_string()))?;
         if !response.status().is_success() {
-            return Err(XenonError::ResponsePassThrough(response));
+            return Err(XenonError::ResponsePassThrough(Box::new(response)));
         }
 
         let body_bytes: Bytes = hyper::body::to_bytes(response.body_mut())
             .await
-            .map_err(|e| {
-                XenonError::RespondWith(XenonResponse::ErrorCreatingSession(e.to_string()))
-            })?;
+            .map_err(|e| create_error(e.to_string()))?;
 
         // Deserialize the response into something WebDriver clients will understand.
-        let mut resp: ConnectionResp = serde_json::from_slice(&body_bytes).map_err(|e| {
-            XenonError::RespondWith(XenonResponse::ErrorCreatingSession(e.to_string()))
-        })?;
+        let resp: ConnectionResp<String> =
+            serde_json::from_slice(&body_bytes).map_err(|e| create_error(e.to_string()))?;
 
-        let session_id = if resp.session_id.is_empty() {
-            resp.value.session_id
-        } else {
-            resp.session_id
+        let session_id = resp.driver_session_id().to_string();
+        if session_id.is_empty() {
+            return Err(create_error(
+                "WebDriver response did not contain a session id".to_string(),
+            ));
+        }
+        let browser_name = match resp.value.capabilities["browserName"].as_str() {
+            Some(name) => name.to_string(),
+            None => config_browser.unwrap_or_default().to_string(),
         };
+        let mut resp = resp.with_session_id(&xsession_id);
+        let websocket_urls = rewrite_websocket_urls(
+            &mut resp.value.capabilities,
+            websocket_base.as_deref(),
+            &xsession_id,
+        );
 
-        // Switch out the session ids in the response with the one from Xenon.
-        resp.session_id = xsession_id.to_string();
-        resp.value.session_id = xsession_id.to_string();
-
-        let bytes_out = serde_json::to_vec(&resp).map_err(|e| {
-            XenonError::RespondWith(XenonResponse::ErrorCreatingSession(e.to_string()))
-        })?;
+        let bytes_out = serde_json::to_vec(&resp).map_err(|e| create_error(e.to_string()))?;
 
         let resp_out = Response::builder()
             .status(response.status())
             .header("Content-Type", "application/json")
+            .header("X-Xenon-Session-Id", xsession_id.to_string())
             .body(Body::from(bytes_out))
-            .map_err(|e| {
-                XenonError::RespondWith(XenonResponse::ErrorCreatingSession(e.to_string()))
-            })?;
+            .map_err(|e| create_error(e.to_string()))?;
 
+        metrics::observe_session_create_time(start.elapsed());
+        let max_requests_per_second = browser.and_then(|b| b.max_requests_per_second());
+        let driver = process.zip(browser.cloned());
         Ok((
             Self {
                 session_id,
+                xsession_id,
                 service_group,
-                scheme,
-                authority,
+                upstream,
                 port,
                 client,
-                last_timestamp: Instant::now(),
+                browser: browser_name,
+                last_timestamp: ActivityTimer::new(),
+                created_at: Utc::now(),
+                request_count: 0,
+                max_requests_per_second,
+                window_start: Instant::now(),
+                window_request_count: 0,
+                client_addr: None,
+                websocket_urls,
+                driver,
             },
             resp_out,
         ))
     }
 
+    /// Create a Session for an existing WebDriver session that was not created via Xenon.
+    pub fn adopt(
+        upstream: Upstream,
+        client: Client<HttpConnector, Body>,
+        service_group: Option<String>,
+        session_id: String,
+        xsession_id: XenonSessionId,
+    ) -> XenonResult<Self> {
+        let port = upstream.authority.port_u16().ok_or_else(|| {
+            XenonError::RespondWith(XenonResponse::InvalidRequest(format!(
+                "No port specified in authority '{}'",
+                upstream.authority
+            )))
+        })?;
+        let port = ServicePort::from(port);
+
+        Ok(Self {
+            session_id,
+            xsession_id,
+            // The browser is unknown, but the group is named after the browser.
+            browser: service_group.clone().unwrap_or_default(),
+            service_group,
+            upstream,
+            port,
+            client,
+            last_timestamp: ActivityTimer::new(),
+            created_at: Utc::now(),
+            request_count: 0,
+            max_requests_per_second: None,
+            window_start: Instant::now(),
+            window_request_count: 0,
+            client_addr: None,
+            websocket_urls: HashMap::new(),
+            driver: None,
+        })
+    }
+
+    pub fn xsession_id(&self) -> &XenonSessionId {
+        &self.xsession_id
+    }
+
+    /// The session id used by the WebDriver itself.
+    pub fn driver_session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    pub fn browser(&self) -> &str {
+        &self.browser
+    }
+
     pub fn port(&self) -> ServicePort {
         self.port
     }
 
+    /// The scheme used to connect to the upstream WebDriver (or node).
+    pub fn scheme(&self) -> &Scheme {
+        &self.upstream.scheme
+    }
+
+    /// The address of the upstream WebDriver (or node).
+    pub fn authority(&self) -> &Authority {
+        &self.upstream.authority
+    }
+
     pub fn service_group(&self) -> &Option<String> {
         &self.service_group
     }
@@ -209,26 +447,42 @@ impl Session {
         self.last_timestamp.elapsed().as_secs()
     }
 
-    pub fn build_request(
-        method: hyper::Method,
-        scheme: &Scheme,
-        authority: &Authority,
-        path: &str,
-        body: Body,
-    ) -> XenonResult<Request<Body>> {
-        let uri_out = hyper::Uri::builder()
-            .scheme(scheme.clone())
-            .authority(authority.clone())
-            .path_and_query(path)
-            .build()
-            .map_err(|e| XenonError::RequestError(e.to_string()))?;
+    /// Reset the inactivity timer without sending a request to the WebDriver.
+    pub fn refresh_timeout(&mut self) {
+        self.last_timestamp.touch();
+    }
 
-        let req_out = Request::builder()
-            .method(method)
-            .uri(uri_out)
-            .body(body)
-            .map_err(|e| XenonError::RequestError(e.to_string()))?;
-        Ok(req_out)
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn request_count(&self) -> u64 {
+        self.request_count
+    }
+
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.client_addr
+    }
+
+    pub fn set_client_addr(&mut self, addr: SocketAddr) {
+        self.client_addr = Some(addr);
+    }
+
+    /// Count a request against the per-session rate limit, if any.
+    fn check_rate_limit(&mut self) -> XenonResult<()> {
+        let limit = match self.max_requests_per_second {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.window_request_count = 0;
+        }
+        if self.window_request_count >= limit {
+            return Err(XenonError::RespondWith(XenonResponse::RateLimitExceeded(1)));
+        }
+        self.window_request_count += 1;
+        Ok(())
     }
 
     pub async fn forward_request(
@@ -236,29 +490,444 @@ impl Session {
         req: Request<Body>,
         endpoint: &str,
     ) -> XenonResult<Response<Body>> {
-        self.last_timestamp = Instant::now();
+        // Deleting the session is never throttled, so that a throttled client can
+        // still end its session rather than leaving it to time out.
+        if !(endpoint.is_empty() && req.method() == hyper::Method::DELETE) {
+            self.check_rate_limit()?;
+        }
+        self.last_timestamp.touch();
+        self.request_count += 1;
 
         // Substitute the uri and send the request again...
+        let path_and_query = self.upstream_path(req.uri(), endpoint);
+        let method = req.method().clone();
+        let original_path = req.uri().path().to_string();
+        let (parts, body) = req.into_parts();
+        let generation = self
+            .driver
+            .as_ref()
+            .map(|(process, _)| process.generation());
+        // For local sessions, the body is buffered so that the request can be sent again
+        // after a restart. Requests to nodes are never resent, so they are streamed.
+        let (body_out, body) = match generation {
+            Some(_) => {
+                let bytes = hyper::body::to_bytes(body)
+                    .await
+                    .map_err(|e| XenonError::RequestError(e.to_string()))?;
+                (Body::from(bytes.clone()), bytes)
+            }
+            None => (body, Bytes::new()),
+        };
+        let mut req_out =
+            build_proxy_request(method.clone(), &self.upstream, &path_and_query, body_out)?;
+        copy_forwarded_headers(&parts.headers, req_out.headers_mut());
+        trace!("Forward {} {} -> {}", method, original_path, req_out.uri());
+        match (self.client.request(req_out).await, generation) {
+            (Ok(response), _) => Ok(response),
+            (Err(e), Some(generation)) if e.is_connect() => {
+                // If the WebDriver has crashed, restart it. The new WebDriver will not know
+                // this session, but this lets the client get a WebDriver error rather than a
+                // connection error, and the service can be used for new sessions.
+                warn!(
+                    "Unable to connect to WebDriver on port {}: {}",
+                    self.port, e
+                );
+                if !self.restart_driver(generation).await? {
+                    // The WebDriver is still running, so it may be busy with other sessions.
+                    return Err(XenonError::RequestError(e.to_string()));
+                }
+                let mut req_out =
+                    build_proxy_request(method, &self.upstream, &path_and_query, Body::from(body))?;
+                copy_forwarded_headers(&parts.headers, req_out.headers_mut());
+                self.client
+                    .request(req_out)
+                    .await
+                    .map_err(|e| XenonError::RequestError(e.to_string()))
+            }
+            (Err(e), _) => Err(XenonError::RequestError(e.to_string())),
+        }
+    }
+
+    /// Forward a WebSocket upgrade request (e.g. for BiDi or CDP) to the WebDriver.
+    ///
+    /// The WebSocket to the WebDriver is connected first, so that a failure is returned
+    /// to the client as a normal error. Messages are then passed through in both
+    /// directions until either side closes the connection.
+    pub async fn forward_websocket(
+        &mut self,
+        req: Request<Body>,
+        endpoint: &str,
+    ) -> XenonResult<Response<Body>> {
+        self.check_rate_limit()?;
+        self.last_timestamp.touch();
+        self.request_count += 1;
+
+        let accept_key = match req.headers().get(hyper::header::SEC_WEBSOCKET_KEY) {
+            Some(key) => derive_accept_key(key.as_bytes()),
+            None => {
+                return Err(XenonError::RespondWith(XenonResponse::InvalidRequest(
+                    "Missing Sec-WebSocket-Key header".to_string(),
+                )))
+            }
+        };
+        // Connect to the URL the WebDriver gave for this endpoint when the session was
+        // created, if any. Otherwise, use the same path on the WebDriver.
+        let url = match self.websocket_urls.get(endpoint) {
+            Some(url) => match req.uri().query() {
+                Some(q) => format!("{}?{}", url, q),
+                None => url.clone(),
+            },
+            None => format!(
+                "ws://{}{}",
+                self.upstream.authority,
+                self.upstream_path(req.uri(), endpoint)
+            ),
+        };
+        if self.upstream.scheme == Scheme::HTTPS || url.starts_with("wss:") {
+            return Err(XenonError::RequestError(format!(
+                "WebSocket connections over TLS are not supported: {}",
+                url
+            )));
+        }
+        let mut req_out = url
+            .as_str()
+            .into_client_request()
+            .map_err(|e| XenonError::RequestError(e.to_string()))?;
+        if let Some(token) = &self.upstream.auth_token {
+            let value = format!("Bearer {}", token)
+                .parse()
+                .map_err(|_| XenonError::RequestError("Invalid auth token".to_string()))?;
+            req_out
+                .headers_mut()
+                .insert(hyper::header::AUTHORIZATION.as_str(), value);
+        }
+        trace!("Forward WebSocket {} -> {}", req.uri().path(), url);
+        let (driver_ws, _) = tokio_tungstenite::connect_async(req_out)
+            .await
+            .map_err(|e| XenonError::RequestError(e.to_string()))?;
+
+        // The client connection is only upgraded once the response below has been sent.
+        // Messages in either direction count as activity on the session.
+        let xsession_id = self.xsession_id.clone();
+        let client_activity = self.last_timestamp.clone();
+        let driver_activity = self.last_timestamp.clone();
+        tokio::spawn(async move {
+            let upgraded = match hyper::upgrade::on(req).await {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("WebSocket upgrade failed for {:?}: {}", xsession_id, e);
+                    return;
+                }
+            };
+            let client_ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+            let (client_tx, client_rx) = client_ws.split();
+            let (driver_tx, driver_rx) = driver_ws.split();
+            let client_rx = client_rx.inspect(move |_| client_activity.touch());
+            let driver_rx = driver_rx.inspect(move |_| driver_activity.touch());
+            tokio::select! {
+                _ = client_rx.forward(driver_tx) => {},
+                _ = driver_rx.forward(client_tx) => {},
+            }
+            debug!("WebSocket closed for {:?}", xsession_id);
+        });
+
+        Response::builder()
+            .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
+            .header(hyper::header::CONNECTION, "Upgrade")
+            .header(hyper::header::UPGRADE, "websocket")
+            .header(hyper::header::SEC_WEBSOCKET_ACCEPT, accept_key)
+            .body(Body::empty())
+            .map_err(|e| XenonError::RespondWith(XenonResponse::InternalServerError(e.to_string())))
+    }
+
+    /// The path (and query) on the WebDriver for a request to the specified session endpoint.
+    fn upstream_path(&self, uri: &hyper::Uri, endpoint: &str) -> String {
         let mut path_and_query = if endpoint.is_empty() {
             format!("/session/{}", self.session_id)
         } else {
             format!("/session/{}/{}", self.session_id, endpoint)
         };
-
-        if let Some(q) = req.uri().query() {
+        if let Some(q) = uri.query() {
             path_and_query += "?";
             path_and_query += q;
         }
-        let req_out = Session::build_request(
-            req.method().clone(),
-            &self.scheme,
-            &self.authority,
-            &path_and_query,
-            req.into_body(),
-        )?;
-        self.client
+        path_and_query
+    }
+
+    /// Delete the session on the upstream WebDriver (or node), e.g. after it timed out.
+    pub async fn delete_remote_session(&self) -> XenonResult<()> {
+        let path = format!("/session/{}", self.session_id);
+        let req_out =
+            build_proxy_request(hyper::Method::DELETE, &self.upstream, &path, Body::empty())?;
+        let response = self
+            .client
             .request(req_out)
             .await
-            .map_err(|e| XenonError::RequestError(e.to_string()))
+            .map_err(|e| XenonError::RequestError(e.to_string()))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(XenonError::RequestError(format!(
+                "DELETE {} returned status {}",
+                path,
+                response.status()
+            )))
+        }
+    }
+
+    /// Restart the local WebDriver process if it has exited, and wait for it to be ready.
+    /// If another session already restarted it since `generation`, only wait for it to
+    /// be ready. Returns false if the process is still running.
+    async fn restart_driver(&self, generation: u64) -> XenonResult<bool> {
+        let (process, browser) = match &self.driver {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+        if !process
+            .restart(generation, self.port, browser.driver_path(), browser.args())
+            .await?
+        {
+            return Ok(false);
+        }
+
+        for _ in 0..DRIVER_RESTART_ATTEMPTS {
+            let status_req =
+                build_proxy_request(hyper::Method::GET, &self.upstream, "/status", Body::empty())?;
+            if let Ok(response) = self.client.request(status_req).await {
+                if response.status().is_success() {
+                    return Ok(true);
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        Err(XenonError::RequestError(format!(
+            "WebDriver on port {} did not become ready after restart",
+            self.port
+        )))
+    }
+}
+
+/// Information about a session, as returned by Xenon endpoints.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub xenon_session_id: XenonSessionId,
+    /// The session id used by the downstream WebDriver or node.
+    pub session_id: String,
+    pub browser: String,
+    pub port: ServicePort,
+    /// The service group for local sessions, or `remote` for sessions on a node.
+    pub service_group: String,
+    pub upstream_url: String,
+    pub created_at: String,
+    pub seconds_since_last_request: u64,
+    pub request_count: u64,
+    pub client_ip: Option<SocketAddr>,
+}
+
+impl From<(&Session, &XenonSessionId)> for SessionInfo {
+    fn from((session, xsession_id): (&Session, &XenonSessionId)) -> Self {
+        Self {
+            xenon_session_id: xsession_id.clone(),
+            session_id: session.driver_session_id().to_string(),
+            browser: session.browser().to_string(),
+            port: session.port(),
+            service_group: session
+                .service_group()
+                .clone()
+                .unwrap_or_else(|| "remote".to_string()),
+            upstream_url: format!("{}://{}", session.scheme(), session.authority()),
+            created_at: session.created_at().to_rfc3339(),
+            seconds_since_last_request: session.seconds_since_last_request(),
+            request_count: session.request_count(),
+            client_ip: session.client_addr(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::XenonError;
+    use crate::response::XenonResponse;
+    use crate::session::{
+        rewrite_websocket_urls, ActivityTimer, ConnectionResp, Session, Upstream, XenonSessionId,
+    };
+    use hyper::http::uri::{Authority, Scheme};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Client, Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_rewrite_websocket_urls() {
+        let xsession_id = XenonSessionId::from("xenon-session");
+        let mut capabilities = serde_json::json!({
+            "browserName": "chrome",
+            "webSocketUrl": "ws://127.0.0.1:9515/session/driver-session",
+            "se:cdp": "ws://127.0.0.1:9222/devtools/browser/abc"
+        });
+        let urls = rewrite_websocket_urls(
+            &mut capabilities,
+            Some("ws://xenon:4444/selenium"),
+            &xsession_id,
+        );
+        assert_eq!(
+            capabilities["webSocketUrl"],
+            "ws://xenon:4444/selenium/session/xenon-session/se/bidi"
+        );
+        assert_eq!(
+            capabilities["se:cdp"],
+            "ws://xenon:4444/selenium/session/xenon-session/se/cdp"
+        );
+        assert_eq!(capabilities["browserName"], "chrome");
+        assert_eq!(
+            urls["se/bidi"],
+            "ws://127.0.0.1:9515/session/driver-session"
+        );
+        assert_eq!(urls["se/cdp"], "ws://127.0.0.1:9222/devtools/browser/abc");
+
+        // The requested `webSocketUrl: true` is not a URL.
+        let mut capabilities = serde_json::json!({"webSocketUrl": true});
+        let urls = rewrite_websocket_urls(&mut capabilities, Some("ws://xenon:4444"), &xsession_id);
+        assert!(urls.is_empty());
+        assert_eq!(capabilities["webSocketUrl"], true);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_activity_timer() {
+        let timer = ActivityTimer::new();
+        let shared = timer.clone();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(timer.elapsed().as_secs(), 10);
+        shared.touch();
+        assert_eq!(timer.elapsed().as_secs(), 0);
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert_eq!(timer.elapsed().as_secs(), 3);
+    }
+
+    #[test]
+    fn test_connection_resp_session_id() {
+        let resp: ConnectionResp<String> = serde_json::from_str(
+            r#"{"value": {"sessionId": "driver-id", "capabilities": {"browserName": "chrome"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(resp.driver_session_id(), "driver-id");
+
+        let xsession_id = XenonSessionId::from("xenon-id");
+        let value = serde_json::to_value(resp.with_session_id(&xsession_id)).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "sessionId": "xenon-id",
+                "value": {"sessionId": "xenon-id", "capabilities": {"browserName": "chrome"}}
+            })
+        );
+    }
+
+    #[test]
+    fn test_connection_resp_null_value() {
+        let resp: ConnectionResp<String> =
+            serde_json::from_str(r#"{"status": 500, "value": null}"#).unwrap();
+        assert_eq!(resp.driver_session_id(), "");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit() {
+        let mut session = Session::adopt(
+            Upstream::new(Scheme::HTTP, "localhost:5000".parse().unwrap()),
+            Client::new(),
+            None,
+            "driver-session".to_string(),
+            XenonSessionId::from("xenon-session"),
+        )
+        .unwrap();
+        for _ in 0..10 {
+            assert!(session.check_rate_limit().is_ok());
+        }
+
+        session.max_requests_per_second = Some(2);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(session.check_rate_limit().is_ok());
+        assert!(session.check_rate_limit().is_ok());
+        assert!(matches!(
+            session.check_rate_limit(),
+            Err(XenonError::RespondWith(XenonResponse::RateLimitExceeded(1)))
+        ));
+
+        tokio::time::advance(Duration::from_millis(999)).await;
+        assert!(session.check_rate_limit().is_err());
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(session.check_rate_limit().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_allows_delete() {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::new(Body::empty()))
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let authority: Authority = server.local_addr().to_string().parse().unwrap();
+        tokio::spawn(server);
+
+        let mut session = Session::adopt(
+            Upstream::new(Scheme::HTTP, authority),
+            Client::new(),
+            None,
+            "driver-session".to_string(),
+            XenonSessionId::from("xenon-session"),
+        )
+        .unwrap();
+        session.max_requests_per_second = Some(1);
+        let request = |method: hyper::Method| Request::builder().method(method).body(Body::empty());
+        assert!(session
+            .forward_request(request(hyper::Method::GET).unwrap(), "url")
+            .await
+            .is_ok());
+        assert!(matches!(
+            session
+                .forward_request(request(hyper::Method::GET).unwrap(), "url")
+                .await,
+            Err(XenonError::RespondWith(XenonResponse::RateLimitExceeded(1)))
+        ));
+        let response = session
+            .forward_request(request(hyper::Method::DELETE).unwrap(), "")
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn test_delete_remote_session() {
+        // A node that only knows about the session "node-session".
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let status = match (req.method(), req.uri().path()) {
+                    (&hyper::Method::DELETE, "/session/node-session") => StatusCode::OK,
+                    _ => StatusCode::NOT_FOUND,
+                };
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let authority: Authority = server.local_addr().to_string().parse().unwrap();
+        tokio::spawn(server);
+
+        for (driver_session_id, success) in [("node-session", true), ("other", false)] {
+            let session = Session::adopt(
+                Upstream::new(Scheme::HTTP, authority.clone()),
+                Client::new(),
+                None,
+                driver_session_id.to_string(),
+                XenonSessionId::from("xenon-session"),
+            )
+            .unwrap();
+            assert_eq!(session.delete_remote_session().await.is_ok(), success);
+        }
     }
 }
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// The session id exposed to Xenon clients. This serializes as a plain string.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct XenonSessionId(String);
+
+impl<T> From<T> for XenonSessionId
+where
+    T: Into<String>,
+{
+    fn from(value: T) -> Self {
+        XenonSessionId(value.into())
+    }
+}
+
+impl Default for XenonSessionId {
+    fn default() -> Self {
+        Self(uuid::Uuid::new_v4().to_string())
+    }
+}
+
+impl XenonSessionId {
+    /// Generate a new session id, with an optional prefix.
+    pub fn new(prefix: Option<&str>) -> Self {
+        match prefix {
+            Some(prefix) => Self(format!("{}-{}", prefix, uuid::Uuid::new_v4())),
+            None => Self::default(),
+        }
+    }
+}
+
+impl Display for XenonSessionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::XenonSessionId;
+
+    #[test]
+    fn test_session_id_serde() {
+        let id = XenonSessionId::new(Some("xenon"));
+        assert!(id.to_string().starts_with("xenon-"));
+        let value = serde_json::to_value(&id).unwrap();
+        assert_eq!(value, serde_json::json!(id.to_string()));
+        let id2: XenonSessionId = serde_json::from_value(value).unwrap();
+        assert_eq!(id, id2);
+    }
+}
@@ -1,14 +1,74 @@
+use crate::access_log::AccessLogger;
+use crate::browser::DEFAULT_SESSION_TIMEOUT_SECS;
 use crate::config::XenonConfig;
-use crate::error::XenonResult;
-use crate::nodes::{NodeId, RemoteNode};
+use crate::error::{XenonError, XenonResult};
+use crate::nodes::{NodeHealthStatus, NodeId, RemoteNode};
 use crate::portmanager::PortManager;
+use crate::ratelimit::RateLimiter;
+use crate::response::XenonResponse;
 use crate::service::{ServiceGroup, ServiceGroupName};
-use crate::session::{Session, XenonSessionId};
+use crate::session::{Session, Upstream, XenonSessionId};
+use dashmap::DashMap;
+use hyper::client::HttpConnector;
+use hyper::http::uri::{Authority, Scheme};
+use hyper::{Body, Client};
 use indexmap::map::IndexMap;
+use log::*;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::{Mutex, RwLock};
 
+/// Create the service groups and remote nodes from the config. Nothing is started,
+/// so this is also used by `--dry-run` to check the browsers and nodes.
+pub fn groups_and_nodes(
+    config: XenonConfig,
+) -> XenonResult<(
+    IndexMap<ServiceGroupName, ServiceGroup>,
+    IndexMap<NodeId, RemoteNode>,
+)> {
+    let mut service_groups = IndexMap::new();
+    let (browsers, node_data_list) = config.browsers_and_nodes();
+    for browser in browsers {
+        let group = ServiceGroup::new(browser);
+        if service_groups.contains_key(group.name()) {
+            return Err(XenonError::ConfigValidation(format!(
+                "Duplicate browser name and version: {}",
+                group.name()
+            )));
+        }
+        service_groups.insert(group.name().to_string(), group);
+    }
+    let mut nodes = IndexMap::new();
+    for node_data in node_data_list {
+        let node = RemoteNode::new(node_data)?;
+        nodes.insert(node.id(), node);
+    }
+    Ok((service_groups, nodes))
+}
+
+/// Acquire a write lock on `XenonState`, logging a summary of the state at debug level.
+/// The operation name is included in the log to show which code path took the lock.
+macro_rules! state_write {
+    ($state:expr, $op:expr) => {{
+        // The summary is taken before the write-lock, so that the write-lock is never
+        // held while waiting for the service groups or remote nodes.
+        let summary = if log::log_enabled!(log::Level::Debug) {
+            Some($state.read().await.summary().await)
+        } else {
+            None
+        };
+        let s = $state.write().await;
+        if let Some(summary) = summary {
+            log::debug!("State write-lock acquired for {} :: {}", $op, summary);
+        }
+        s
+    }};
+}
+pub(crate) use state_write;
+
 #[derive(Debug)]
 pub struct XenonState {
     // The service groups and port manager are each wrapped in Arc so that they
@@ -37,31 +97,178 @@ pub struct XenonState {
     // Remote nodes will be queried only when local service groups cannot service
     // a new session request.
     remote_nodes: Arc<RwLock<IndexMap<NodeId, RemoteNode>>>,
+
+    // The current config. Settings that can change at runtime are read from here
+    // rather than being copied into the state, so that a reloaded config can be
+    // compared against it and applied.
+    config: Arc<RwLock<XenonConfig>>,
+
+    // Per-client rate limiting, if configured.
+    rate_limiter: Option<RateLimiter>,
+
+    // HTTP clients are shared by all sessions with the same upstream, so that
+    // each upstream only has one connection pool.
+    clients: Arc<DashMap<(Scheme, Authority), Client<HttpConnector, Body>>>,
+
+    // Optional bearer token that clients must send.
+    auth_token: Option<String>,
+
+    // Whether clients connect to this server over TLS.
+    tls: bool,
+
+    // Optional cap on the total number of sessions across all service groups and nodes.
+    max_sessions: Option<usize>,
+
+    // Slots reserved under max_sessions for sessions that are still being created.
+    pending_sessions: AtomicUsize,
+
+    // Optional access log, separate from the main log.
+    access_logger: Option<Arc<AccessLogger>>,
+
+    // Set once shutdown has started. No new sessions are created after this.
+    is_draining: AtomicBool,
+
+    // Running totals since startup. These are never reset.
+    sessions_created_total: Arc<AtomicU64>,
+    sessions_deleted_total: Arc<AtomicU64>,
+    sessions_timed_out_total: Arc<AtomicU64>,
+}
+
+/// The number of sessions created, deleted by clients, and deleted due to
+/// inactivity since Xenon started.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct SessionTotals {
+    pub created: u64,
+    pub deleted: u64,
+    pub timed_out: u64,
 }
 
 impl XenonState {
-    pub fn new(config: XenonConfig) -> XenonResult<Self> {
-        let port_manager = PortManager::new(&config);
-        let mut service_groups = IndexMap::new();
-        let (browsers, node_data_list) = config.browsers_and_nodes();
-        for browser in browsers {
-            let group = ServiceGroup::new(browser);
-            service_groups.insert(group.name().to_string(), group);
-        }
-        let mut nodes = IndexMap::new();
-        for node_data in node_data_list {
-            let node = RemoteNode::new(node_data)?;
-            nodes.insert(node.id(), node);
+    /// Create the state from the config, and start a WebDriver for each browser
+    /// with `pre_warm` set.
+    pub async fn new(config: XenonConfig) -> XenonResult<Self> {
+        let mut port_manager = PortManager::new(&config);
+        let rate_limiter = config.rate_limit().cloned().map(RateLimiter::new);
+        let config_stored = Arc::new(RwLock::new(config.clone()));
+        let (mut service_groups, nodes) = groups_and_nodes(config)?;
+        for (name, group) in service_groups.iter_mut() {
+            if !group.browser.pre_warm() {
+                continue;
+            }
+            // A failure here is not fatal, since the WebDriver will be started
+            // again when the first session is requested.
+            match group.get_or_start_service(&mut port_manager).await {
+                Ok(service) => info!(
+                    "Pre-warmed WebDriver for group '{}' on port {}",
+                    name,
+                    service.port()
+                ),
+                Err(e) => warn!("Failed to pre-warm WebDriver for group '{}': {}", name, e),
+            }
         }
-
         Ok(Self {
             service_groups: Arc::new(RwLock::new(service_groups)),
             port_manager: Arc::new(RwLock::new(port_manager)),
             sessions: HashMap::new(),
             remote_nodes: Arc::new(RwLock::new(nodes)),
+            config: config_stored,
+            rate_limiter,
+            clients: Arc::new(DashMap::new()),
+            auth_token: None,
+            tls: false,
+            max_sessions: None,
+            pending_sessions: AtomicUsize::new(0),
+            access_logger: None,
+            is_draining: AtomicBool::new(false),
+            sessions_created_total: Arc::new(AtomicU64::new(0)),
+            sessions_deleted_total: Arc::new(AtomicU64::new(0)),
+            sessions_timed_out_total: Arc::new(AtomicU64::new(0)),
         })
     }
 
+    /// Stop accepting new sessions. Existing sessions are unaffected, but requests
+    /// waiting in a session queue fail straight away.
+    pub async fn start_draining(&self) {
+        self.is_draining.store(true, Ordering::SeqCst);
+        for group in self.service_groups.read().await.values() {
+            group.queue().close();
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.is_draining.load(Ordering::SeqCst)
+    }
+
+    /// Require all clients to send this bearer token (except for GET /status).
+    pub fn set_auth_token(&mut self, token: Option<String>) {
+        self.auth_token = token;
+    }
+
+    pub fn auth_token(&self) -> Option<&str> {
+        self.auth_token.as_deref()
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, tls: bool) {
+        self.tls = tls;
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+
+    pub fn set_access_logger(&mut self, logger: AccessLogger) {
+        self.access_logger = Some(Arc::new(logger));
+    }
+
+    pub fn access_logger(&self) -> Option<Arc<AccessLogger>> {
+        self.access_logger.clone()
+    }
+
+    pub fn set_max_sessions(&mut self, max_sessions: Option<usize>) {
+        self.max_sessions = max_sessions;
+    }
+
+    /// Reserve a slot under the global session cap for a session that is about to be
+    /// created. Returns false if the cap has been reached. Every session, including
+    /// sessions on nodes and those adopted via `/admin/session`, counts towards the cap.
+    ///
+    /// The slot must be given back with `add_reserved_session()` once the session has
+    /// been created, or with `release_session_slot()` if it could not be.
+    pub fn try_reserve_session_slot(&self) -> bool {
+        // The number of sessions cannot change while this read-only borrow is held,
+        // so only the pending count needs to be updated atomically.
+        let active = self.sessions.len();
+        self.pending_sessions
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+                match self.max_sessions {
+                    Some(max_sessions) if active + pending >= max_sessions => None,
+                    _ => Some(pending + 1),
+                }
+            })
+            .is_ok()
+    }
+
+    /// The number of sessions that can still be created under the global session cap,
+    /// or `None` if there is no cap.
+    pub fn remaining_session_slots(&self) -> Option<usize> {
+        let pending = self.pending_sessions.load(Ordering::SeqCst);
+        self.max_sessions
+            .map(|max_sessions| max_sessions.saturating_sub(self.sessions.len() + pending))
+    }
+
+    /// Give back a slot reserved with `try_reserve_session_slot()` without adding a session.
+    pub fn release_session_slot(&self) {
+        self.pending_sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Add a session that was reserved with `try_reserve_session_slot()`.
+    /// The session takes over the slot, so the count never drops in between.
+    pub fn add_reserved_session(&mut self, session_id: XenonSessionId, session: Session) {
+        self.add_session(session_id, session);
+        self.release_session_slot();
+    }
+
     pub fn port_manager(&self) -> Arc<RwLock<PortManager>> {
         self.port_manager.clone()
     }
@@ -74,28 +281,346 @@ impl XenonState {
         self.remote_nodes.clone()
     }
 
+    pub fn config(&self) -> Arc<RwLock<XenonConfig>> {
+        self.config.clone()
+    }
+
+    /// Get the shared HTTP client for the specified upstream.
+    pub fn client(&self, upstream: &Upstream) -> Client<HttpConnector, Body> {
+        self.clients
+            .entry((upstream.scheme.clone(), upstream.authority.clone()))
+            .or_default()
+            .clone()
+    }
+
+    /// Generate a new session id using the configured prefix.
+    pub async fn new_session_id(&self) -> XenonSessionId {
+        XenonSessionId::new(self.config.read().await.session_id_prefix())
+    }
+
+    /// Check whether the client at the specified address has exceeded the rate limit.
+    pub fn check_rate_limit(&self, ip: IpAddr) -> XenonResult<()> {
+        match &self.rate_limiter {
+            Some(rate_limiter) => rate_limiter.check(ip).map_err(|wait| {
+                XenonError::RespondWith(XenonResponse::RateLimitExceeded(
+                    wait.as_secs_f64().ceil() as u64
+                ))
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Discard rate limit state for clients that are no longer limited.
+    pub fn evict_idle_rate_limits(&self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.evict_full_buckets();
+        }
+    }
+
     pub fn get_session(&self, session_id: &XenonSessionId) -> Option<Arc<Mutex<Session>>> {
         self.sessions.get(session_id).cloned()
     }
 
     pub fn add_session(&mut self, session_id: XenonSessionId, session: Session) {
+        // This would be a programming error, so fail loudly.
+        assert_eq!(
+            &session_id,
+            session.xsession_id(),
+            "Session added with mismatched session id"
+        );
         self.sessions
             .insert(session_id, Arc::new(Mutex::new(session)));
+        self.sessions_created_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sessions(&self) -> Vec<Arc<Mutex<Session>>> {
+        self.sessions.values().cloned().collect()
+    }
+
+    pub fn session_ids(&self) -> Vec<XenonSessionId> {
+        self.sessions.keys().cloned().collect()
+    }
+
+    pub fn num_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn has_session(&self, session_id: &XenonSessionId) -> bool {
+        self.sessions.contains_key(session_id)
     }
 
     pub fn delete_session(&mut self, session_id: &XenonSessionId) -> Option<Arc<Mutex<Session>>> {
-        self.sessions.remove(session_id)
+        let session = self.sessions.remove(session_id);
+        if session.is_some() {
+            self.sessions_deleted_total.fetch_add(1, Ordering::Relaxed);
+        }
+        session
+    }
+
+    /// Remove a session that has timed out.
+    pub fn expire_session(&mut self, session_id: &XenonSessionId) -> Option<Arc<Mutex<Session>>> {
+        let session = self.sessions.remove(session_id);
+        if session.is_some() {
+            self.sessions_timed_out_total
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        session
+    }
+
+    pub fn session_totals(&self) -> SessionTotals {
+        SessionTotals {
+            created: self.sessions_created_total.load(Ordering::Relaxed),
+            deleted: self.sessions_deleted_total.load(Ordering::Relaxed),
+            timed_out: self.sessions_timed_out_total.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Free the node capacity used by a remote session.
+    pub async fn release_node_session(&self, xsession_id: &XenonSessionId) {
+        for node in self.remote_nodes.write().await.values_mut() {
+            node.remove_session(xsession_id);
+        }
+        // This may free a slot under the global session cap, which queued requests
+        // for any group could be waiting for.
+        for group in self.service_groups.read().await.values() {
+            group.queue().notify();
+        }
+    }
+
+    /// Get the health status of every remote node.
+    pub async fn node_health_summary(&self) -> Vec<NodeHealthStatus> {
+        self.remote_nodes
+            .read()
+            .await
+            .values()
+            .map(|node| node.health())
+            .collect()
+    }
+
+    /// Get a short summary of the state, for debug logging.
+    /// This takes read locks on the service groups and remote nodes.
+    pub async fn summary(&self) -> String {
+        let services: Vec<String> = self
+            .service_groups
+            .read()
+            .await
+            .iter()
+            .map(|(name, group)| format!("{}={}", name, group.num_services()))
+            .collect();
+        let num_nodes = self.remote_nodes.read().await.len();
+        format!(
+            "sessions: {}, services: [{}], nodes: {}",
+            self.sessions.len(),
+            services.join(", "),
+            num_nodes
+        )
     }
 
+    /// Get the ids of all sessions that have not received a request recently.
+    /// The timeout depends on the browser config for the session's service group.
+    /// Remote sessions use the default timeout.
+    /// This never waits on a session lock. A session that is currently locked is
+    /// in the middle of a request, so it cannot have timed out.
     pub async fn get_timeout_sessions(&self) -> Vec<XenonSessionId> {
-        let mut ids = Vec::new();
-        for (xsession_id, mutex_session) in self.sessions.iter() {
-            let session = mutex_session.lock().await;
-            // Timeout after 30 mins.
-            if session.seconds_since_last_request() > 1800 {
-                ids.push(xsession_id.clone());
-            }
+        let timeouts: HashMap<ServiceGroupName, u64> = self
+            .service_groups
+            .read()
+            .await
+            .iter()
+            .map(|(name, group)| (name.clone(), group.browser.session_timeout_secs()))
+            .collect();
+
+        self.sessions
+            .iter()
+            .filter(|(_, mutex_session)| match mutex_session.try_lock() {
+                Ok(session) => {
+                    let timeout = session
+                        .service_group()
+                        .as_ref()
+                        .and_then(|name| timeouts.get(name).copied())
+                        .unwrap_or(DEFAULT_SESSION_TIMEOUT_SECS);
+                    session.seconds_since_last_request() > timeout
+                }
+                Err(_) => false,
+            })
+            .map(|(xsession_id, _)| xsession_id.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::XenonConfig;
+    use crate::error::XenonError;
+    use crate::session::{Session, Upstream, XenonSessionId};
+    use crate::state::{groups_and_nodes, XenonState};
+    use hyper::http::uri::Scheme;
+    use hyper::Client;
+    use tokio::time::Duration;
+
+    fn session(group: Option<&str>, port: u16, xsession_id: &XenonSessionId) -> Session {
+        Session::adopt(
+            Upstream::new(Scheme::HTTP, format!("localhost:{}", port).parse().unwrap()),
+            Client::new(),
+            group.map(|x| x.to_string()),
+            "driver".to_string(),
+            xsession_id.clone(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reserve_session_slot() {
+        let mut state = XenonState::new(XenonConfig::default()).await.unwrap();
+        assert!(state.try_reserve_session_slot());
+        state.release_session_slot();
+
+        state.set_max_sessions(Some(2));
+        // Adopted sessions count towards the cap.
+        let adopted = XenonSessionId::from("adopted");
+        state.add_session(adopted.clone(), session(None, 4444, &adopted));
+        assert!(state.try_reserve_session_slot());
+        assert!(!state.try_reserve_session_slot());
+
+        // The created session takes over the reserved slot.
+        let created = XenonSessionId::from("created");
+        state.add_reserved_session(created.clone(), session(None, 4445, &created));
+        assert!(!state.try_reserve_session_slot());
+
+        state.delete_session(&adopted);
+        assert!(state.try_reserve_session_slot());
+        assert!(!state.try_reserve_session_slot());
+        state.release_session_slot();
+        assert!(state.try_reserve_session_slot());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_session_timeout_per_group() {
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+browsers:
+  - name: chrome
+    session_timeout_secs: 300
+  - name: firefox
+"#,
+        )
+        .unwrap();
+        let mut state = XenonState::new(config).await.unwrap();
+
+        let chrome_id = XenonSessionId::from("chrome");
+        let firefox_id = XenonSessionId::from("firefox");
+        let remote_id = XenonSessionId::from("remote");
+        state.add_session(
+            chrome_id.clone(),
+            session(Some("chrome-any"), 5000, &chrome_id),
+        );
+        state.add_session(
+            firefox_id.clone(),
+            session(Some("firefox-any"), 5001, &firefox_id),
+        );
+        state.add_session(remote_id.clone(), session(None, 8888, &remote_id));
+        assert!(state.get_timeout_sessions().await.is_empty());
+
+        tokio::time::advance(Duration::from_secs(301)).await;
+        assert_eq!(state.get_timeout_sessions().await, vec![chrome_id.clone()]);
+
+        tokio::time::advance(Duration::from_secs(1500)).await;
+        let mut expired = state.get_timeout_sessions().await;
+        expired.sort_by_key(|x| x.to_string());
+        assert_eq!(expired, vec![chrome_id, firefox_id, remote_id]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_pre_warm() {
+        // A long sleep stands in for the WebDriver.
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+browsers:
+  - name: chrome
+    driver_path: sleep
+    args: ["{PORT}"]
+    pre_warm: true
+  - name: firefox
+    driver_path: sleep
+    args: ["{PORT}"]
+ports: ["auto:2"]
+"#,
+        )
+        .unwrap();
+        let state = XenonState::new(config).await.unwrap();
+        let rwlock_groups = state.service_groups();
+        let rwlock_port_manager = state.port_manager();
+        let (mut port_manager, mut groups) =
+            tokio::join!(rwlock_port_manager.write(), rwlock_groups.write());
+        assert_eq!(groups["chrome-any"].num_services(), 1);
+        assert_eq!(groups["chrome-any"].total_sessions(), 0);
+        assert_eq!(groups["firefox-any"].num_services(), 0);
+        assert_eq!(port_manager.available_ports().count(), 1);
+        for group in groups.values_mut() {
+            group.terminate_all(&mut port_manager).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_service_group_names() {
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+browsers:
+  - name: chrome
+  - name: chrome
+    version: "90"
+  - name: chrome
+    version: "91"
+"#,
+        )
+        .unwrap();
+        let state = XenonState::new(config).await.unwrap();
+        let groups = state.service_groups();
+        let names: Vec<String> = groups.read().await.keys().cloned().collect();
+        assert_eq!(names, vec!["chrome-any", "chrome-90", "chrome-91"]);
+
+        let config: XenonConfig = serde_yaml::from_str(
+            r#"
+browsers:
+  - name: chrome
+    version: "90"
+  - name: chrome
+    version: "90"
+"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            XenonState::new(config.clone()).await,
+            Err(XenonError::ConfigValidation(_))
+        ));
+        assert!(groups_and_nodes(config).is_err());
+    }
+
+    #[test]
+    fn test_groups_and_nodes_invalid_node_url() {
+        let config: XenonConfig =
+            serde_yaml::from_str("nodes:\n  - url: 'http://[invalid'").unwrap();
+        assert!(groups_and_nodes(config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_totals() {
+        let mut state = XenonState::new(XenonConfig::default()).await.unwrap();
+        let ids: Vec<XenonSessionId> = (0..3)
+            .map(|i| XenonSessionId::from(i.to_string()))
+            .collect();
+        for id in &ids {
+            state.add_session(id.clone(), session(None, 8888, id));
         }
-        ids
+        assert!(state.delete_session(&ids[0]).is_some());
+        assert!(state.delete_session(&ids[0]).is_none());
+        assert!(state.expire_session(&ids[1]).is_some());
+
+        let totals = state.session_totals();
+        assert_eq!(totals.created, 3);
+        assert_eq!(totals.deleted, 1);
+        assert_eq!(totals.timed_out, 1);
+        assert_eq!(state.num_sessions(), 1);
     }
 }
@@ -0,0 +1,80 @@
+use crate::nodes::NodeHealthStatus;
+use crate::service::ServiceGroup;
+use crate::state::SessionTotals;
+use serde::Serialize;
+
+/// The status of a local service group.
+#[derive(Debug, Serialize)]
+pub struct ServiceGroupStatus {
+    pub name: String,
+    pub sessions: usize,
+    pub max_sessions: u32,
+    pub active_services: usize,
+    /// The highest number of sessions any one WebDriver in this group has had at once.
+    pub peak_concurrent_sessions: usize,
+}
+
+impl From<&ServiceGroup> for ServiceGroupStatus {
+    fn from(group: &ServiceGroup) -> Self {
+        Self {
+            name: group.name().to_string(),
+            sessions: group.total_sessions(),
+            max_sessions: group.browser.max_sessions(),
+            active_services: group.num_services(),
+            peak_concurrent_sessions: group.peak_concurrent_sessions(),
+        }
+    }
+}
+
+/// The body of the W3C `value` returned by GET /status.
+/// Xenon is `ready` if it can accept at least one more session, either locally
+/// or on a reachable remote node, without going over the global session cap.
+#[derive(Debug, Serialize)]
+pub struct StatusResponse {
+    pub ready: bool,
+    pub message: String,
+    pub total_sessions: usize,
+    pub session_totals: SessionTotals,
+    /// The number of additional sessions that could be created right now.
+    pub total_capacity: u32,
+    pub service_groups: Vec<ServiceGroupStatus>,
+    pub nodes: Vec<NodeHealthStatus>,
+}
+
+impl StatusResponse {
+    pub fn new(
+        total_sessions: usize,
+        session_totals: SessionTotals,
+        service_groups: Vec<ServiceGroupStatus>,
+        nodes: Vec<NodeHealthStatus>,
+        remaining_session_slots: Option<usize>,
+    ) -> Self {
+        let local_capacity: u32 = service_groups
+            .iter()
+            .map(|g| g.max_sessions.saturating_sub(g.sessions as u32))
+            .sum();
+        let node_capacity: u32 = nodes
+            .iter()
+            .filter(|n| n.reachable)
+            .map(|n| n.capacity)
+            .sum();
+        let mut total_capacity = local_capacity + node_capacity;
+        if let Some(slots) = remaining_session_slots {
+            total_capacity = total_capacity.min(u32::try_from(slots).unwrap_or(u32::MAX));
+        }
+        let ready = total_capacity > 0;
+        Self {
+            ready,
+            message: if ready {
+                "Xenon is ready to accept new sessions".to_string()
+            } else {
+                "Xenon has no capacity for new sessions".to_string()
+            },
+            total_sessions,
+            session_totals,
+            total_capacity,
+            service_groups,
+            nodes,
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::error::{XenonError, XenonResult};
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Build a TLS acceptor from PEM-encoded certificate chain and private key files.
+pub fn load_tls_acceptor(cert_path: &Path, key_path: &Path) -> XenonResult<TlsAcceptor> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| XenonError::TlsError(format!("Invalid certificate or key: {}", e)))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn open_pem_file(path: &Path) -> XenonResult<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| XenonError::TlsError(format!("Unable to open '{}': {}", path.display(), e)))
+}
+
+fn load_certs(path: &Path) -> XenonResult<Vec<Certificate>> {
+    let mut reader = open_pem_file(path)?;
+    let certs = rustls_pemfile::certs(&mut reader)
+        .map_err(|e| XenonError::TlsError(format!("Unable to read '{}': {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(XenonError::TlsError(format!(
+            "No certificates found in '{}'",
+            path.display()
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &Path) -> XenonResult<PrivateKey> {
+    let mut reader = open_pem_file(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key))) => {
+                return Ok(PrivateKey(key));
+            }
+            Ok(Some(_)) => continue,
+            Ok(None) => break,
+            Err(e) => {
+                return Err(XenonError::TlsError(format!(
+                    "Unable to read '{}': {}",
+                    path.display(),
+                    e
+                )));
+            }
+        }
+    }
+    Err(XenonError::TlsError(format!(
+        "No private key found in '{}'",
+        path.display()
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::XenonError;
+    use crate::tls::load_tls_acceptor;
+    use std::path::Path;
+
+    #[test]
+    fn test_load_tls_acceptor_errors() {
+        let missing = Path::new("/nonexistent/xenon-cert.pem");
+        assert!(matches!(
+            load_tls_acceptor(missing, missing),
+            Err(XenonError::TlsError(_))
+        ));
+
+        // A file that exists but contains no PEM items.
+        let not_pem = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        assert!(matches!(
+            load_tls_acceptor(&not_pem, &not_pem),
+            Err(XenonError::TlsError(_))
+        ));
+    }
+}
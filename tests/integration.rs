@@ -0,0 +1,158 @@
+//! Start the Xenon binary with a mock WebDriver (see `mock_webdriver.py`) and run
+//! a session through it. Requires `python3` on the PATH.
+#![cfg(unix)]
+
+use hyper::{Body, Client, Method, Request, StatusCode};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Kills Xenon and removes the config file if the test fails part way through.
+struct XenonProcess {
+    child: Child,
+    config_path: PathBuf,
+}
+
+impl XenonProcess {
+    fn start(port: u16) -> Self {
+        let script = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("mock_webdriver.py");
+        let config_path =
+            std::env::temp_dir().join(format!("xenon-integration-{}.yml", std::process::id()));
+        let config = format!(
+            r#"
+browsers:
+  - name: mock
+    driver_path: python3
+    args: ["{}"]
+    max_sessions: 2
+ports:
+  - "auto:2"
+"#,
+            script.display()
+        );
+        std::fs::write(&config_path, config).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_xenon-webdriver"))
+            .arg("--port")
+            .arg(port.to_string())
+            .arg("--cfg")
+            .arg(&config_path)
+            .env("XENON_LOG", "warn")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        Self { child, config_path }
+    }
+
+    /// Send SIGTERM and wait for Xenon to exit.
+    fn terminate(mut self) -> std::process::ExitStatus {
+        let status = Command::new("kill")
+            .arg("-TERM")
+            .arg(self.child.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "Xenon did not shut down");
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for XenonProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = std::fs::remove_file(&self.config_path);
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+async fn request(
+    method: Method,
+    url: &str,
+    body: &str,
+) -> hyper::Result<(StatusCode, serde_json::Value)> {
+    let req = Request::builder()
+        .method(method)
+        .uri(url)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = Client::new().request(req).await?;
+    let status = response.status();
+    let bytes = hyper::body::to_bytes(response.into_body()).await?;
+    let json = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+    Ok((status, json))
+}
+
+#[tokio::test]
+async fn test_session_lifecycle() {
+    if Command::new("python3").arg("--version").output().is_err() {
+        eprintln!("Skipping integration test because python3 is not available");
+        return;
+    }
+
+    let port = free_port();
+    let xenon = XenonProcess::start(port);
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    // Wait for Xenon to be ready.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match request(Method::GET, &format!("{}/status", base_url), "").await {
+            Ok((StatusCode::OK, json)) => {
+                assert_eq!(json["value"]["ready"], true);
+                break;
+            }
+            _ => {
+                assert!(Instant::now() < deadline, "Xenon did not start");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+
+    // Create a session.
+    let (status, json) = request(
+        Method::POST,
+        &format!("{}/session", base_url),
+        r#"{"capabilities": {"alwaysMatch": {"browserName": "mock"}}}"#,
+    )
+    .await
+    .unwrap();
+    assert_eq!(status, StatusCode::OK, "{}", json);
+    let session_id = json["value"]["sessionId"].as_str().unwrap().to_string();
+    assert_ne!(session_id, "mock-session");
+
+    // Forward a request.
+    let session_url = format!("{}/session/{}", base_url, session_id);
+    let (status, json) = request(Method::GET, &format!("{}/url", session_url), "")
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json["value"], "about:blank");
+
+    // Delete the session.
+    let (status, _) = request(Method::DELETE, &session_url, "").await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+    let (_, json) = request(Method::GET, &format!("{}/url", session_url), "")
+        .await
+        .unwrap();
+    assert_eq!(json["value"]["error"], "invalid session id");
+
+    assert!(xenon.terminate().success());
+}
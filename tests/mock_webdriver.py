@@ -0,0 +1,66 @@
+#!/usr/bin/env python3
+"""A minimal WebDriver server for integration tests.
+
+Usage: mock_webdriver.py --port=PORT
+"""
+import json
+import sys
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+SESSION_ID = "mock-session"
+
+
+class Handler(BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+
+    def send_json(self, value, status=200):
+        body = json.dumps({"value": value}).encode()
+        self.send_response(status)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def read_body(self):
+        length = int(self.headers.get("Content-Length", 0))
+        return self.rfile.read(length) if length else b""
+
+    def do_GET(self):
+        if self.path == "/status":
+            self.send_json({"ready": True, "message": "mock"})
+        elif self.path == "/session/%s/url" % SESSION_ID:
+            self.send_json("about:blank")
+        else:
+            self.send_json({"error": "unknown command", "message": self.path}, 404)
+
+    def do_POST(self):
+        self.read_body()
+        if self.path == "/session":
+            self.send_json(
+                {"sessionId": SESSION_ID, "capabilities": {"browserName": "mock"}}
+            )
+        else:
+            self.send_json({"error": "unknown command", "message": self.path}, 404)
+
+    def do_DELETE(self):
+        if self.path == "/session/%s" % SESSION_ID:
+            self.send_json(None)
+        else:
+            self.send_json({"error": "invalid session id", "message": self.path}, 404)
+
+    def log_message(self, format, *args):
+        pass
+
+
+def main():
+    port = None
+    for arg in sys.argv[1:]:
+        if arg.startswith("--port="):
+            port = int(arg[len("--port="):])
+    if port is None:
+        sys.exit("Missing --port")
+    HTTPServer(("127.0.0.1", port), Handler).serve_forever()
+
+
+if __name__ == "__main__":
+    main()